@@ -0,0 +1,14 @@
+#pragma once
+
+// Bindings match FRAME_BINDING and OBJECT_BINDING in src/gfx/blocks.rs
+layout(std140, binding = 0) uniform Frame {
+	mat4 view;
+	mat4 proj;
+	vec3 camera_pos;
+	float time;
+};
+
+layout(std140, binding = 1) uniform Object {
+	mat4 trans;
+	vec4 object_color;
+};
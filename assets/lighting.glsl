@@ -0,0 +1,63 @@
+#pragma once
+
+const float PI = 3.14159265359;
+
+// Reflectance at normal incidence of dielectrics, as glTF assumes
+const vec3 DIELECTRIC_F0 = vec3(0.04);
+
+// Trowbridge-Reitz (GGX) normal distribution, with alpha = roughness^2
+float distribution_ggx(float n_dot_h, float roughness) {
+	float alpha = roughness * roughness;
+	float alpha2 = alpha * alpha;
+	float denom = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+
+	return alpha2 / (PI * denom * denom);
+}
+
+// Height-correlated Smith-GGX visibility, which folds in the 1 / (4 n.l n.v) of the BRDF
+float visibility_smith_ggx(float n_dot_l, float n_dot_v, float roughness) {
+	float alpha = roughness * roughness;
+	float alpha2 = alpha * alpha;
+	float ggx_v = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - alpha2) + alpha2);
+	float ggx_l = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - alpha2) + alpha2);
+	float ggx = ggx_v + ggx_l;
+
+	return ggx > 0.0 ? 0.5 / ggx : 0.0;
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+	return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Schlick with the grazing reflectance damped by roughness, for image-based light where the
+// halfway vector is unknown
+vec3 fresnel_schlick_roughness(float cos_theta, vec3 f0, float roughness) {
+	return f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Outgoing radiance of the metallic-roughness BRDF for one light of `radiance`
+vec3 metallic_roughness(vec3 base_color, float metallic, float roughness,
+                        vec3 normal, vec3 light_dir, vec3 eye_dir, vec3 radiance) {
+	vec3 halfway_dir = normalize(light_dir + eye_dir);
+	float n_dot_l = clamp(dot(normal, light_dir), 0.0, 1.0);
+	float n_dot_v = clamp(dot(normal, eye_dir), 0.0001, 1.0);
+	float n_dot_h = clamp(dot(normal, halfway_dir), 0.0, 1.0);
+	float v_dot_h = clamp(dot(eye_dir, halfway_dir), 0.0, 1.0);
+
+	vec3 f0 = mix(DIELECTRIC_F0, base_color, metallic);
+	vec3 fresnel = fresnel_schlick(v_dot_h, f0);
+
+	vec3 specular = fresnel * distribution_ggx(n_dot_h, roughness) * visibility_smith_ggx(n_dot_l, n_dot_v, roughness);
+	// Metals have no diffuse term; what isn't reflected specularly is
+	vec3 diffuse = (1.0 - fresnel) * (1.0 - metallic) * base_color / PI;
+
+	return (diffuse + specular) * radiance * n_dot_l;
+}
+
+vec3 srgb_to_linear(vec3 color) {
+	return pow(color, vec3(2.2));
+}
+
+vec3 linear_to_srgb(vec3 color) {
+	return pow(color, vec3(1.0 / 2.2));
+}
@@ -0,0 +1,13 @@
+#version 430
+
+in float life;
+
+out vec4 out_color;
+
+void main() {
+	// Round points, fading out over the last second
+	vec2 offset = gl_PointCoord * 2.0 - 1.0;
+	float falloff = max(1.0 - dot(offset, offset), 0.0);
+
+	out_color = vec4(vec3(1.0, 0.6, 0.2) * falloff * min(life, 1.0), 1.0);
+}
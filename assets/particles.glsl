@@ -0,0 +1,12 @@
+#pragma once
+
+// Binding matches PARTICLE_BINDING in src/gfx/blocks.rs
+struct Particle {
+	// w is the seconds left to live; dead particles have none
+	vec4 position;
+	vec4 velocity;
+};
+
+layout(std430, binding = 1) buffer Particles {
+	Particle particles[];
+};
@@ -0,0 +1,20 @@
+#version 430
+
+#include "common.glsl"
+#include "particles.glsl"
+
+out float life;
+
+void main() {
+	Particle particle = particles[gl_VertexID];
+	life = particle.position.w;
+
+	if (life <= 0.0) {
+		// Outside the clip volume, so dead particles are dropped
+		gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+		return;
+	}
+
+	gl_Position = proj * view * vec4(particle.position.xyz, 1.0);
+	gl_PointSize = clamp(8.0 / gl_Position.w, 1.0, 8.0);
+}
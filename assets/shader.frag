@@ -1,44 +1,92 @@
 #version 430
 
-uniform mat4 trans;
-uniform mat4 proj;
-uniform mat4 view;
+#include "common.glsl"
+#include "lighting.glsl"
 
-uniform sampler2D tex;
+// Metallic-roughness shading as glTF defines it: each map is multiplied by its factor, and a
+// map that's compiled out counts as white
+uniform sampler2D base_color_tex;
 uniform sampler2D normal_tex;
-uniform sampler2D disp_tex;
+uniform sampler2D metallic_roughness_tex;
+uniform sampler2D occlusion_tex;
+uniform sampler2D emissive_tex;
+uniform samplerCube env_tex;
+
+uniform vec4 base_color_factor = vec4(1.0);
+uniform float metallic_factor = 1.0;
+uniform float roughness_factor = 1.0;
+uniform float normal_scale = 1.0;
+uniform float occlusion_strength = 1.0;
+uniform vec3 emissive_factor = vec3(0.0);
+
+// The light sits at the origin
+uniform vec3 light_color = vec3(1.0);
+uniform float light_intensity = 1.0;
 
 in vec2 Texcoord;
 in vec4 position_ws;
 in mat3 TBN;
 in vec4 lightpos_ws;
 in vec4 camerapos_ws;
+flat in mat4 model_trans;
+flat in vec4 tint;
 
 out vec4 out_color;
 
 void main() {
-	vec4 light_color = vec4(0.8, 0.8, 0.8, 1.0);
-	
-	vec4 mat_color = texture(tex, Texcoord);
-
-	vec3 normal_map = texture(normal_tex, Texcoord).rgb;
-	vec3 normal_adj = normalize(normal_map * 2.0 - 1.0);
-	vec3 normal_ws = mat3(trans) * TBN * normal_adj;
-
-	vec4 disp_map = vec4(texture(disp_tex, Texcoord).rgb, 1.0) * 2.0 - 1.0;
-
-	vec4 position_adj = trans * (position_ws);
-	
-	vec4 lightdir_ws = normalize(lightpos_ws - position_adj);
-	float light_dist = distance(lightpos_ws, position_adj);
-	vec4 eyedir_ws = normalize(camerapos_ws - position_adj);
-	
-	float diff = clamp(dot(lightdir_ws.xyz, normal_ws), 0.0, 1.0);
-	
-	vec3 halfway_dir = normalize(lightdir_ws + eyedir_ws).xyz;
-	float spec = pow(max(dot(normal_ws, halfway_dir), 0.0), 16.0);
-
-	out_color =
-		spec * mat_color +
-		diff * mat_color;
+	vec4 base_color = texture(base_color_tex, Texcoord);
+	base_color = vec4(srgb_to_linear(base_color.rgb), base_color.a) * base_color_factor * tint;
+
+	float metallic = metallic_factor;
+	float roughness = roughness_factor;
+#ifdef METALLIC_ROUGHNESS_MAP
+	// Roughness in green and metalness in blue, as in glTF
+	vec4 metallic_roughness_map = texture(metallic_roughness_tex, Texcoord);
+	metallic *= metallic_roughness_map.b;
+	roughness *= metallic_roughness_map.g;
+#endif
+	metallic = clamp(metallic, 0.0, 1.0);
+	// Perfectly smooth surfaces make the highlight vanish between pixels
+	roughness = clamp(roughness, 0.04, 1.0);
+
+#ifdef NORMAL_MAP
+	vec3 normal_map = texture(normal_tex, Texcoord).rgb * 2.0 - 1.0;
+	vec3 normal_adj = normalize(normal_map * vec3(normal_scale, normal_scale, 1.0));
+#else
+	vec3 normal_adj = vec3(0.0, 0.0, 1.0);
+#endif
+	vec3 normal_ws = normalize(mat3(model_trans) * TBN * normal_adj);
+
+	vec4 position_adj = model_trans * (position_ws);
+
+	vec3 lightdir_ws = normalize(lightpos_ws.xyz - position_adj.xyz);
+	float light_dist = distance(lightpos_ws.xyz, position_adj.xyz);
+	vec3 eyedir_ws = normalize(camerapos_ws.xyz - position_adj.xyz);
+
+	vec3 radiance = light_color * light_intensity / (light_dist * light_dist);
+	vec3 color = metallic_roughness(base_color.rgb, metallic, roughness, normal_ws, lightdir_ws, eyedir_ws, radiance);
+
+	// Ambient light from the environment map, blurrier the rougher the surface
+	float n_dot_v = clamp(dot(normal_ws, eyedir_ws), 0.0001, 1.0);
+	vec3 fresnel = fresnel_schlick_roughness(n_dot_v, mix(DIELECTRIC_F0, base_color.rgb, metallic), roughness);
+	float max_lod = float(textureQueryLevels(env_tex) - 1);
+
+	vec3 env_diffuse = srgb_to_linear(textureLod(env_tex, normal_ws, max_lod).rgb);
+	vec3 env_specular = srgb_to_linear(textureLod(env_tex, reflect(-eyedir_ws, normal_ws), roughness * max_lod).rgb);
+	vec3 ambient = (1.0 - fresnel) * (1.0 - metallic) * base_color.rgb * env_diffuse + fresnel * env_specular;
+
+#ifdef OCCLUSION_MAP
+	ambient *= mix(1.0, texture(occlusion_tex, Texcoord).r, occlusion_strength);
+#endif
+	color += ambient;
+
+	vec3 emissive = emissive_factor;
+#ifdef EMISSIVE_MAP
+	emissive *= srgb_to_linear(texture(emissive_tex, Texcoord).rgb);
+#endif
+	color += emissive;
+
+	// Reinhard, since the target is 8 bits per channel
+	color = color / (color + 1.0);
+	out_color = vec4(linear_to_srgb(color), base_color.a);
 }
@@ -1,29 +1,68 @@
 #version 430
 
-uniform mat4 trans;
-uniform mat4 proj;
-uniform mat4 view;
+#ifdef MULTI_DRAW
+// Extension directives have to come before any declarations
+#extension GL_ARB_shader_draw_parameters : require
+#endif
 
-in vec3 position;
-in vec3 normal;
-in vec3 tangent;
-in vec2 texcoord;
+#include "common.glsl"
 
-out vec2 Texcoord;
-out vec4 position_ws;
-out mat3 TBN;
-out vec4 lightpos_ws;
-out vec4 camerapos_ws;
+// Fixed locations keep the mesh's vertex arrays valid when the shader is reloaded
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec3 tangent;
+layout(location = 3) in vec2 texcoord;
+
+#if defined(MULTI_DRAW)
+// Filled by MultiDraw in src/gfx/batch.rs, one entry per draw command
+struct DrawData {
+	mat4 trans;
+	vec4 color;
+};
+
+layout(std430, binding = 0) readonly buffer Draws {
+	DrawData draws[];
+};
+
+// Where this multi-draw call's entries start; gl_DrawIDARB counts from 0 in every call
+uniform int draw_offset;
+
+#define MODEL_TRANS draws[draw_offset + gl_DrawIDARB].trans
+#define MODEL_COLOR draws[draw_offset + gl_DrawIDARB].color
+#elif defined(INSTANCED)
+// One per instance; the matrix takes locations 4 to 7
+layout(location = 4) in mat4 instance_trans;
+layout(location = 8) in vec4 instance_color;
+#define MODEL_TRANS instance_trans
+#define MODEL_COLOR instance_color
+#else
+#define MODEL_TRANS trans
+#define MODEL_COLOR object_color
+#endif
+
+#ifdef TESSELLATION
+// Passed through untransformed; displace.tese emits the surface once it's subdivided
+out VertexData {
+	vec3 position;
+	vec3 normal;
+	vec3 tangent;
+	vec2 texcoord;
+	mat4 model;
+	vec4 color;
+} vs_out;
+
+void main() {
+	vs_out.position = position;
+	vs_out.normal = normal;
+	vs_out.tangent = tangent;
+	vs_out.texcoord = texcoord;
+	vs_out.model = MODEL_TRANS;
+	vs_out.color = MODEL_COLOR;
+}
+#else
+#include "surface.glsl"
 
 void main() {
-	lightpos_ws = vec4(0, 0, 0, 1.0);
-	camerapos_ws = vec4(0, 0, -5.0, 1.0);	/* FIXME: make a uniform */
-	
-	position_ws = vec4(position, abs(distance(vec3(camerapos_ws), position)));
-
-	TBN = mat3(tangent, cross(normal, tangent), normal);
-	
-	Texcoord = texcoord;
-	
-	gl_Position = proj * view * trans * position_ws;
+	emit_surface(position, normal, tangent, texcoord, MODEL_TRANS, MODEL_COLOR);
 }
+#endif
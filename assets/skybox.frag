@@ -0,0 +1,11 @@
+#version 430
+
+uniform samplerCube env_tex;
+
+in vec3 direction;
+
+out vec4 out_color;
+
+void main() {
+	out_color = texture(env_tex, normalize(direction));
+}
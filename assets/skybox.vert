@@ -0,0 +1,15 @@
+#version 430
+
+uniform mat4 inv_view_proj;
+
+out vec3 direction;
+
+void main() {
+	// Oversized triangle covering the screen, placed on the far plane
+	vec2 position = vec2((gl_VertexID << 1) & 2, gl_VertexID & 2) * 2.0 - 1.0;
+
+	vec4 world = inv_view_proj * vec4(position, 1.0, 1.0);
+	direction = world.xyz / world.w;
+
+	gl_Position = vec4(position, 1.0, 1.0);
+}
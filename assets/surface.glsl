@@ -0,0 +1,27 @@
+#pragma once
+
+// Everything shader.frag reads, written by whichever stage runs last before rasterization
+
+out vec2 Texcoord;
+out vec4 position_ws;
+out mat3 TBN;
+out vec4 lightpos_ws;
+out vec4 camerapos_ws;
+// The object's transform and tint, from the Object block or the instance
+flat out mat4 model_trans;
+flat out vec4 tint;
+
+void emit_surface(vec3 position, vec3 normal, vec3 tangent, vec2 texcoord, mat4 model, vec4 color) {
+	lightpos_ws = vec4(0, 0, 0, 1.0);
+	camerapos_ws = vec4(camera_pos, 1.0);
+	
+	position_ws = vec4(position, abs(distance(vec3(camerapos_ws), position)));
+
+	TBN = mat3(tangent, cross(normal, tangent), normal);
+	
+	Texcoord = texcoord;
+	model_trans = model;
+	tint = color;
+	
+	gl_Position = proj * view * model * position_ws;
+}
@@ -21,7 +21,7 @@ use std::path::Path;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
-    let mut file = File::create(&Path::new(&out_dir).join("bindings.rs")).unwrap();
+    let mut file = File::create(Path::new(&out_dir).join("bindings.rs")).unwrap();
 
     Registry::new(Api::Gl, (4, 5), Profile::Core, Fallbacks::All, [])
         .write_bindings(GlobalGenerator, &mut file)
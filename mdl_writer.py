@@ -16,8 +16,86 @@ def pack_tri_texcoords(tc1, tc2, tc3):
     return struct.pack('>ffffff', tc1[0], 1.0 - tc1[1], tc2[0], 1.0 - tc2[1], tc3[0], 1.0 - tc3[1])
 
 
-def pack_tri_indices(i1, i2, i3):
-    return struct.pack('>III', i1, i2, i3)    
+# Files start with the magic bytes and a format version; version 1 follows each material
+# name with its factors, version 2 gives the index size its own byte ahead of the count.
+# The loader reads files without them as version 0.
+MAGIC = b'MDL\0'
+VERSION = 2
+
+
+# Index formats by size in bytes. There are no byte indices: most hardware can't draw
+# them natively, so the driver would widen them anyway.
+INDEX_FORMATS = {2: 'H', 4: 'I'}
+
+
+def index_size(num_verts):
+    if num_verts <= 1 << 16:
+        return 2
+    return 4
+
+
+def pack_tri_indices(size, i1, i2, i3):
+    return struct.pack('>' + INDEX_FORMATS[size] * 3, i1, i2, i3)
+
+
+def pack_index_header(size, num_indices):
+    return struct.pack('>BI', size, num_indices)
+
+
+def pack_string(string):
+    data = string.encode('utf-8')
+    return struct.pack('>H', len(data)) + data
+
+
+def principled_node(material):
+    if not material.use_nodes or not material.node_tree:
+        return None
+    for node in material.node_tree.nodes:
+        if node.type == 'BSDF_PRINCIPLED':
+            return node
+    return None
+
+
+def material_factors(material):
+    # Base color RGBA, metallic, roughness and emissive RGB, as glTF defines them
+    if material is None:
+        return (1.0, 1.0, 1.0, 1.0, 0.0, 0.6, 0.0, 0.0, 0.0)
+
+    node = principled_node(material)
+    if node:
+        color = node.inputs['Base Color'].default_value
+        return (color[0], color[1], color[2], color[3],
+                node.inputs['Metallic'].default_value, node.inputs['Roughness'].default_value,
+                0.0, 0.0, 0.0)
+
+    # Blender Internal has no metalness; map hardness to roughness like Blinn-Phong to Beckmann
+    color = material.diffuse_color
+    roughness = min(1.0, (2.0 / (material.specular_hardness + 2.0)) ** 0.5)
+    emit = material.emit
+    return (color[0], color[1], color[2], material.alpha, 0.0, roughness,
+            color[0] * emit, color[1] * emit, color[2] * emit)
+
+
+def pack_materials(mesh):
+    # Empty slots get an empty name, which the loader gives the default material
+    data = struct.pack('>I', len(mesh.materials))
+    for material in mesh.materials:
+        data += pack_string(material.name if material else '')
+        data += struct.pack('>9f', *material_factors(material))
+    return data
+
+
+def face_material(mesh, face):
+    # Faces can point past the material slots when there are none
+    return face.material_index if face.material_index < len(mesh.materials) else 0
+
+
+def pack_submeshes(submeshes):
+    # (first index, index count, material slot) per range
+    data = struct.pack('>I', len(submeshes))
+    for first_index, count, material in submeshes:
+        data += struct.pack('>IIH', first_index, count, material)
+    return data
 
 
 def pack_tri_norm(norm):
@@ -58,7 +136,14 @@ def write_verts(file, mesh):
     
     uv_data = mesh.tessface_uv_textures.active.data
     
-    for face_idx, face in enumerate(mesh.tessfaces):
+    # Every face gets its own three vertices
+    size = index_size(len(mesh.tessfaces) * 3)
+    
+    # Faces sorted by material so each material's indices are one range
+    faces = sorted(enumerate(mesh.tessfaces), key=lambda item: face_material(mesh, item[1]))
+    submeshes = []
+    
+    for tri_idx, (face_idx, face) in enumerate(faces):
         if len(face.vertices) != 3:
             print("Quads don't work right, use tris")
             return
@@ -66,9 +151,15 @@ def write_verts(file, mesh):
         face_uv = uv_data[face_idx]
         uv = face_uv.uv1, face_uv.uv2, face_uv.uv3
         
+        material = face_material(mesh, face)
+        if submeshes and submeshes[-1][2] == material:
+            submeshes[-1][1] += 3
+        else:
+            submeshes.append([num_indices, 3, material])
+        
         num_indices += 3
-        idx_base = face_idx * 3
-        indices += pack_tri_indices(idx_base, idx_base + 1, idx_base + 2)
+        idx_base = tri_idx * 3
+        indices += pack_tri_indices(size, idx_base, idx_base + 1, idx_base + 2)
 
         num_verts += 3
         tris += pack_tri_verts(mesh.vertices, face.vertices[0], face.vertices[1], face.vertices[2])
@@ -76,7 +167,7 @@ def write_verts(file, mesh):
         
         normals += pack_tri_norms(avg_normals, face.vertices)
         
-    file.write(struct.pack('>I', num_indices))
+    file.write(pack_index_header(size, num_indices))
     file.write(indices)
     
     file.write(struct.pack('>I', num_verts))
@@ -88,6 +179,9 @@ def write_verts(file, mesh):
     file.write(struct.pack('>I', num_verts))
     file.write(normals)
     
+    file.write(pack_materials(mesh))
+    file.write(pack_submeshes(submeshes))
+    
     
 def pack_matrix4_row(row):
     return struct.pack('>ffff', row[0], row[1], row[2], row[3])
@@ -105,6 +199,7 @@ def write(filepath):
     scene = bpy.context.scene
 
     file = open(filepath, 'wb')
+    file.write(MAGIC + struct.pack('>H', VERSION))
     for obj in bpy.context.selected_objects:
         matrix = obj.matrix_world.copy()
         me = obj.to_mesh(scene, True, "PREVIEW")
@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::time::SystemTime;
+use time;
+use gfx::image;
+use gfx::lowlevel::*;
+use gfx::material::{Material, MetallicRoughness};
+use gfx::material_file::{MaterialDesc, TextureSource};
+use gfx::mesh::Mesh;
+use gfx::model::model_loader;
+use gfx::skybox;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TextureKind {
+    Image,
+    CubeMap,
+    Volume,
+    Array,
+    /// Built in code rather than loaded; the key's path is just a name.
+    Generated,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TextureKey {
+    path: PathBuf,
+    kind: TextureKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ProgramKey {
+    stages: Vec<(ShaderStage, PathBuf)>,
+    defines: Defines,
+}
+
+impl ProgramKey {
+    fn stage_paths(&self) -> Vec<(ShaderStage, &Path)> {
+        self.stages.iter().map(|&(stage, ref path)| (stage, path.as_path())).collect()
+    }
+
+    fn name(&self) -> String {
+        self.stages.iter().
+            map(|(_, path)| path.display().to_string()).
+            collect::<Vec<_>>().
+            join(" + ")
+    }
+}
+
+/// Factors overriding a material file's, compared bit for bit so they can be part of a key.
+#[derive(Clone, Copy, Debug)]
+struct Factors(MetallicRoughness);
+
+impl Factors {
+    fn bits(&self) -> [u32; 11] {
+        let factors = &self.0;
+        [factors.base_color.x.to_bits(), factors.base_color.y.to_bits(),
+         factors.base_color.z.to_bits(), factors.base_color.w.to_bits(),
+         factors.metallic.to_bits(), factors.roughness.to_bits(),
+         factors.emissive.x.to_bits(), factors.emissive.y.to_bits(), factors.emissive.z.to_bits(),
+         factors.normal_scale.to_bits(), factors.occlusion_strength.to_bits()]
+    }
+}
+
+impl PartialEq for Factors {
+    fn eq(&self, other: &Factors) -> bool {
+        self.bits() == other.bits()
+    }
+}
+
+impl Eq for Factors {}
+
+impl Hash for Factors {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+
+/// A material file, with defines added on top of the ones it declares, and factors in place of
+/// its own for meshes that bring theirs.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct MaterialKey {
+    path: PathBuf,
+    defines: Defines,
+    factors: Option<Factors>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum AssetKey {
+    Texture(TextureKey),
+    Program(ProgramKey),
+    Material(MaterialKey),
+    Mesh(PathBuf),
+}
+
+/// The files an asset was loaded from, with the modification times they had then.
+struct Watch {
+    asset: AssetKey,
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// One loaded asset, as listed by `Assets::report`.
+#[derive(Debug)]
+pub struct AssetInfo {
+    pub kind: &'static str,
+    pub name: String,
+    /// Handles held outside the registry.
+    pub handles: usize,
+    pub byte_size: usize,
+}
+
+/// Where linked program binaries are kept between runs.
+pub const PROGRAM_CACHE_DIR: &str = "assets/shader_cache";
+
+/// Loads textures, programs, materials and meshes once per path and hands out shared handles to them.
+///
+/// The registry only keeps weak references, so an asset's GL objects are freed as soon as the
+/// last handle is dropped, and a later request loads it again.
+///
+/// Every file-backed asset is watched; `reload_changed` swaps freshly loaded GL objects into
+/// the existing handles. It must be called on the thread that owns the GL context.
+pub struct Assets {
+    textures: HashMap<TextureKey, WeakHandle<Texture>>,
+    samplers: Vec<(SamplerParams, Weak<Sampler>)>,
+    /// One entry per compiled permutation.
+    programs: HashMap<ProgramKey, WeakHandle<Program>>,
+    /// One entry per material file and set of extra defines.
+    materials: HashMap<MaterialKey, WeakHandle<Material>>,
+    meshes: HashMap<PathBuf, WeakHandle<Mesh>>,
+
+    watches: Vec<Watch>,
+    /// Linked program binaries from earlier runs; unset when the driver can't provide them.
+    pub program_cache: Option<ProgramCache>,
+    /// Whether meshes loaded from now on use the displacement-mapped tessellation path.
+    pub tessellate_meshes: bool,
+    pub poll_interval: time::Duration,
+    last_poll: time::Timespec,
+}
+
+fn lookup<K, T>(map: &HashMap<K, WeakHandle<T>>, key: &K) -> Option<Handle<T>>
+        where K: Eq + Hash {
+    map.get(key).and_then(|weak| weak.upgrade())
+}
+
+impl Assets {
+    pub fn new() -> Assets {
+        Assets {
+            textures: HashMap::new(),
+            samplers: Vec::new(),
+            programs: HashMap::new(),
+            materials: HashMap::new(),
+            meshes: HashMap::new(),
+            watches: Vec::new(),
+            program_cache: ProgramCache::new(Path::new(PROGRAM_CACHE_DIR)),
+            tessellate_meshes: false,
+            poll_interval: time::Duration::milliseconds(500),
+            last_poll: time::get_time(),
+        }
+    }
+
+    fn load_texture(key: &TextureKey) -> io::Result<Texture> {
+        let path = &key.path;
+        let name = path.to_string_lossy();
+
+        match key.kind {
+            TextureKind::Image => Ok(Texture::new(&name, &image::load_image(path)?)),
+            TextureKind::CubeMap => Ok(Texture::new_cube(&name, &image::load_cube_map(path)?)),
+            TextureKind::Volume => Ok(Texture::new_3d(&name, &image::load_volume(path)?)),
+            TextureKind::Array => Ok(Texture::new_array(&name, &image::load_volume(path)?)),
+            TextureKind::Generated => Err(io::Error::new(io::ErrorKind::NotFound,
+                                                         format!("{} isn't loaded from disk", name))),
+        }
+    }
+
+    fn texture_of_kind(&mut self, path: &Path, kind: TextureKind) -> io::Result<Handle<Texture>> {
+        let key = TextureKey { path: path.to_path_buf(), kind };
+
+        if let Some(tex) = lookup(&self.textures, &key) {
+            return Ok(tex);
+        }
+
+        let tex = Handle::new(Assets::load_texture(&key)?);
+        self.textures.insert(key.clone(), tex.downgrade());
+        self.watch(AssetKey::Texture(key), &[path]);
+
+        Ok(tex)
+    }
+
+    pub fn texture(&mut self, path: &Path) -> io::Result<Handle<Texture>> {
+        self.texture_of_kind(path, TextureKind::Image)
+    }
+
+    /// Loads a cube map from a single cross or equirectangular image; see `image::load_cube_map`.
+    pub fn cube_map(&mut self, path: &Path) -> io::Result<Handle<Texture>> {
+        self.texture_of_kind(path, TextureKind::CubeMap)
+    }
+
+    pub fn volume(&mut self, path: &Path) -> io::Result<Handle<Texture>> {
+        self.texture_of_kind(path, TextureKind::Volume)
+    }
+
+    /// Loads a texture array stored as a strip of square layers, like a volume.
+    pub fn texture_array(&mut self, path: &Path) -> io::Result<Handle<Texture>> {
+        self.texture_of_kind(path, TextureKind::Array)
+    }
+
+    /// Shares a texture made in code under `name`, calling `make` only if it isn't loaded.
+    pub fn generated_texture<F>(&mut self, name: &str, make: F) -> Handle<Texture>
+            where F: FnOnce() -> Texture {
+        let key = TextureKey { path: PathBuf::from(name), kind: TextureKind::Generated };
+
+        if let Some(tex) = lookup(&self.textures, &key) {
+            return tex;
+        }
+
+        let tex = Handle::new(make());
+        self.textures.insert(key, tex.downgrade());
+
+        tex
+    }
+
+    pub fn sampler(&mut self, params: SamplerParams) -> Rc<Sampler> {
+        self.samplers.retain(|(_, weak)| weak.upgrade().is_some());
+
+        if let Some(sampler) = self.samplers.iter().
+                filter(|&(entry_params, _)| *entry_params == params).
+                filter_map(|(_, weak)| weak.upgrade()).
+                next() {
+            return sampler;
+        }
+
+        let sampler = Rc::new(Sampler::new(params));
+        self.samplers.push((params, Rc::downgrade(&sampler)));
+
+        sampler
+    }
+
+    pub fn program(&mut self, vs_path: &Path, fs_path: &Path) -> Result<Handle<Program>, ShaderError> {
+        self.program_variant(vs_path, fs_path, &Defines::new())
+    }
+
+    /// The permutation of a shader pair selected by `defines`, compiled once per define set.
+    pub fn program_variant(&mut self, vs_path: &Path, fs_path: &Path, defines: &Defines)
+            -> Result<Handle<Program>, ShaderError> {
+        self.program_stages(&[(ShaderStage::Vertex, vs_path), (ShaderStage::Fragment, fs_path)], defines)
+    }
+
+    /// A program built from one file per stage, such as a tessellated or geometry shader
+    /// pipeline.
+    pub fn program_stages(&mut self, stages: &[(ShaderStage, &Path)], defines: &Defines)
+            -> Result<Handle<Program>, ShaderError> {
+        let key = ProgramKey {
+            stages: stages.iter().map(|&(stage, path)| (stage, path.to_path_buf())).collect(),
+            defines: defines.clone(),
+        };
+
+        if let Some(program) = lookup(&self.programs, &key) {
+            return Ok(program);
+        }
+
+        let program = Program::from_stage_paths(stages, defines, self.program_cache.as_ref())?;
+        let source_paths = program.source_paths.clone();
+
+        let program = Handle::new(program);
+        self.programs.insert(key.clone(), program.downgrade());
+        self.watch_paths(AssetKey::Program(key), &source_paths);
+
+        Ok(program)
+    }
+
+    fn load_material(&mut self, key: &MaterialKey) -> io::Result<Material> {
+        let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+
+        let mut text = String::new();
+        File::open(&key.path)?.read_to_string(&mut text)?;
+        let desc = MaterialDesc::parse(&text).
+            map_err(|err| invalid(format!("{}: {}", key.path.display(), err)))?;
+
+        let mut defines = desc.defines.clone();
+        defines.extend(key.defines.iter().map(|(name, value)| (name.clone(), value.clone())));
+
+        let stages = desc.stages.iter().map(|&(stage, ref path)| (stage, path.as_path())).collect::<Vec<_>>();
+        let program = self.program_stages(&stages, &defines).map_err(|err| invalid(err.to_string()))?;
+
+        let mut material = Material::with_program(program);
+        for texture in &desc.textures {
+            let handle = match texture.source {
+                TextureSource::Image(ref path) => self.texture(path)?,
+                TextureSource::CubeMap(ref path) => self.cube_map(path)?,
+                TextureSource::Volume(ref path) => self.volume(path)?,
+                TextureSource::Array(ref path) => self.texture_array(path)?,
+                TextureSource::Environment => skybox::environment_map(self),
+            };
+
+            let sampler = self.sampler(texture.sampler);
+            material.add_texture(&texture.uniform, handle, sampler);
+        };
+
+        for &(ref name, param) in &desc.params {
+            material.set_param(name, param);
+        };
+        material.state = desc.state;
+
+        if let Some(Factors(ref factors)) = key.factors {
+            factors.set_params(&mut material);
+        }
+
+        Ok(material)
+    }
+
+    /// Loads a material file (see `MaterialDesc`), with `defines` selecting a permutation on top
+    /// of the file's own and `factors`, if any, replacing its. Each set of factors is a material
+    /// of its own, reloaded along with the file. Shader and parse errors come back as
+    /// `InvalidData`.
+    pub fn material(&mut self, path: &Path, defines: &Defines, factors: Option<MetallicRoughness>)
+            -> io::Result<Handle<Material>> {
+        let key = MaterialKey { path: path.to_path_buf(), defines: defines.clone(), factors: factors.map(Factors) };
+
+        if let Some(material) = lookup(&self.materials, &key) {
+            return Ok(material);
+        }
+
+        let material = Handle::new(self.load_material(&key)?);
+        self.materials.insert(key.clone(), material.downgrade());
+        self.watch(AssetKey::Material(key), &[path]);
+
+        Ok(material)
+    }
+
+    fn load_mesh(&mut self, path: &Path) -> io::Result<Mesh> {
+        let mut file = File::open(path)?;
+        let model = model_loader::load_model(&mut file);
+
+        let mut mesh = model.into_mesh(self);
+        mesh.set_name(&path.to_string_lossy());
+        Ok(mesh)
+    }
+
+    pub fn mesh(&mut self, path: &Path) -> io::Result<Handle<Mesh>> {
+        if let Some(mesh) = lookup(&self.meshes, &path.to_path_buf()) {
+            return Ok(mesh);
+        }
+
+        let mesh = Handle::new(self.load_mesh(path)?);
+        self.meshes.insert(path.to_path_buf(), mesh.downgrade());
+        self.watch(AssetKey::Mesh(path.to_path_buf()), &[path]);
+
+        Ok(mesh)
+    }
+
+    fn watch(&mut self, asset: AssetKey, paths: &[&Path]) {
+        let paths = paths.iter().map(|path| path.to_path_buf()).collect::<Vec<_>>();
+        self.watch_paths(asset, &paths);
+    }
+
+    fn watch_paths(&mut self, asset: AssetKey, paths: &[PathBuf]) {
+        self.watches.retain(|watch| watch.asset != asset);
+
+        let mut files: Vec<(PathBuf, Option<SystemTime>)> = Vec::new();
+        for path in paths {
+            if !files.iter().any(|(watched, _)| watched == path) {
+                files.push((path.clone(), modified_time(path)));
+            }
+        };
+
+        self.watches.push(Watch { asset, files });
+    }
+
+    /// Checks watched files at most once per `poll_interval` and reloads any asset whose files
+    /// changed. An asset that fails to load keeps its current version and the error is logged.
+    /// Returns the number of assets reloaded.
+    pub fn reload_changed(&mut self) -> usize {
+        let now = time::get_time();
+        if now - self.last_poll < self.poll_interval {
+            return 0;
+        }
+        self.last_poll = now;
+
+        self.collect();
+
+        let mut changed = Vec::new();
+        for watch in &mut self.watches {
+            let mut dirty = false;
+
+            for &mut (ref path, ref mut modified) in &mut watch.files {
+                let current = modified_time(path);
+                if current != *modified {
+                    *modified = current;
+                    dirty = true;
+                }
+            };
+
+            if dirty {
+                changed.push(watch.asset.clone());
+            }
+        };
+
+        changed.into_iter().filter(|asset| self.reload(asset)).count()
+    }
+
+    fn reload(&mut self, asset: &AssetKey) -> bool {
+        match *asset {
+            AssetKey::Texture(ref key) => {
+                let tex = match lookup(&self.textures, key) {
+                    Some(tex) => tex,
+                    None => return false,
+                };
+
+                match Assets::load_texture(key) {
+                    Ok(new_tex) => {
+                        tex.replace(new_tex);
+                        println!("Reloaded {}", key.path.display());
+                        true
+                    },
+                    Err(err) => {
+                        println!("Failed to reload {}: {}", key.path.display(), err);
+                        false
+                    },
+                }
+            },
+
+            AssetKey::Program(ref key) => {
+                let program = match lookup(&self.programs, key) {
+                    Some(program) => program,
+                    None => return false,
+                };
+
+                match Program::from_stage_paths(&key.stage_paths(), &key.defines, self.program_cache.as_ref()) {
+                    Ok(new_program) => {
+                        // Includes may have changed too
+                        let source_paths = new_program.source_paths.clone();
+                        program.replace(new_program);
+                        self.watch_paths(asset.clone(), &source_paths);
+
+                        println!("Reloaded {}", key.name());
+                        true
+                    },
+                    Err(err) => {
+                        println!("Keeping previous program: {}", err);
+                        false
+                    },
+                }
+            },
+
+            AssetKey::Material(ref key) => {
+                let material = match lookup(&self.materials, key) {
+                    Some(material) => material,
+                    None => return false,
+                };
+
+                match self.load_material(key) {
+                    Ok(new_material) => {
+                        material.replace(new_material);
+                        println!("Reloaded {}", key.path.display());
+                        true
+                    },
+                    Err(err) => {
+                        println!("Failed to reload {}: {}", key.path.display(), err);
+                        false
+                    },
+                }
+            },
+
+            AssetKey::Mesh(ref path) => {
+                let mesh = match lookup(&self.meshes, path) {
+                    Some(mesh) => mesh,
+                    None => return false,
+                };
+
+                match self.load_mesh(path) {
+                    Ok(new_mesh) => {
+                        mesh.replace(new_mesh);
+                        println!("Reloaded {}", path.display());
+                        true
+                    },
+                    Err(err) => {
+                        println!("Failed to reload {}: {}", path.display(), err);
+                        false
+                    },
+                }
+            },
+        }
+    }
+
+    /// Forgets registry entries and watches whose assets have already been freed.
+    pub fn collect(&mut self) {
+        self.textures.retain(|_, weak| weak.upgrade().is_some());
+        self.samplers.retain(|(_, weak)| weak.upgrade().is_some());
+        self.programs.retain(|_, weak| weak.upgrade().is_some());
+        self.materials.retain(|_, weak| weak.upgrade().is_some());
+        self.meshes.retain(|_, weak| weak.upgrade().is_some());
+
+        let textures = &self.textures;
+        let programs = &self.programs;
+        let materials = &self.materials;
+        let meshes = &self.meshes;
+        self.watches.retain(|watch| match watch.asset {
+            AssetKey::Texture(ref key) => textures.contains_key(key),
+            AssetKey::Program(ref key) => programs.contains_key(key),
+            AssetKey::Material(ref key) => materials.contains_key(key),
+            AssetKey::Mesh(ref path) => meshes.contains_key(path),
+        });
+    }
+
+    /// Lists everything currently loaded. Mesh sizes cover vertex and index buffers only,
+    /// since their textures and programs are listed separately.
+    pub fn report(&self) -> Vec<AssetInfo> {
+        let mut infos = Vec::new();
+
+        for (key, weak) in &self.textures {
+            if let Some(tex) = weak.upgrade() {
+                infos.push(AssetInfo {
+                    kind: "texture",
+                    name: key.path.to_string_lossy().into_owned(),
+                    handles: tex.handle_count() - 1,
+                    byte_size: tex.borrow().byte_size,
+                });
+            };
+        };
+
+        for (params, weak) in &self.samplers {
+            if let Some(sampler) = weak.upgrade() {
+                infos.push(AssetInfo {
+                    kind: "sampler",
+                    name: format!("{:?}", params),
+                    handles: Rc::strong_count(&sampler) - 1,
+                    byte_size: 0,
+                });
+            };
+        };
+
+        for (key, weak) in &self.programs {
+            if let Some(program) = weak.upgrade() {
+                let defines = key.defines.keys().cloned().collect::<Vec<_>>().join(" ");
+
+                infos.push(AssetInfo {
+                    kind: "program",
+                    name: format!("{} [{}]", key.name(), defines),
+                    handles: program.handle_count() - 1,
+                    byte_size: program.borrow().binary_size(),
+                });
+            };
+        };
+
+        for (key, weak) in &self.materials {
+            if let Some(material) = weak.upgrade() {
+                let defines = key.defines.keys().cloned().collect::<Vec<_>>().join(" ");
+                let factors = if key.factors.is_some() { " with factors" } else { "" };
+
+                infos.push(AssetInfo {
+                    kind: "material",
+                    name: format!("{} [{}]{}", key.path.display(), defines, factors),
+                    handles: material.handle_count() - 1,
+                    byte_size: 0,
+                });
+            };
+        };
+
+        for (path, weak) in &self.meshes {
+            if let Some(mesh) = weak.upgrade() {
+                infos.push(AssetInfo {
+                    kind: "mesh",
+                    name: path.to_string_lossy().into_owned(),
+                    handles: mesh.handle_count() - 1,
+                    byte_size: mesh.borrow().byte_size(),
+                });
+            };
+        };
+
+        infos.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+        infos
+    }
+
+    pub fn print_report(&self) {
+        let infos = self.report();
+        let total: usize = infos.iter().map(|info| info.byte_size).sum();
+
+        println!("{} assets loaded, {} KiB", infos.len(), total / 1024);
+        for info in &infos {
+            println!("  {:8} {:>8} KiB  {:>3} handles  {}",
+                     info.kind, info.byte_size / 1024, info.handles, info.name);
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use gfx::material::{Cull, MaterialParam};
+    use gfx::lowlevel::test_context;
+    use gfx::material_file::MaterialDesc;
+    use gfx::model::{DEFAULT_MATERIAL, DISPLACED_MATERIAL};
+    use super::*;
+
+    /// Compiles and links every permutation meshes use of the material file at `path`.
+    fn link_material_programs(path: &str) -> Vec<Handle<Program>> {
+        test_context::make_current();
+
+        let mut assets = Assets::new();
+        assets.program_cache = None;
+
+        let desc = MaterialDesc::parse(&fs::read_to_string(path).unwrap()).unwrap();
+        let stages = desc.stages.iter().map(|&(stage, ref path)| (stage, path.as_path())).collect::<Vec<_>>();
+
+        [None, Some("INSTANCED"), Some("MULTI_DRAW")].iter().
+            map(|define| {
+                let mut defines = desc.defines.clone();
+                if let Some(define) = *define {
+                    defines.insert(String::from(define), String::from("1"));
+                }
+
+                assets.program_stages(&stages, &defines).unwrap_or_else(|err| panic!("{}", err))
+            }).
+            collect()
+    }
+
+    fn param(material: &Handle<Material>, name: &str) -> Option<MaterialParam> {
+        material.borrow().params.iter().find(|&(param, _)| param == name).map(|&(_, value)| value)
+    }
+
+    #[test]
+    fn factors_make_registered_materials() {
+        test_context::make_current();
+
+        let path = env::temp_dir().join(format!("four-kb-factors-{}.mat", process::id()));
+        let write = |extra: &str| {
+            fs::write(&path, format!("vertex assets/shader.vert\nfragment assets/shader.frag\n\
+                                      uniform float metallic_factor 0\n{}", extra)).unwrap();
+        };
+        write("");
+
+        let mut assets = Assets::new();
+        assets.program_cache = None;
+        let metal = MetallicRoughness { metallic: 1.0, ..Default::default() };
+        let rough = MetallicRoughness { roughness: 0.25, ..metal };
+
+        let plain = assets.material(&path, &Defines::new(), None).unwrap();
+        let with_metal = assets.material(&path, &Defines::new(), Some(metal)).unwrap();
+        assert!(assets.material(&path, &Defines::new(), Some(metal)).unwrap().ptr_eq(&with_metal));
+        assert!(!assets.material(&path, &Defines::new(), Some(rough)).unwrap().ptr_eq(&with_metal));
+
+        assert_eq!(param(&plain, "metallic_factor"), Some(MaterialParam::Float(0.0)));
+        assert_eq!(param(&with_metal, "metallic_factor"), Some(MaterialParam::Float(1.0)));
+
+        // Edits to the file reach the derived material, which keeps its factors
+        write("cull none\n");
+        let key = MaterialKey { path: path.clone(), defines: Defines::new(), factors: Some(Factors(metal)) };
+        assert!(assets.reload(&AssetKey::Material(key)));
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(with_metal.borrow().state.cull, Cull::None);
+        assert_eq!(param(&with_metal, "metallic_factor"), Some(MaterialParam::Float(1.0)));
+    }
+
+    #[test]
+    fn default_material_links() {
+        for program in link_material_programs(DEFAULT_MATERIAL) {
+            assert!(!program.borrow().is_tessellated());
+        };
+    }
+
+    #[test]
+    fn displaced_material_links() {
+        for program in link_material_programs(DISPLACED_MATERIAL) {
+            let program = program.borrow();
+            assert!(program.is_tessellated());
+            assert!(program.has_stage(ShaderStage::TessControl));
+            assert!(program.reflection.uniforms.contains_key("disp_tex"));
+        };
+    }
+}
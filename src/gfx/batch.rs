@@ -0,0 +1,287 @@
+use std::mem;
+use gl;
+use gl::types::*;
+use gfx::blocks::DRAW_DATA_BINDING;
+use gfx::lowlevel::*;
+use gfx::material::Material;
+use gfx::mesh::{InstanceData, Mesh};
+
+/// The command layout `glMultiDrawElementsIndirect` reads.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct DrawElementsIndirectCommand {
+    pub count: GLuint,
+    pub instance_count: GLuint,
+    pub first_index: GLuint,
+    pub base_vertex: GLint,
+    pub base_instance: GLuint,
+}
+
+/// Where a mesh's data sits in the shared buffers. The buffer names tell when the mesh was
+/// reloaded and the copy is stale.
+struct PooledMesh {
+    mesh: WeakHandle<Mesh>,
+    vertices: GLuint,
+    indices: GLuint,
+    /// Where the mesh's indices start; its submeshes are relative to this.
+    first_index: GLuint,
+    base_vertex: GLint,
+}
+
+/// Draws many meshes with a handful of calls: their vertices and indices are copied into
+/// shared buffers, and each frame every submesh of every object becomes one indirect command,
+/// with one `glMultiDrawElementsIndirect` per distinct material. Per-draw transforms and colors go in
+/// a storage buffer that shader.vert indexes with `gl_DrawIDARB`.
+///
+/// Only meshes with `multi_draw_materials` and the same vertex layout can share a batch. Their
+/// indices are widened to the widest type among them.
+pub struct MultiDraw {
+    pool: Vec<PooledMesh>,
+    layout: Option<(VertexLayout, IndexType)>,
+    /// Owns the shared vertex buffer.
+    vao: Option<VAO>,
+    indices: Option<IBO>,
+    commands: StreamBuffer<DrawElementsIndirectCommand>,
+    draws: StreamBuffer<InstanceData>,
+}
+
+/// Most commands in one `glMultiDrawElementsIndirect`; bigger groups take several.
+const COMMANDS_PER_DRAW: usize = 4096;
+
+impl MultiDraw {
+    /// `None` when the context can't draw indirectly or lacks `gl_DrawIDARB`.
+    pub fn new() -> Option<MultiDraw> {
+        if !caps().multi_draw_indirect {
+            return None;
+        }
+
+        let multi_draw = MultiDraw {
+            pool: Vec::new(),
+            layout: None,
+            vao: None,
+            indices: None,
+            commands: StreamBuffer::new(gl::DRAW_INDIRECT_BUFFER, COMMANDS_PER_DRAW),
+            draws: StreamBuffer::new(gl::SHADER_STORAGE_BUFFER, COMMANDS_PER_DRAW),
+        };
+        multi_draw.commands.set_label("multi-draw commands");
+        multi_draw.draws.set_label("multi-draw data");
+
+        Some(multi_draw)
+    }
+
+    fn find(&self, handle: &Handle<Mesh>) -> Option<usize> {
+        let mesh = handle.borrow();
+
+        self.pool.iter().position(|pooled| {
+            pooled.vertices == mesh.vertices.id() &&
+                pooled.indices == mesh.ibo.id() &&
+                pooled.mesh.upgrade().is_some_and(|pooled_mesh| pooled_mesh.ptr_eq(handle))
+        })
+    }
+
+    /// Replaces the shared buffers with copies of `meshes`, which must share a layout, with
+    /// their indices as `index_type`.
+    fn pack(&mut self, meshes: &[Handle<Mesh>], index_type: IndexType) {
+        let first = meshes[0].borrow();
+        let layout = first.layout().clone();
+
+        let vertex_bytes = meshes.iter().map(|mesh| mesh.borrow().vertices.byte_size).sum();
+        let index_count = meshes.iter().map(|mesh| mesh.borrow().ibo.len()).sum();
+        let vertices = VBO::uninitialized(vertex_bytes, BufferUsage::Static);
+        let indices = IBO::uninitialized(index_type, index_count, BufferUsage::Static);
+
+        self.pool.clear();
+        let mut vertex_offset = 0;
+        let mut first_index = 0;
+
+        for handle in meshes {
+            let mesh = handle.borrow();
+            vertices.copy_from(&mesh.vertices, 0, vertex_offset, mesh.vertices.byte_size);
+
+            // Narrower indices take a round trip through the CPU to be widened
+            if mesh.ibo.index_type == index_type {
+                indices.copy_from(&mesh.ibo, 0, first_index * index_type.size(), mesh.ibo.byte_size);
+            } else {
+                indices.write(first_index, &mesh.ibo.read());
+            }
+
+            self.pool.push(PooledMesh {
+                mesh: handle.downgrade(),
+                vertices: mesh.vertices.id(),
+                indices: mesh.ibo.id(),
+                first_index: first_index as GLuint,
+                base_vertex: (vertex_offset / layout.stride) as GLint,
+            });
+
+            vertex_offset += mesh.vertices.byte_size;
+            first_index += mesh.ibo.len();
+        };
+
+        vertices.set_label("multi-draw vertices");
+        indices.set_label("multi-draw indices");
+
+        // Attribute locations are fixed in the shaders, so any of the programs will do
+        let vao = {
+            let material = material_of(&first, 0);
+            let material = material.borrow();
+            let vao = VAOBuilder::new(&material.program.borrow()).
+                buffer(vertices, layout.clone()).
+                index_buffer(&indices).
+                build();
+            vao
+        };
+
+        vao.set_label("multi-draw");
+        self.vao = Some(vao);
+        self.indices = Some(indices);
+        self.layout = Some((layout, index_type));
+    }
+
+    /// Draws what it can of `objects`, returning the rest for the caller to draw some other
+    /// way. Frame state comes from the blocks bound by the caller.
+    pub fn draw(&mut self, objects: Vec<(Handle<Mesh>, InstanceData)>) -> Vec<(Handle<Mesh>, InstanceData)> {
+        // The first batchable mesh decides the layout this frame
+        let layout = objects.iter().
+            find(|&(mesh, _)| !mesh.borrow().multi_draw_materials.is_empty()).
+            map(|(mesh, _)| mesh.borrow().layout().clone());
+
+        let layout = match layout {
+            Some(layout) => layout,
+            None => return objects,
+        };
+
+        let (batched, rest): (Vec<_>, Vec<_>) = objects.into_iter().partition(|(mesh, _)| {
+            let mesh = mesh.borrow();
+            !mesh.multi_draw_materials.is_empty() && *mesh.layout() == layout
+        });
+
+        let mut meshes: Vec<Handle<Mesh>> = Vec::new();
+        for (mesh, _) in &batched {
+            if !meshes.iter().any(|other| other.ptr_eq(mesh)) {
+                meshes.push(mesh.clone());
+            }
+        };
+
+        let index_type = meshes.iter().map(|mesh| mesh.borrow().ibo.index_type).max().unwrap();
+        let layout = (layout, index_type);
+        let stale = self.layout.as_ref() != Some(&layout) || meshes.iter().any(|mesh| self.find(mesh).is_none());
+        if stale {
+            self.pack(&meshes, index_type);
+        }
+
+        // One command per submesh, grouped by material so each group is one call
+        let mut groups: Vec<(Handle<Material>, Vec<DrawElementsIndirectCommand>, Vec<InstanceData>)> = Vec::new();
+        for (handle, instance) in batched {
+            let pooled = &self.pool[self.find(&handle).unwrap()];
+            let mesh = handle.borrow();
+
+            for submesh in &mesh.submeshes {
+                let command = DrawElementsIndirectCommand {
+                    count: submesh.count,
+                    instance_count: 1,
+                    first_index: pooled.first_index + submesh.first_index,
+                    base_vertex: pooled.base_vertex,
+                    base_instance: 0,
+                };
+
+                let material = material_of(&mesh, submesh.material);
+                let group = groups.iter().position(|group| {
+                    group.0.ptr_eq(&material) || group.0.borrow().same_bindings(&material.borrow())
+                });
+
+                // Draw data is indexed by draw ID, so each command gets its own copy
+                match group {
+                    Some(group) => {
+                        groups[group].1.push(command);
+                        groups[group].2.push(instance);
+                    },
+                    None => groups.push((material, vec![command], vec![instance])),
+                };
+            };
+        };
+
+        self.commands.begin_frame();
+        self.draws.begin_frame();
+
+        self.vao.as_ref().unwrap().bind();
+        self.commands.bind();
+        self.draws.bind_base(DRAW_DATA_BINDING);
+
+        let capacity = self.commands.capacity().min(self.draws.capacity());
+        for (material, group_commands, group_draws) in &groups {
+            let material = material.borrow();
+            let _group = debug_group_with(|| {
+                format!("multi-draw {} x{}", material.program.borrow().label(), group_commands.len())
+            });
+            let mode = Mesh::bind_material(&material);
+
+            for (commands, draws) in group_commands.chunks(capacity).zip(group_draws.chunks(capacity)) {
+                let commands_offset = self.commands.push(commands);
+                let draw_offset = self.draws.push(draws) / mem::size_of::<InstanceData>();
+                material.program.borrow().set_uniform("draw_offset", &(draw_offset as i32));
+
+                unsafe {
+                    gl::MultiDrawElementsIndirect(mode, index_type.gl_enum(), commands_offset as *const GLvoid,
+                                                  commands.len() as GLsizei, 0);
+                };
+            };
+
+            material.unbind();
+        };
+
+        rest
+    }
+}
+
+fn material_of(mesh: &Mesh, material: usize) -> Handle<Material> {
+    mesh.multi_draw_materials[material].clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use cgmath::{Matrix4, One, Vector4, Zero};
+    use gfx::assets::Assets;
+    use gfx::lowlevel::test_context;
+    use gfx::mesh::{MeshVertex, Submesh};
+    use super::*;
+
+    fn mesh(material: &Handle<Material>, vertex_count: usize, indices: &[u32]) -> Handle<Mesh> {
+        let vertex = MeshVertex { position: Zero::zero(), normal: Zero::zero(), tangent: Zero::zero(),
+                                  texcoord: Zero::zero() };
+        let submesh = Submesh { first_index: 0, count: indices.len() as u32, material: 0 };
+
+        let mut mesh = Mesh::new(vec![material.clone()], vec![submesh], indices, &vec![vertex; vertex_count]);
+        mesh.multi_draw_materials = vec![material.clone()];
+        Handle::new(mesh)
+    }
+
+    #[test]
+    fn meshes_with_different_index_types_share_a_batch() {
+        test_context::make_current();
+
+        let mut assets = Assets::new();
+        assets.program_cache = None;
+        let mut defines = Defines::new();
+        defines.insert(String::from("MULTI_DRAW"), String::from("1"));
+        let stages = [(ShaderStage::Vertex, Path::new("assets/shader.vert")),
+                      (ShaderStage::Fragment, Path::new("assets/shader.frag"))];
+        let program = assets.program_stages(&stages, &defines).unwrap();
+        let material = Handle::new(Material::with_program(program));
+
+        let narrow = mesh(&material, 3, &[0, 1, 2]);
+        let wide = mesh(&material, (1 << 16) + 1, &[0, 1, 1 << 16]);
+        assert_eq!(narrow.borrow().ibo.index_type, IndexType::U16);
+        assert_eq!(wide.borrow().ibo.index_type, IndexType::U32);
+
+        let draw = InstanceData { instance_trans: Matrix4::one(), instance_color: Vector4::new(1.0, 1.0, 1.0, 1.0) };
+        let mut multi_draw = MultiDraw::new().unwrap();
+        let rest = multi_draw.draw(vec![(narrow, draw), (wide, draw)]);
+        assert!(rest.is_empty());
+
+        let indices = multi_draw.indices.as_ref().unwrap();
+        assert_eq!(indices.index_type, IndexType::U32);
+        assert_eq!(indices.read(), vec![0, 1, 2, 0, 1, 1 << 16]);
+        assert_eq!(multi_draw.pool.iter().map(|pooled| pooled.first_index).collect::<Vec<_>>(), vec![0, 3]);
+    }
+}
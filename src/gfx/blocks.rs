@@ -0,0 +1,31 @@
+use cgmath::{Matrix4, Point3, Vector4};
+use gl::types::*;
+
+/// Binding points of the standard blocks, matching the layout qualifiers in common.glsl.
+pub const FRAME_BINDING: GLuint = 0;
+pub const OBJECT_BINDING: GLuint = 1;
+/// Storage buffer binding of the per-draw data read by multi-draw batches, matching
+/// shader.vert.
+pub const DRAW_DATA_BINDING: GLuint = 0;
+/// Storage buffer binding of the particles, matching particles.glsl.
+pub const PARTICLE_BINDING: GLuint = 1;
+
+std140_block! {
+    /// Camera state shared by every draw in a frame.
+    pub struct FrameBlock: "Frame" {
+        pub view: Matrix4<f32>,
+        pub proj: Matrix4<f32>,
+        pub camera_pos: Point3<f32>,
+        /// Seconds since startup.
+        pub time: f32,
+    }
+}
+
+std140_block! {
+    /// Per-draw state of a scene object.
+    pub struct ObjectBlock: "Object" {
+        pub trans: Matrix4<f32>,
+        /// Multiplies the material's color.
+        pub color: Vector4<f32>,
+    }
+}
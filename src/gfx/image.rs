@@ -2,6 +2,7 @@ use png;
 use std::fs::File;
 use std::path::Path;
 use std::io;
+use std::f32::consts::PI;
 use gl;
 
 #[derive(Debug)]
@@ -12,12 +13,163 @@ pub struct Image {
 	pub color_type: u32,
 }
 
+impl Image {
+    /// A `width` x `height` image filled with a single RGBA color.
+    pub fn solid(width: u32, height: u32, color: [u8; 4]) -> Image {
+        let data = color.iter().cloned().cycle().take((width * height * 4) as usize).collect();
+
+        Image { width, height, data, color_type: gl::RGBA }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self.color_type {
+            gl::RGBA => 4,
+            _ => 3,
+        }
+    }
+
+    /// Copies out the `width` x `height` block starting at (`x`, `y`).
+    pub fn sub_image(&self, x: u32, y: u32, width: u32, height: u32) -> Image {
+        let channels = self.channels();
+        let mut data = Vec::with_capacity(width as usize * height as usize * channels);
+
+        for row in y..(y + height) {
+            let start = (row as usize * self.width as usize + x as usize) * channels;
+            data.extend_from_slice(&self.data[start..(start + width as usize * channels)]);
+        };
+
+        Image { width, height, data, color_type: self.color_type }
+    }
+
+    /// Rotates the image by 180 degrees.
+    pub fn rotated_180(&self) -> Image {
+        let channels = self.channels();
+        let mut data = Vec::with_capacity(self.data.len());
+
+        for pixel in self.data.chunks(channels).rev() {
+            data.extend_from_slice(pixel);
+        };
+
+        Image { width: self.width, height: self.height, data, color_type: self.color_type }
+    }
+
+    fn sample_bilinear(&self, u: f32, v: f32, out: &mut Vec<u8>) {
+        let channels = self.channels();
+        let x = u * self.width as f32 - 0.5;
+        let y = (v * self.height as f32 - 0.5).max(0.0);
+        let fx = x - x.floor();
+        let fy = y - y.floor();
+
+        // Wrap horizontally around the seam, clamp vertically at the poles
+        let x0 = ((x.floor() as i64).rem_euclid(self.width as i64)) as usize;
+        let x1 = (x0 + 1) % self.width as usize;
+        let y0 = (y.floor() as usize).min(self.height as usize - 1);
+        let y1 = (y0 + 1).min(self.height as usize - 1);
+
+        let texel = |x: usize, y: usize, c: usize| {
+            self.data[(y * self.width as usize + x) * channels + c] as f32
+        };
+
+        for c in 0..channels {
+            let top = texel(x0, y0, c) * (1.0 - fx) + texel(x1, y0, c) * fx;
+            let bottom = texel(x0, y1, c) * (1.0 - fx) + texel(x1, y1, c) * fx;
+            out.push((top * (1.0 - fy) + bottom * fy).round() as u8);
+        };
+    }
+}
+
+/// Loads a volume stored as a horizontal strip of square slices, as used by color-grading
+/// LUTs: a 16x16x16 volume is a 256x16 image. Returns the slices front to back. Texture
+/// arrays are stored the same way, one layer per slice.
+pub fn load_volume(path: &Path) -> io::Result<Vec<Image>> {
+    let image = load_image(path)?;
+
+    if image.height == 0 || image.width % image.height != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("{}: volume strip {}x{} isn't a row of square slices",
+                                          path.display(), image.width, image.height)));
+    }
+
+    let size = image.height;
+    Ok((0..(image.width / size)).
+        map(|slice| image.sub_image(slice * size, 0, size, size)).
+        collect())
+}
+
+/// Cube map faces in GL order: +X, -X, +Y, -Y, +Z, -Z.
+pub type CubeFaces = [Image; 6];
+
+/// Loads a cube map stored in a single image. The layout is picked from the aspect ratio:
+/// 4:3 is a horizontal cross, 3:4 a vertical cross, and 2:1 an equirectangular panorama.
+pub fn load_cube_map(path: &Path) -> io::Result<CubeFaces> {
+    let image = load_image(path)?;
+
+    if image.width * 3 == image.height * 4 {
+        Ok(cross_to_cube_faces(&image, false))
+    } else if image.width * 4 == image.height * 3 {
+        Ok(cross_to_cube_faces(&image, true))
+    } else if image.width == image.height * 2 {
+        let face_size = image.height / 2;
+        Ok(equirect_to_cube_faces(&image, face_size))
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData,
+                           format!("{}: unrecognised cube map layout {}x{}",
+                                   path.display(), image.width, image.height)))
+    }
+}
+
+/// Splits a cross layout into faces. The horizontal cross has -X, +Z, +X, -Z along the middle
+/// row; the vertical cross stacks +Y, +Z, -Y, -Z down the middle column, with -Z upside down.
+pub fn cross_to_cube_faces(image: &Image, vertical: bool) -> CubeFaces {
+    let size = if vertical { image.width / 3 } else { image.width / 4 };
+    let face = |x: u32, y: u32| image.sub_image(x * size, y * size, size, size);
+
+    if vertical {
+        [face(2, 1), face(0, 1), face(1, 0), face(1, 2), face(1, 1), face(1, 3).rotated_180()]
+    } else {
+        [face(2, 1), face(0, 1), face(1, 0), face(1, 2), face(1, 1), face(3, 1)]
+    }
+}
+
+/// Resamples an equirectangular panorama into six `face_size` square faces.
+pub fn equirect_to_cube_faces(image: &Image, face_size: u32) -> CubeFaces {
+    let face = |index: usize| {
+        let mut data = Vec::with_capacity((face_size * face_size) as usize * image.channels());
+
+        for y in 0..face_size {
+            for x in 0..face_size {
+                let s = 2.0 * (x as f32 + 0.5) / face_size as f32 - 1.0;
+                let t = 2.0 * (y as f32 + 0.5) / face_size as f32 - 1.0;
+
+                let (dx, dy, dz) = match index {
+                    0 => (1.0, -t, -s),
+                    1 => (-1.0, -t, s),
+                    2 => (s, 1.0, t),
+                    3 => (s, -1.0, -t),
+                    4 => (s, -t, 1.0),
+                    _ => (-s, -t, -1.0),
+                };
+
+                let len = (dx * dx + dy * dy + dz * dz).sqrt();
+                let u = 0.5 + dx.atan2(-dz) / (2.0 * PI);
+                let v = (dy / len).acos() / PI;
+
+                image.sample_bilinear(u, v, &mut data);
+            };
+        };
+
+        Image { width: face_size, height: face_size, data, color_type: image.color_type }
+    };
+
+    [face(0), face(1), face(2), face(3), face(4), face(5)]
+}
+
 /// Load the image using `png`
 pub fn load_image(path: &Path) -> io::Result<Image> {
-    let decoder = png::Decoder::new(try!(File::open(path)));
-    let (info, mut reader) = try!(decoder.read_info());
+    let decoder = png::Decoder::new(File::open(path)?);
+    let (info, mut reader) = decoder.read_info()?;
     let mut img_data = vec![0; info.buffer_size()];
-    try!(reader.next_frame(&mut img_data));
+    reader.next_frame(&mut img_data)?;
 			
 	let color_type = match info.color_type {
 		png::ColorType::RGB => gl::RGB,
@@ -29,6 +181,6 @@ pub fn load_image(path: &Path) -> io::Result<Image> {
 		width: info.width,
 		height: info.height,
 		data: img_data,
-		color_type: color_type,
+		color_type,
 	})
 }
\ No newline at end of file
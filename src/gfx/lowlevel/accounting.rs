@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use gl;
+use gl::types::*;
+
+/// What kind of GL object an accounting entry is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+	VertexBuffer,
+	IndexBuffer,
+	UniformBuffer,
+	StorageBuffer,
+	/// Indirect commands, staging and anything else.
+	OtherBuffer,
+	VertexArray,
+	Texture,
+	Renderbuffer,
+	Program,
+	Framebuffer,
+}
+
+impl Category {
+	pub fn for_buffer_target(target: GLenum) -> Category {
+		match target {
+			gl::ARRAY_BUFFER => Category::VertexBuffer,
+			gl::ELEMENT_ARRAY_BUFFER => Category::IndexBuffer,
+			gl::UNIFORM_BUFFER => Category::UniformBuffer,
+			gl::SHADER_STORAGE_BUFFER => Category::StorageBuffer,
+			_ => Category::OtherBuffer,
+		}
+	}
+
+	/// The GL namespace the object's name lives in, as passed to `glObjectLabel`.
+	pub fn namespace(self) -> GLenum {
+		match self {
+			Category::VertexBuffer | Category::IndexBuffer | Category::UniformBuffer |
+			Category::StorageBuffer | Category::OtherBuffer => gl::BUFFER,
+			Category::VertexArray => gl::VERTEX_ARRAY,
+			Category::Texture => gl::TEXTURE,
+			Category::Renderbuffer => gl::RENDERBUFFER,
+			Category::Program => gl::PROGRAM,
+			Category::Framebuffer => gl::FRAMEBUFFER,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Category::VertexBuffer => "vertex buffers",
+			Category::IndexBuffer => "index buffers",
+			Category::UniformBuffer => "uniform buffers",
+			Category::StorageBuffer => "storage buffers",
+			Category::OtherBuffer => "other buffers",
+			Category::VertexArray => "vertex arrays",
+			Category::Texture => "textures",
+			Category::Renderbuffer => "renderbuffers",
+			Category::Program => "programs",
+			Category::Framebuffer => "framebuffers",
+		}
+	}
+}
+
+/// A live GL object.
+#[derive(Clone, Debug)]
+pub struct TrackedObject {
+	pub category: Category,
+	pub id: GLuint,
+	pub label: String,
+	/// Driver-side memory it was allocated, as far as we can tell; 0 for containers like
+	/// vertex arrays.
+	pub byte_size: usize,
+}
+
+// Keyed by namespace and name, which is what GL itself identifies objects by
+static OBJECTS: Mutex<BTreeMap<(GLenum, GLuint), TrackedObject>> = Mutex::new(BTreeMap::new());
+
+fn with_objects<R, F: FnOnce(&mut BTreeMap<(GLenum, GLuint), TrackedObject>) -> R>(f: F) -> R {
+	let mut objects = OBJECTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	f(&mut objects)
+}
+
+/// Records a newly created object. The lowlevel wrappers call this; there's no need to
+/// elsewhere.
+pub fn track(category: Category, id: GLuint, label: &str, byte_size: usize) {
+	with_objects(|objects| {
+		objects.insert((category.namespace(), id), TrackedObject {
+			category,
+			id,
+			label: String::from(label),
+			byte_size,
+		});
+	});
+}
+
+/// Updates an object's size after it's reallocated.
+pub fn track_size(category: Category, id: GLuint, byte_size: usize) {
+	with_objects(|objects| {
+		if let Some(object) = objects.get_mut(&(category.namespace(), id)) {
+			object.byte_size = byte_size;
+		}
+	});
+}
+
+/// Renames an object; called by `label_object`, so the table has the same names as captures.
+pub fn track_label(namespace: GLenum, id: GLuint, label: &str) {
+	with_objects(|objects| {
+		if let Some(object) = objects.get_mut(&(namespace, id)) {
+			object.label = String::from(label);
+		}
+	});
+}
+
+pub fn untrack(category: Category, id: GLuint) {
+	with_objects(|objects| {
+		objects.remove(&(category.namespace(), id));
+	});
+}
+
+/// Every object still alive.
+pub fn live_objects() -> Vec<TrackedObject> {
+	with_objects(|objects| objects.values().cloned().collect())
+}
+
+/// Count and total size of one category.
+#[derive(Clone, Copy, Debug)]
+pub struct CategoryTotal {
+	pub category: Category,
+	pub count: usize,
+	pub byte_size: usize,
+}
+
+/// Totals of the categories that have live objects, in category order.
+pub fn totals() -> Vec<CategoryTotal> {
+	totals_of(live_objects())
+}
+
+fn totals_of(objects: Vec<TrackedObject>) -> Vec<CategoryTotal> {
+	let mut totals: BTreeMap<Category, CategoryTotal> = BTreeMap::new();
+
+	for object in objects {
+		let total = totals.entry(object.category).
+			or_insert(CategoryTotal { category: object.category, count: 0, byte_size: 0 });
+		total.count += 1;
+		total.byte_size += object.byte_size;
+	};
+
+	totals.into_values().collect()
+}
+
+struct Bytes(usize);
+
+impl fmt::Display for Bytes {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let bytes = self.0 as f64;
+
+		if bytes >= 1024.0 * 1024.0 {
+			write!(f, "{:.1} MiB", bytes / (1024.0 * 1024.0))
+		} else if bytes >= 1024.0 {
+			write!(f, "{:.1} KiB", bytes / 1024.0)
+		} else {
+			write!(f, "{} B", self.0)
+		}
+	}
+}
+
+/// One line with the overall total and each category's, for periodic logging.
+pub fn summary() -> String {
+	let totals = totals();
+	let bytes = totals.iter().map(|total| total.byte_size).sum();
+
+	let categories = totals.iter().
+		map(|total| format!("{} {} ({})", total.count, total.category.name(), Bytes(total.byte_size))).
+		collect::<Vec<_>>().
+		join(", ");
+
+	format!("GPU memory {}: {}", Bytes(bytes), categories)
+}
+
+/// Lists every object still alive. Call once everything should have been dropped, at
+/// shutdown; anything listed was leaked, typically by a reference cycle between handles.
+/// Returns whether anything was.
+pub fn report_leaks() -> bool {
+	let objects = live_objects();
+	if objects.is_empty() {
+		return false;
+	}
+
+	println!("{} GL objects still alive:", objects.len());
+	for object in &objects {
+		println!("    {:?} {} \"{}\", {}", object.category, object.id, object.label, Bytes(object.byte_size));
+	};
+
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use gl;
+	use gfx::lowlevel::buffer::{Buffer, BufferUsage};
+	use gfx::lowlevel::debug::label_object;
+	use gfx::lowlevel::test_context;
+	use gfx::lowlevel::texture::{Texture, TextureFormat};
+	use super::*;
+
+	// The table is shared by every test thread, and each has a context of its own handing out
+	// the same names, so tests only look at the entries they made
+
+	fn totals_among(ids: &[GLuint]) -> Vec<(Category, usize, usize)> {
+		let objects = live_objects().into_iter().
+			filter(|object| ids.contains(&object.id)).
+			collect();
+
+		totals_of(objects).iter().
+			map(|total| (total.category, total.count, total.byte_size)).
+			collect()
+	}
+
+	fn is_live(label: &str) -> bool {
+		live_objects().iter().any(|object| object.label == label)
+	}
+
+	#[test]
+	fn tracking_updates_the_totals() {
+		// Far past any name a test context hands out
+		let ids = [0xffff_ff00, 0xffff_ff01, 0xffff_ff02];
+
+		track(Category::Texture, ids[0], "first", 256);
+		track(Category::Texture, ids[1], "second", 1024);
+		track(Category::VertexBuffer, ids[2], "vertices", 64);
+		assert_eq!(totals_among(&ids), vec![(Category::VertexBuffer, 1, 64), (Category::Texture, 2, 1280)]);
+
+		track_size(Category::VertexBuffer, ids[2], 128);
+		untrack(Category::Texture, ids[0]);
+		assert_eq!(totals_among(&ids), vec![(Category::VertexBuffer, 1, 128), (Category::Texture, 1, 1024)]);
+
+		untrack(Category::Texture, ids[1]);
+		untrack(Category::VertexBuffer, ids[2]);
+		assert!(totals_among(&ids).is_empty());
+	}
+
+	#[test]
+	fn names_are_per_namespace() {
+		let id = 0xffff_ff10;
+
+		track(Category::IndexBuffer, id, "indices", 32);
+		track(Category::Texture, id, "texture", 16);
+		untrack(Category::IndexBuffer, id);
+		assert_eq!(totals_among(&[id]), vec![(Category::Texture, 1, 16)]);
+
+		untrack(Category::Texture, id);
+	}
+
+	#[test]
+	fn dropped_objects_are_untracked() {
+		test_context::make_current();
+
+		let buffer = Buffer::new(gl::ARRAY_BUFFER, &[0.0f32; 16], BufferUsage::Static);
+		label_object(gl::BUFFER, buffer.id(), "accounting test buffer");
+		let texture = Texture::new_empty("accounting test texture", TextureFormat::Rgba16F, 4, 4, 1);
+		assert!(is_live("accounting test buffer"));
+		assert!(is_live("accounting test texture"));
+
+		drop(buffer);
+		drop(texture);
+		assert!(!is_live("accounting test buffer"));
+		assert!(!is_live("accounting test texture"));
+	}
+}
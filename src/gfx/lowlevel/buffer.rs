@@ -0,0 +1,687 @@
+use std::mem;
+use std::ptr;
+use std::marker::PhantomData;
+use gl;
+use gl::types::*;
+use gfx::lowlevel::accounting::{self, Category};
+use gfx::lowlevel::caps::caps;
+use gfx::lowlevel::debug::label_object;
+
+/// How often a buffer's contents are expected to change; a hint for where the driver puts it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferUsage {
+	/// Written once, drawn many times.
+	Static,
+	/// Rewritten every frame.
+	Stream,
+	/// Written by shaders and read back or reused by the GPU.
+	GpuWritten,
+}
+
+impl BufferUsage {
+	pub fn gl_enum(self) -> GLenum {
+		match self {
+			BufferUsage::Static => gl::STATIC_DRAW,
+			BufferUsage::Stream => gl::STREAM_DRAW,
+			BufferUsage::GpuWritten => gl::DYNAMIC_COPY,
+		}
+	}
+}
+
+/// A GL buffer object. Zero-length buffers are allowed; they just can't be written to.
+///
+/// Edits go through direct state access when available. Otherwise they bind the buffer to
+/// `COPY_WRITE_BUFFER`, which no draw state depends on, so a bound vertex array is never
+/// disturbed.
+pub struct Buffer {
+	id: GLuint,
+	target: GLenum,
+	pub usage: BufferUsage,
+	/// Allocated size, which may be more than the data last streamed into it.
+	pub byte_size: usize,
+}
+
+// What `glBindBufferRange` offsets into a buffer bound to `target` must be a multiple of
+fn offset_alignment(target: GLenum) -> usize {
+	let mut alignment = 1;
+	if target == gl::UNIFORM_BUFFER {
+		unsafe { gl::GetIntegerv(gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT, &mut alignment) };
+	}
+	alignment.max(1) as usize
+}
+
+fn create_buffer() -> GLuint {
+	let mut id = 0;
+	unsafe {
+		if caps().direct_state_access {
+			gl::CreateBuffers(1, &mut id);
+		} else {
+			gl::GenBuffers(1, &mut id);
+		}
+	};
+	id
+}
+
+impl Buffer {
+	fn create(target: GLenum, usage: BufferUsage) -> Buffer {
+		let id = create_buffer();
+		accounting::track(Category::for_buffer_target(target), id, &format!("buffer {}", id), 0);
+
+		Buffer { id, target, usage, byte_size: 0 }
+	}
+
+	pub fn new<T>(target: GLenum, data: &[T], usage: BufferUsage) -> Buffer {
+		let mut buffer = Buffer::create(target, usage);
+		buffer.allocate(mem::size_of_val(data), data.as_ptr() as *const GLvoid);
+		buffer
+	}
+
+	/// A buffer of `byte_size` bytes with undefined contents.
+	pub fn uninitialized(target: GLenum, byte_size: usize, usage: BufferUsage) -> Buffer {
+		let mut buffer = Buffer::create(target, usage);
+		buffer.allocate(byte_size, ptr::null());
+		buffer
+	}
+
+	fn allocate(&mut self, byte_size: usize, data: *const GLvoid) {
+		let data = if byte_size == 0 { ptr::null() } else { data };
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::NamedBufferData(self.id, byte_size as GLsizeiptr, data, self.usage.gl_enum());
+			} else {
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, self.id);
+				gl::BufferData(gl::COPY_WRITE_BUFFER, byte_size as GLsizeiptr, data, self.usage.gl_enum());
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, 0);
+			}
+		};
+
+		self.byte_size = byte_size;
+		accounting::track_size(Category::for_buffer_target(self.target), self.id, byte_size);
+	}
+
+	pub fn id(&self) -> GLuint {
+		self.id
+	}
+
+	pub fn bind(&self) {
+		unsafe {
+			gl::BindBuffer(self.target, self.id);
+		}
+	}
+
+	/// Names the buffer in debug output and captures.
+	pub fn set_label(&self, name: &str) {
+		label_object(gl::BUFFER, self.id, name);
+	}
+
+	/// Attaches the buffer to an indexed binding point of its target, for uniform and storage
+	/// buffers.
+	pub fn bind_base(&self, index: GLuint) {
+		unsafe {
+			gl::BindBufferBase(self.target, index, self.id);
+		}
+	}
+
+	/// Overwrites part of the buffer, starting `offset` elements of `T` in.
+	pub fn update<T>(&self, offset: usize, data: &[T]) {
+		self.update_at(offset * mem::size_of::<T>(), data);
+	}
+
+	// Like `update`, for byte offsets that aren't a whole number of elements
+	fn update_at<T>(&self, byte_offset: usize, data: &[T]) {
+		let byte_len = mem::size_of_val(data);
+		assert!(byte_offset + byte_len <= self.byte_size, "update past the end of a buffer");
+
+		if byte_len == 0 {
+			return;
+		}
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::NamedBufferSubData(self.id, byte_offset as GLintptr, byte_len as GLsizeiptr,
+									   data.as_ptr() as *const GLvoid);
+			} else {
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, self.id);
+				gl::BufferSubData(gl::COPY_WRITE_BUFFER, byte_offset as GLintptr, byte_len as GLsizeiptr,
+								  data.as_ptr() as *const GLvoid);
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, 0);
+			}
+		};
+	}
+
+	/// Copies `byte_len` bytes of `source`, starting at `source_offset`, to `offset` in this
+	/// buffer, without a round trip through the CPU.
+	pub fn copy_from(&self, source: &Buffer, source_offset: usize, offset: usize, byte_len: usize) {
+		assert!(source_offset + byte_len <= source.byte_size, "copy past the end of a buffer");
+		assert!(offset + byte_len <= self.byte_size, "copy past the end of a buffer");
+
+		if byte_len == 0 {
+			return;
+		}
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::CopyNamedBufferSubData(source.id, self.id, source_offset as GLintptr, offset as GLintptr,
+										   byte_len as GLsizeiptr);
+			} else {
+				gl::BindBuffer(gl::COPY_READ_BUFFER, source.id);
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, self.id);
+				gl::CopyBufferSubData(gl::COPY_READ_BUFFER, gl::COPY_WRITE_BUFFER, source_offset as GLintptr,
+									  offset as GLintptr, byte_len as GLsizeiptr);
+				gl::BindBuffer(gl::COPY_READ_BUFFER, 0);
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, 0);
+			}
+		};
+	}
+
+	/// Reallocates to `byte_size` bytes, keeping the contents up to the smaller of the old and
+	/// new sizes; anything past the old size is undefined. The buffer keeps its name, so
+	/// vertex arrays and bindings that refer to it stay valid.
+	pub fn resize(&mut self, byte_size: usize) {
+		let kept = self.byte_size.min(byte_size);
+		if kept == 0 {
+			self.allocate(byte_size, ptr::null());
+			return;
+		}
+
+		// GL can't reallocate in place, so the kept part goes through a scratch buffer
+		let scratch = Buffer::uninitialized(gl::COPY_READ_BUFFER, kept, BufferUsage::Stream);
+		scratch.copy_from(self, 0, 0, kept);
+		self.allocate(byte_size, ptr::null());
+		self.copy_from(&scratch, 0, 0, kept);
+	}
+
+	/// Detaches the current storage so the next write doesn't wait for draws still reading
+	/// it; the driver frees the old storage once they're done.
+	pub fn orphan(&mut self) {
+		let byte_size = self.byte_size;
+		self.allocate(byte_size, ptr::null());
+	}
+
+	/// Maps the whole buffer with `access` (`gl::MAP_READ_BIT` and so on). Null for an empty
+	/// buffer or when mapping fails. Must be paired with `unmap`.
+	pub fn map(&self, access: GLbitfield) -> *mut GLvoid {
+		if self.byte_size == 0 {
+			return ptr::null_mut();
+		}
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::MapNamedBufferRange(self.id, 0, self.byte_size as GLsizeiptr, access)
+			} else {
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, self.id);
+				let data = gl::MapBufferRange(gl::COPY_WRITE_BUFFER, 0, self.byte_size as GLsizeiptr, access);
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, 0);
+				data
+			}
+		}
+	}
+
+	pub fn unmap(&self) {
+		if self.byte_size == 0 {
+			return;
+		}
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::UnmapNamedBuffer(self.id);
+			} else {
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, self.id);
+				gl::UnmapBuffer(gl::COPY_WRITE_BUFFER);
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, 0);
+			}
+		};
+	}
+}
+
+impl Drop for Buffer {
+	fn drop(&mut self) {
+		accounting::untrack(Category::for_buffer_target(self.target), self.id);
+
+		unsafe {
+			gl::DeleteBuffers(1, &self.id);
+		}
+	}
+}
+
+/// Regions of the rings behind `StreamBuffer`: the one being written plus the ones the GPU may
+/// still be reading.
+pub const FRAMES_IN_FLIGHT: usize = 3;
+
+/// A persistently mapped buffer split into `regions` equal parts, written in turn while the GPU
+/// reads the others. Leaving a region fences it behind the draws issued so far, and the CPU only
+/// waits if it laps the GPU.
+pub struct RingBuffer<T: Copy> {
+	id: GLuint,
+	target: GLenum,
+	data: *mut u8,
+	/// Elements per region.
+	pub capacity: usize,
+	/// Bytes from one region to the next, including the padding aligned pushes may need.
+	region_size: usize,
+	/// Multiple that offsets returned by `push` are rounded up to.
+	alignment: usize,
+	fences: Vec<GLsync>,
+	region: usize,
+	/// Bytes written to the current region.
+	cursor: usize,
+	_element: PhantomData<T>,
+}
+
+impl<T: Copy> RingBuffer<T> {
+	/// `None` when the context lacks `ARB_buffer_storage` (GL 4.4).
+	pub fn new(target: GLenum, capacity: usize, regions: usize) -> Option<RingBuffer<T>> {
+		assert!(regions > 0, "a ring buffer needs at least one region");
+
+		if !caps().buffer_storage {
+			return None;
+		}
+
+		// Room for `capacity` elements however they're split between pushes, each one aligned
+		let alignment = offset_alignment(target);
+		let region_size = capacity * mem::size_of::<T>().next_multiple_of(alignment);
+
+		let byte_size = (region_size * regions).max(1);
+		let flags = gl::MAP_WRITE_BIT | gl::MAP_PERSISTENT_BIT | gl::MAP_COHERENT_BIT;
+		let id = create_buffer();
+
+		let data = unsafe {
+			if caps().direct_state_access {
+				gl::NamedBufferStorage(id, byte_size as GLsizeiptr, ptr::null(), flags);
+				gl::MapNamedBufferRange(id, 0, byte_size as GLsizeiptr, flags)
+			} else {
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, id);
+				gl::BufferStorage(gl::COPY_WRITE_BUFFER, byte_size as GLsizeiptr, ptr::null(), flags);
+				let data = gl::MapBufferRange(gl::COPY_WRITE_BUFFER, 0, byte_size as GLsizeiptr, flags);
+				gl::BindBuffer(gl::COPY_WRITE_BUFFER, 0);
+				data
+			}
+		};
+
+		if data.is_null() {
+			unsafe { gl::DeleteBuffers(1, &id) };
+			return None;
+		}
+
+		accounting::track(Category::for_buffer_target(target), id, &format!("ring buffer {}", id), byte_size);
+
+		Some(RingBuffer {
+			id,
+			target,
+			data: data as *mut u8,
+			capacity,
+			region_size,
+			alignment,
+			fences: vec![ptr::null(); regions],
+			region: 0,
+			cursor: 0,
+			_element: PhantomData,
+		})
+	}
+
+	pub fn id(&self) -> GLuint {
+		self.id
+	}
+
+	pub fn bind(&self) {
+		unsafe {
+			gl::BindBuffer(self.target, self.id);
+		}
+	}
+
+	/// Fences the current region behind the draws issued so far and moves to the next, waiting
+	/// for the GPU to finish the draws that last read it. Does nothing while the current region
+	/// is empty.
+	pub fn next_region(&mut self) {
+		if self.cursor == 0 {
+			return;
+		}
+
+		unsafe {
+			self.fences[self.region] = gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+		};
+
+		self.region = (self.region + 1) % self.fences.len();
+		self.cursor = 0;
+
+		let fence = mem::replace(&mut self.fences[self.region], ptr::null());
+		if fence.is_null() {
+			return;
+		}
+
+		unsafe {
+			while gl::ClientWaitSync(fence, gl::SYNC_FLUSH_COMMANDS_BIT, 1_000_000) == gl::TIMEOUT_EXPIRED {}
+			gl::DeleteSync(fence);
+		};
+	}
+
+	/// Appends `data` to the current region, moving to the next one if it doesn't fit, and
+	/// returns its byte offset from the start of the buffer. Uniform buffer offsets are aligned
+	/// so each push can be bound on its own. `None` if it's more than a region holds.
+	pub fn push(&mut self, data: &[T]) -> Option<usize> {
+		if data.len() > self.capacity {
+			return None;
+		}
+
+		let byte_len = mem::size_of_val(data);
+		let mut start = self.cursor.next_multiple_of(self.alignment);
+		if start + byte_len > self.region_size {
+			self.next_region();
+			start = 0;
+		}
+
+		let offset = self.region * self.region_size + start;
+		unsafe {
+			ptr::copy_nonoverlapping(data.as_ptr() as *const u8, self.data.add(offset), byte_len);
+		};
+		self.cursor = start + byte_len;
+
+		Some(offset)
+	}
+}
+
+impl<T: Copy> Drop for RingBuffer<T> {
+	fn drop(&mut self) {
+		unsafe {
+			for &fence in &self.fences {
+				if !fence.is_null() {
+					gl::DeleteSync(fence);
+				}
+			};
+
+			accounting::untrack(Category::for_buffer_target(self.target), self.id);
+
+			// Deleting a buffer unmaps it
+			gl::DeleteBuffers(1, &self.id);
+		}
+	}
+}
+
+/// Data rewritten every frame: a `RingBuffer` with `FRAMES_IN_FLIGHT` regions where buffer
+/// storage is supported, otherwise a buffer orphaned at the start of every frame and appended
+/// to within it.
+pub enum StreamBuffer<T: Copy> {
+	Ring(RingBuffer<T>),
+	Orphaned {
+		buffer: Buffer,
+		alignment: usize,
+		/// Bytes written this frame.
+		cursor: usize,
+		_element: PhantomData<T>,
+	},
+}
+
+impl<T: Copy> StreamBuffer<T> {
+	/// Takes up to `capacity` elements per `push`.
+	pub fn new(target: GLenum, capacity: usize) -> StreamBuffer<T> {
+		match RingBuffer::new(target, capacity, FRAMES_IN_FLIGHT) {
+			Some(ring) => StreamBuffer::Ring(ring),
+			None => StreamBuffer::Orphaned {
+				buffer: Buffer::uninitialized(target, 0, BufferUsage::Stream),
+				alignment: offset_alignment(target),
+				cursor: 0,
+				_element: PhantomData,
+			},
+		}
+	}
+
+	pub fn id(&self) -> GLuint {
+		match *self {
+			StreamBuffer::Ring(ref ring) => ring.id(),
+			StreamBuffer::Orphaned { ref buffer, .. } => buffer.id(),
+		}
+	}
+
+	/// Most elements a `push` takes.
+	pub fn capacity(&self) -> usize {
+		match *self {
+			StreamBuffer::Ring(ref ring) => ring.capacity,
+			StreamBuffer::Orphaned { .. } => usize::MAX,
+		}
+	}
+
+	/// Allocated size, all regions included.
+	pub fn byte_size(&self) -> usize {
+		match *self {
+			StreamBuffer::Ring(ref ring) => ring.region_size * ring.fences.len(),
+			StreamBuffer::Orphaned { ref buffer, .. } => buffer.byte_size,
+		}
+	}
+
+	pub fn bind(&self) {
+		match *self {
+			StreamBuffer::Ring(ref ring) => ring.bind(),
+			StreamBuffer::Orphaned { ref buffer, .. } => buffer.bind(),
+		}
+	}
+
+	/// Attaches the whole buffer to an indexed binding point, for storage buffers indexed from
+	/// the offsets `push` returns.
+	pub fn bind_base(&self, index: GLuint) {
+		unsafe {
+			gl::BindBufferBase(self.target(), index, self.id());
+		};
+	}
+
+	/// Attaches `len` elements from `offset` bytes in to an indexed binding point.
+	pub fn bind_range(&self, index: GLuint, offset: usize, len: usize) {
+		unsafe {
+			gl::BindBufferRange(self.target(), index, self.id(), offset as GLintptr,
+								(len * mem::size_of::<T>()) as GLsizeiptr);
+		};
+	}
+
+	fn target(&self) -> GLenum {
+		match *self {
+			StreamBuffer::Ring(ref ring) => ring.target,
+			StreamBuffer::Orphaned { ref buffer, .. } => buffer.target,
+		}
+	}
+
+	pub fn set_label(&self, name: &str) {
+		label_object(gl::BUFFER, self.id(), name);
+	}
+
+	/// Starts a new frame's data, so the GPU can go on reading the last frame's.
+	pub fn begin_frame(&mut self) {
+		match *self {
+			StreamBuffer::Ring(ref mut ring) => ring.next_region(),
+			StreamBuffer::Orphaned { ref mut buffer, ref mut cursor, .. } => {
+				if *cursor > 0 {
+					buffer.orphan();
+					*cursor = 0;
+				}
+			},
+		};
+	}
+
+	/// Appends `data` for the draws that follow and returns its byte offset in the buffer,
+	/// aligned like `RingBuffer::push`. Panics if it's more than `capacity` elements.
+	pub fn push(&mut self, data: &[T]) -> usize {
+		match *self {
+			StreamBuffer::Ring(ref mut ring) => ring.push(data).expect("push past the capacity of a stream buffer"),
+			StreamBuffer::Orphaned { ref mut buffer, alignment, ref mut cursor, .. } => {
+				let start = cursor.next_multiple_of(alignment);
+				let end = start + mem::size_of_val(data);
+
+				// Earlier pushes this frame may not have been drawn yet, so growing keeps them
+				if end > buffer.byte_size {
+					buffer.resize(end.max(buffer.byte_size * 2));
+				}
+
+				buffer.update_at(start, data);
+				*cursor = end;
+				start
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::mem;
+	use gl;
+	use gl::types::*;
+	use gfx::lowlevel::test_context;
+	use super::*;
+
+	/// Reads back `len` elements of `T` from `offset` bytes into the buffer named `id`.
+	fn read<T: Copy + Default>(id: GLuint, offset: usize, len: usize) -> Vec<T> {
+		let mut data = vec![T::default(); len];
+		unsafe {
+			gl::GetNamedBufferSubData(id, offset as GLintptr, (len * mem::size_of::<T>()) as GLsizeiptr,
+									  data.as_mut_ptr() as *mut GLvoid);
+		};
+		data
+	}
+
+	#[test]
+	fn ring_wraps_around_its_regions() {
+		test_context::make_current();
+
+		let mut ring = RingBuffer::<u32>::new(gl::ARRAY_BUFFER, 2, 3).unwrap();
+		let region_size = 2 * mem::size_of::<u32>();
+
+		assert_eq!(ring.push(&[1, 2]), Some(0));
+		// Full, so on to the next region
+		assert_eq!(ring.push(&[3]), Some(region_size));
+		assert_eq!(ring.push(&[4]), Some(region_size + 4));
+		ring.next_region();
+		assert_eq!(ring.push(&[5, 6]), Some(2 * region_size));
+		ring.next_region();
+		assert_eq!(ring.push(&[7]), Some(0));
+
+		assert_eq!(read::<u32>(ring.id(), 0, 6), [7, 2, 3, 4, 5, 6]);
+		assert_eq!(ring.push(&[1, 2, 3]), None);
+	}
+
+	#[test]
+	fn ring_waits_for_the_gpu_before_reuse() {
+		test_context::make_current();
+
+		let mut ring = RingBuffer::<u32>::new(gl::ARRAY_BUFFER, 1, 2).unwrap();
+		let copy = Buffer::uninitialized(gl::COPY_WRITE_BUFFER, mem::size_of::<u32>(), BufferUsage::Static);
+
+		// The GPU reads the first region after the CPU is done with it
+		let offset = ring.push(&[1]).unwrap();
+		unsafe {
+			gl::CopyNamedBufferSubData(ring.id(), copy.id(), offset as GLintptr, 0, 4);
+		};
+		ring.next_region();
+		assert!(!ring.fences[0].is_null());
+
+		ring.push(&[2]).unwrap();
+		ring.next_region();
+
+		// Back at the first region, whose fence was waited for and released
+		assert_eq!(ring.region, 0);
+		assert!(ring.fences[0].is_null());
+		assert!(!ring.fences[1].is_null());
+		ring.push(&[3]).unwrap();
+
+		assert_eq!(read::<u32>(copy.id(), 0, 1), [1]);
+		assert_eq!(read::<u32>(ring.id(), 0, 2), [3, 2]);
+	}
+
+	#[test]
+	fn empty_region_is_not_left() {
+		test_context::make_current();
+
+		let mut ring = RingBuffer::<u32>::new(gl::ARRAY_BUFFER, 1, 2).unwrap();
+		ring.next_region();
+		assert_eq!(ring.region, 0);
+		assert!(ring.fences.iter().all(|fence| fence.is_null()));
+	}
+
+	#[test]
+	fn uniform_regions_are_aligned() {
+		test_context::make_current();
+
+		let mut alignment = 0;
+		unsafe { gl::GetIntegerv(gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT, &mut alignment) };
+
+		let mut ring = RingBuffer::<[f32; 3]>::new(gl::UNIFORM_BUFFER, 1, 2).unwrap();
+		ring.push(&[[0.0; 3]]);
+		let offset = ring.push(&[[1.0; 3]]).unwrap();
+		assert!(offset > 0 && offset.is_multiple_of(alignment as usize));
+	}
+
+	#[test]
+	fn uniform_pushes_within_a_region_are_aligned() {
+		test_context::make_current();
+
+		let alignment = offset_alignment(gl::UNIFORM_BUFFER);
+		let mut ring = RingBuffer::<[f32; 3]>::new(gl::UNIFORM_BUFFER, 2, 2).unwrap();
+
+		assert_eq!(ring.push(&[[0.0; 3]]), Some(0));
+		let offset = ring.push(&[[1.0; 3]]).unwrap();
+		assert_eq!(ring.region, 0);
+		assert_eq!(offset, 12usize.next_multiple_of(alignment));
+		assert_eq!(read::<[f32; 3]>(ring.id(), offset, 1), [[1.0; 3]]);
+
+		unsafe {
+			gl::BindBufferRange(gl::UNIFORM_BUFFER, 0, ring.id(), offset as GLintptr, 12);
+			assert_eq!(gl::GetError(), gl::NO_ERROR);
+		};
+	}
+
+	#[test]
+	fn resizing_keeps_the_contents() {
+		test_context::make_current();
+
+		let mut buffer = Buffer::new(gl::ARRAY_BUFFER, &[1u32, 2, 3, 4], BufferUsage::Static);
+		let id = buffer.id();
+
+		buffer.resize(8);
+		assert_eq!(buffer.byte_size, 8);
+		assert_eq!(read::<u32>(buffer.id(), 0, 2), [1, 2]);
+
+		buffer.resize(16);
+		assert_eq!(buffer.id(), id);
+		assert_eq!(read::<u32>(buffer.id(), 0, 2), [1, 2]);
+
+		buffer.resize(0);
+		assert_eq!(buffer.byte_size, 0);
+	}
+
+	fn orphaned<T: Copy>(target: GLenum) -> StreamBuffer<T> {
+		StreamBuffer::Orphaned {
+			buffer: Buffer::uninitialized(target, 0, BufferUsage::Stream),
+			alignment: offset_alignment(target),
+			cursor: 0,
+			_element: PhantomData,
+		}
+	}
+
+	#[test]
+	fn orphaned_buffers_append_until_the_next_frame() {
+		test_context::make_current();
+
+		let mut buffer = orphaned::<u32>(gl::ARRAY_BUFFER);
+		assert_eq!(buffer.push(&[1, 2]), 0);
+		// Growing keeps the first push
+		assert_eq!(buffer.push(&[3]), 8);
+		assert_eq!(read::<u32>(buffer.id(), 0, 3), [1, 2, 3]);
+
+		buffer.begin_frame();
+		assert_eq!(buffer.push(&[4]), 0);
+
+		let alignment = offset_alignment(gl::UNIFORM_BUFFER);
+		let mut uniforms = orphaned::<[f32; 3]>(gl::UNIFORM_BUFFER);
+		uniforms.push(&[[0.0; 3]]);
+		assert_eq!(uniforms.push(&[[1.0; 3]]), 12usize.next_multiple_of(alignment));
+	}
+
+	#[test]
+	fn stream_buffers_append_within_a_frame() {
+		test_context::make_current();
+
+		let mut buffer = StreamBuffer::<u32>::new(gl::ARRAY_BUFFER, 4);
+		buffer.begin_frame();
+		let first = buffer.push(&[1, 2]);
+		let second = buffer.push(&[3]);
+		assert_eq!(second, first + 2 * mem::size_of::<u32>());
+		assert_eq!(read::<u32>(buffer.id(), first, 3), [1, 2, 3]);
+	}
+}
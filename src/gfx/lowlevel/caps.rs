@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+use std::env;
+use std::ffi::CStr;
+use std::sync::OnceLock;
+use gl;
+use gl::types::*;
+
+// EXT_texture_filter_anisotropic isn't part of the core bindings until 4.6.
+const MAX_TEXTURE_MAX_ANISOTROPY: GLenum = 0x84FF;
+
+/// What the current context supports, for picking between code paths.
+#[derive(Debug)]
+pub struct Caps {
+	pub version: (u32, u32),
+	/// Direct state access (GL 4.5 or `ARB_direct_state_access`): objects are created and
+	/// edited by name, without disturbing bindings. Without it the wrappers fall back to
+	/// 3.3-style bind-to-edit.
+	pub direct_state_access: bool,
+	/// Immutable texture storage (GL 4.2 or `ARB_texture_storage`). The bind-to-edit fallback
+	/// specifies each level with `glTexImage*` without it.
+	pub texture_storage: bool,
+	/// Immutable multisampled texture storage (GL 4.3 or `ARB_texture_storage_multisample`).
+	pub texture_storage_multisample: bool,
+	/// Instanced draws that start partway into the per-instance attributes (GL 4.2 or
+	/// `ARB_base_instance`).
+	pub base_instance: bool,
+	/// Immutable, persistently mappable buffer storage (GL 4.4 or `ARB_buffer_storage`).
+	pub buffer_storage: bool,
+	/// `glMultiDrawElementsIndirect` (GL 4.3 or `ARB_multi_draw_indirect`) along with
+	/// `gl_DrawIDARB` in shaders, which batched draws need to find their per-draw data. The
+	/// shaders require `ARB_shader_draw_parameters` by name, so 4.6 alone doesn't count.
+	pub multi_draw_indirect: bool,
+	/// Debug message callbacks, object labels and debug groups (GL 4.3 or `KHR_debug`).
+	pub debug_output: bool,
+	/// Longest object label or debug group name, terminator included.
+	pub max_label_length: usize,
+	/// Highest sampler anisotropy, or `None` without anisotropic filtering (GL 4.6,
+	/// `ARB_texture_filter_anisotropic` or `EXT_texture_filter_anisotropic`).
+	pub max_anisotropy: Option<f32>,
+}
+
+impl Caps {
+	fn detect() -> Caps {
+		let mut major = 0;
+		let mut minor = 0;
+		let mut count = 0;
+
+		let mut extensions = HashSet::new();
+		unsafe {
+			gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+			gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+			gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut count);
+
+			for i in 0..(count as GLuint) {
+				let name = gl::GetStringi(gl::EXTENSIONS, i);
+				if !name.is_null() {
+					extensions.insert(CStr::from_ptr(name as *const _).to_string_lossy().into_owned());
+				}
+			};
+		};
+
+		let version = (major as u32, minor as u32);
+		let has = |min_version: (u32, u32), extension: &str| version >= min_version || extensions.contains(extension);
+
+		// Set to exercise the fallback paths on a driver that doesn't need them
+		let force_fallback = env::var_os("FOUR_KB_NO_DSA").is_some();
+
+		let direct_state_access = !force_fallback &&
+			has((4, 5), "GL_ARB_direct_state_access") &&
+			gl::CreateBuffers::is_loaded() &&
+			gl::CreateVertexArrays::is_loaded() &&
+			gl::CreateTextures::is_loaded();
+
+		let texture_storage = has((4, 2), "GL_ARB_texture_storage") && gl::TexStorage2D::is_loaded();
+		let texture_storage_multisample = has((4, 3), "GL_ARB_texture_storage_multisample") &&
+			gl::TexStorage2DMultisample::is_loaded();
+
+		let base_instance = has((4, 2), "GL_ARB_base_instance") &&
+			gl::DrawElementsInstancedBaseInstance::is_loaded();
+
+		let buffer_storage = has((4, 4), "GL_ARB_buffer_storage") && gl::BufferStorage::is_loaded();
+
+		let multi_draw_indirect = has((4, 3), "GL_ARB_multi_draw_indirect") &&
+			extensions.contains("GL_ARB_shader_draw_parameters") &&
+			gl::MultiDrawElementsIndirect::is_loaded();
+
+		let debug_output = has((4, 3), "GL_KHR_debug") && gl::DebugMessageCallback::is_loaded();
+
+		let mut max_label_length = 0;
+		if debug_output {
+			unsafe { gl::GetIntegerv(gl::MAX_LABEL_LENGTH, &mut max_label_length) };
+		}
+
+		let anisotropic = has((4, 6), "GL_ARB_texture_filter_anisotropic") ||
+			extensions.contains("GL_EXT_texture_filter_anisotropic");
+		let max_anisotropy = if anisotropic {
+			let mut max_anisotropy = 1.0;
+			unsafe { gl::GetFloatv(MAX_TEXTURE_MAX_ANISOTROPY, &mut max_anisotropy) };
+			Some(max_anisotropy)
+		} else {
+			None
+		};
+
+		Caps {
+			version,
+			direct_state_access,
+			texture_storage,
+			texture_storage_multisample,
+			base_instance,
+			buffer_storage,
+			multi_draw_indirect,
+			debug_output,
+			max_label_length: max_label_length as usize,
+			max_anisotropy,
+		}
+	}
+}
+
+static CAPS: OnceLock<Caps> = OnceLock::new();
+
+/// The capabilities of the current context, detected on first use; the GL function pointers
+/// must be loaded by then.
+pub fn caps() -> &'static Caps {
+	CAPS.get_or_init(Caps::detect)
+}
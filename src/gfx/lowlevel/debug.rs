@@ -0,0 +1,203 @@
+use std::env;
+use std::ffi::CStr;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use gl;
+use gl::types::*;
+use gfx::lowlevel::accounting;
+use gfx::lowlevel::caps::caps;
+
+/// How serious a driver debug message is, from least to most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+	Notification,
+	Low,
+	Medium,
+	High,
+}
+
+impl Severity {
+	fn from_gl(severity: GLenum) -> Severity {
+		match severity {
+			gl::DEBUG_SEVERITY_HIGH => Severity::High,
+			gl::DEBUG_SEVERITY_MEDIUM => Severity::Medium,
+			gl::DEBUG_SEVERITY_LOW => Severity::Low,
+			_ => Severity::Notification,
+		}
+	}
+
+	fn parse(name: &str) -> Option<Severity> {
+		match name {
+			"notification" | "all" => Some(Severity::Notification),
+			"low" => Some(Severity::Low),
+			"medium" => Some(Severity::Medium),
+			"high" => Some(Severity::High),
+			_ => None,
+		}
+	}
+}
+
+/// The lowest severity to report, set from `FOUR_KB_GL_DEBUG` (`notification`, `low`,
+/// `medium`, `high` or `off`). Debug builds default to `medium`, release builds to off.
+pub fn requested_severity() -> Option<Severity> {
+	match env::var("FOUR_KB_GL_DEBUG") {
+		Ok(ref value) if value == "off" => None,
+		Ok(value) => Severity::parse(&value).or_else(|| {
+			println!("Unknown FOUR_KB_GL_DEBUG value \"{}\", using medium", value);
+			Some(Severity::Medium)
+		}),
+		Err(_) if cfg!(debug_assertions) => Some(Severity::Medium),
+		Err(_) => None,
+	}
+}
+
+// Read from the callback, which may run on a driver thread
+static MIN_SEVERITY: AtomicUsize = AtomicUsize::new(Severity::Medium as usize);
+
+extern "system" fn debug_callback(source: GLenum, ty: GLenum, id: GLuint, severity: GLenum, _length: GLsizei,
+								  message: *const GLchar, _user_param: *mut GLvoid) {
+	let severity = Severity::from_gl(severity);
+	if (severity as usize) < MIN_SEVERITY.load(Ordering::Relaxed) {
+		return;
+	}
+
+	let source = match source {
+		gl::DEBUG_SOURCE_API => "api",
+		gl::DEBUG_SOURCE_WINDOW_SYSTEM => "window system",
+		gl::DEBUG_SOURCE_SHADER_COMPILER => "shader compiler",
+		gl::DEBUG_SOURCE_THIRD_PARTY => "third party",
+		gl::DEBUG_SOURCE_APPLICATION => "application",
+		_ => "other",
+	};
+
+	let ty = match ty {
+		gl::DEBUG_TYPE_ERROR => "error",
+		gl::DEBUG_TYPE_DEPRECATED_BEHAVIOR => "deprecated",
+		gl::DEBUG_TYPE_UNDEFINED_BEHAVIOR => "undefined behavior",
+		gl::DEBUG_TYPE_PORTABILITY => "portability",
+		gl::DEBUG_TYPE_PERFORMANCE => "performance",
+		gl::DEBUG_TYPE_MARKER => "marker",
+		gl::DEBUG_TYPE_PUSH_GROUP | gl::DEBUG_TYPE_POP_GROUP => return,
+		_ => "other",
+	};
+
+	let message = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+	println!("GL {} ({}, {:?}, #{}): {}", ty, source, severity, id, message);
+}
+
+/// Routes driver messages at `min_severity` and above to stdout, synchronously so they arrive
+/// inside the call that caused them. Returns false when the context has no `KHR_debug` or
+/// wasn't created with the debug flag; errors then only show up through `check_errors`.
+pub fn enable_debug_output(min_severity: Severity) -> bool {
+	if !caps().debug_output {
+		return false;
+	}
+
+	let mut flags = 0;
+	unsafe { gl::GetIntegerv(gl::CONTEXT_FLAGS, &mut flags) };
+	if flags as GLuint & gl::CONTEXT_FLAG_DEBUG_BIT == 0 {
+		return false;
+	}
+
+	MIN_SEVERITY.store(min_severity as usize, Ordering::Relaxed);
+
+	unsafe {
+		gl::Enable(gl::DEBUG_OUTPUT);
+		gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+		gl::DebugMessageCallback(debug_callback, ptr::null());
+		gl::DebugMessageControl(gl::DONT_CARE, gl::DONT_CARE, gl::DONT_CARE, 0, ptr::null(), gl::TRUE);
+	};
+
+	true
+}
+
+/// Drains `glGetError`, reporting each error with `context`. Returns whether there were any.
+pub fn check_errors(context: &str) -> bool {
+	let mut found = false;
+
+	loop {
+		let error = unsafe { gl::GetError() };
+		if error == gl::NO_ERROR {
+			return found;
+		}
+
+		let name = match error {
+			gl::INVALID_ENUM => "invalid enum",
+			gl::INVALID_VALUE => "invalid value",
+			gl::INVALID_OPERATION => "invalid operation",
+			gl::INVALID_FRAMEBUFFER_OPERATION => "invalid framebuffer operation",
+			gl::OUT_OF_MEMORY => "out of memory",
+			gl::STACK_OVERFLOW => "stack overflow",
+			gl::STACK_UNDERFLOW => "stack underflow",
+			_ => "unknown error",
+		};
+
+		println!("GL error in {}: {} (0x{:x})", context, name, error);
+		found = true;
+	};
+}
+
+// Labels longer than the limit are an error, so cut them at a character boundary
+fn truncate_label(name: &str) -> &str {
+	let mut len = name.len().min(caps().max_label_length.saturating_sub(1));
+	while !name.is_char_boundary(len) {
+		len -= 1;
+	};
+
+	&name[..len]
+}
+
+/// Names a GL object for debug messages, frame captures and the accounting table.
+/// `identifier` is its kind, such as `gl::BUFFER` or `gl::TEXTURE`.
+pub fn label_object(identifier: GLenum, id: GLuint, name: &str) {
+	accounting::track_label(identifier, id, name);
+
+	if !caps().debug_output || id == 0 {
+		return;
+	}
+
+	let name = truncate_label(name);
+	unsafe {
+		gl::ObjectLabel(identifier, id, name.len() as GLsizei, name.as_ptr() as *const GLchar);
+	};
+}
+
+/// A named region of commands in captures, open until dropped.
+pub struct DebugGroup {
+	active: bool,
+}
+
+pub fn debug_group(name: &str) -> DebugGroup {
+	if !caps().debug_output {
+		return DebugGroup { active: false };
+	}
+
+	push_debug_group(name)
+}
+
+/// Like `debug_group`, but only builds the name when debug output is on, so per-draw groups
+/// with formatted names cost nothing without it.
+pub fn debug_group_with<F: FnOnce() -> String>(name: F) -> DebugGroup {
+	if !caps().debug_output {
+		return DebugGroup { active: false };
+	}
+
+	push_debug_group(&name())
+}
+
+fn push_debug_group(name: &str) -> DebugGroup {
+	let name = truncate_label(name);
+	unsafe {
+		gl::PushDebugGroup(gl::DEBUG_SOURCE_APPLICATION, 0, name.len() as GLsizei, name.as_ptr() as *const GLchar);
+	};
+
+	DebugGroup { active: true }
+}
+
+impl Drop for DebugGroup {
+	fn drop(&mut self) {
+		if self.active {
+			unsafe { gl::PopDebugGroup() };
+		}
+	}
+}
@@ -0,0 +1,475 @@
+use std::error::Error;
+use std::fmt;
+use gl;
+use gl::types::*;
+use gfx::lowlevel::accounting::{self, Category};
+use gfx::lowlevel::caps::caps;
+use gfx::lowlevel::debug::label_object;
+use gfx::lowlevel::handle::Handle;
+use gfx::lowlevel::texture::{Texture, TextureFormat};
+
+pub struct Renderbuffer {
+	pub id: GLuint,
+}
+
+impl Renderbuffer {
+	pub fn new(name: &str, format: TextureFormat, width: u32, height: u32, samples: u32) -> Renderbuffer {
+		let mut rbo = Renderbuffer { id: 0 };
+		// Asking for one sample can give a multisampled renderbuffer, which doesn't match
+		// single-sampled textures in the same framebuffer
+		let samples = if samples > 1 { samples } else { 0 };
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::CreateRenderbuffers(1, &mut rbo.id);
+				gl::NamedRenderbufferStorageMultisample(rbo.id, samples as i32, format.internal_format(),
+														width as i32, height as i32);
+			} else {
+				// Put back whatever was bound, like texture edits do
+				let mut previous = 0;
+				gl::GetIntegerv(gl::RENDERBUFFER_BINDING, &mut previous);
+
+				gl::GenRenderbuffers(1, &mut rbo.id);
+				gl::BindRenderbuffer(gl::RENDERBUFFER, rbo.id);
+				gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples as i32,
+												   format.internal_format(),
+												   width as i32, height as i32);
+				gl::BindRenderbuffer(gl::RENDERBUFFER, previous as GLuint);
+			}
+		};
+
+		let byte_size = format.bytes_per_pixel() as usize * width as usize * height as usize * samples.max(1) as usize;
+		accounting::track(Category::Renderbuffer, rbo.id, name, byte_size);
+		label_object(gl::RENDERBUFFER, rbo.id, name);
+
+		rbo
+	}
+}
+
+impl Drop for Renderbuffer {
+	fn drop(&mut self) {
+		accounting::untrack(Category::Renderbuffer, self.id);
+
+		unsafe {
+			gl::DeleteRenderbuffers(1, &self.id);
+		}
+	}
+}
+
+pub enum Attachment {
+	Texture(Handle<Texture>),
+	// Nothing reads the renderbuffer back; it's only held so it lives as long as the framebuffer
+	#[allow(dead_code)]
+	Renderbuffer(Renderbuffer),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthDesc {
+	pub format: TextureFormat,
+	/// Use a renderbuffer instead of a texture when the depth never needs sampling.
+	pub renderbuffer: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FramebufferDesc {
+	/// The framebuffer follows the window, scaled by this (0.5 renders at half resolution).
+	pub scale: f32,
+	pub samples: u32,
+	pub colors: Vec<TextureFormat>,
+	pub depth: Option<DepthDesc>,
+}
+
+#[derive(Debug)]
+pub enum FramebufferError {
+	ZeroSize,
+	TooManyColorAttachments(usize),
+	BadColorFormat(TextureFormat),
+	BadDepthFormat(TextureFormat),
+	Incomplete(GLenum),
+	/// Multisampled framebuffers can only be blitted at their own size; resolve first.
+	ScaledMultisampleBlit,
+}
+
+impl fmt::Display for FramebufferError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			FramebufferError::ZeroSize => write!(f, "framebuffer has zero size"),
+			FramebufferError::TooManyColorAttachments(count) =>
+				write!(f, "{} color attachments requested, driver supports fewer", count),
+			FramebufferError::BadColorFormat(format) =>
+				write!(f, "{:?} can't be used as a color attachment", format),
+			FramebufferError::BadDepthFormat(format) =>
+				write!(f, "{:?} can't be used as a depth attachment", format),
+			FramebufferError::Incomplete(status) => {
+				let reason = match status {
+					gl::FRAMEBUFFER_UNDEFINED => "undefined",
+					gl::FRAMEBUFFER_INCOMPLETE_ATTACHMENT => "incomplete attachment",
+					gl::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT => "missing attachment",
+					gl::FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER => "incomplete draw buffer",
+					gl::FRAMEBUFFER_INCOMPLETE_READ_BUFFER => "incomplete read buffer",
+					gl::FRAMEBUFFER_UNSUPPORTED => "unsupported format combination",
+					gl::FRAMEBUFFER_INCOMPLETE_MULTISAMPLE => "mismatched sample counts",
+					gl::FRAMEBUFFER_INCOMPLETE_LAYER_TARGETS => "mismatched layer targets",
+					_ => "unknown status",
+				};
+				write!(f, "framebuffer incomplete: {} (0x{:x})", reason, status)
+			},
+			FramebufferError::ScaledMultisampleBlit =>
+				write!(f, "multisampled framebuffers can't be blitted to a different size"),
+		}
+	}
+}
+
+impl Error for FramebufferError {}
+
+pub struct Framebuffer {
+	pub name: String,
+	pub id: GLuint,
+	pub desc: FramebufferDesc,
+	pub width: u32,
+	pub height: u32,
+	pub colors: Vec<Handle<Texture>>,
+	pub depth: Option<Attachment>,
+}
+
+impl Framebuffer {
+	pub fn new(name: &str, desc: FramebufferDesc, window_size: (u32, u32))
+			-> Result<Framebuffer, FramebufferError> {
+		let mut fbo = Framebuffer {
+			name: String::from(name),
+			id: 0,
+			desc,
+			width: 0,
+			height: 0,
+			colors: Vec::new(),
+			depth: None,
+		};
+
+		unsafe {
+			gl::GenFramebuffers(1, &mut fbo.id);
+		};
+
+		accounting::track(Category::Framebuffer, fbo.id, name, 0);
+		fbo.create_attachments(window_size)?;
+		label_object(gl::FRAMEBUFFER, fbo.id, name);
+
+		Ok(fbo)
+	}
+
+	fn create_attachments(&mut self, window_size: (u32, u32)) -> Result<(), FramebufferError> {
+		let width = (window_size.0 as f32 * self.desc.scale) as u32;
+		let height = (window_size.1 as f32 * self.desc.scale) as u32;
+
+		if width == 0 || height == 0 {
+			return Err(FramebufferError::ZeroSize);
+		}
+
+		let mut max_attachments = 0;
+		unsafe {
+			gl::GetIntegerv(gl::MAX_COLOR_ATTACHMENTS, &mut max_attachments);
+		};
+		if self.desc.colors.len() > max_attachments as usize {
+			return Err(FramebufferError::TooManyColorAttachments(self.desc.colors.len()));
+		}
+
+		if let Some(&format) = self.desc.colors.iter().find(|format| format.is_depth()) {
+			return Err(FramebufferError::BadColorFormat(format));
+		}
+		if let Some(depth_desc) = self.desc.depth {
+			if !depth_desc.format.is_depth() {
+				return Err(FramebufferError::BadDepthFormat(depth_desc.format));
+			}
+		}
+
+		let samples = self.desc.samples;
+		let mut colors = Vec::with_capacity(self.desc.colors.len());
+
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, self.id);
+		};
+
+		for (i, &format) in self.desc.colors.iter().enumerate() {
+			let tex_name = format!("{}.color{}", self.name, i);
+			let tex = Texture::new_empty(&tex_name, format, width, height, samples);
+
+			unsafe {
+				gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0 + i as GLenum,
+										 tex.target, tex.id, 0);
+			};
+
+			colors.push(tex);
+		};
+
+		let mut depth_tex = None;
+		let mut depth_rbo = None;
+
+		if let Some(depth_desc) = self.desc.depth {
+			let format = depth_desc.format;
+
+			let attachment_point = if format.has_stencil() {
+				gl::DEPTH_STENCIL_ATTACHMENT
+			} else {
+				gl::DEPTH_ATTACHMENT
+			};
+
+			if depth_desc.renderbuffer {
+				let rbo = Renderbuffer::new(&format!("{}.depth", self.name), format, width, height, samples);
+				unsafe {
+					gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, attachment_point,
+												gl::RENDERBUFFER, rbo.id);
+				};
+				depth_rbo = Some(rbo);
+			} else {
+				let tex_name = format!("{}.depth", self.name);
+				let tex = Texture::new_empty(&tex_name, format, width, height, samples);
+				unsafe {
+					gl::FramebufferTexture2D(gl::FRAMEBUFFER, attachment_point,
+											 tex.target, tex.id, 0);
+				};
+				depth_tex = Some(tex);
+			}
+		};
+
+		let draw_buffers = (0..colors.len()).
+			map(|i| gl::COLOR_ATTACHMENT0 + i as GLenum).
+			collect::<Vec<_>>();
+
+		let status = unsafe {
+			if draw_buffers.is_empty() {
+				gl::DrawBuffer(gl::NONE);
+				gl::ReadBuffer(gl::NONE);
+			} else {
+				gl::DrawBuffers(draw_buffers.len() as i32, draw_buffers.as_ptr());
+				gl::ReadBuffer(gl::COLOR_ATTACHMENT0);
+			}
+
+			let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+			gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+			status
+		};
+
+		// Keep the new attachments either way, so the old ones are released. Textures are swapped
+		// into the existing handles, so materials sampling them see the new ones.
+		self.width = width;
+		self.height = height;
+
+		if self.colors.len() == colors.len() {
+			for (handle, tex) in self.colors.iter().zip(colors) {
+				handle.replace(tex);
+			};
+		} else {
+			self.colors = colors.into_iter().map(Handle::new).collect();
+		}
+
+		self.depth = match (self.depth.take(), depth_tex, depth_rbo) {
+			(Some(Attachment::Texture(handle)), Some(tex), _) => {
+				handle.replace(tex);
+				Some(Attachment::Texture(handle))
+			},
+			(_, Some(tex), _) => Some(Attachment::Texture(Handle::new(tex))),
+			(_, None, Some(rbo)) => Some(Attachment::Renderbuffer(rbo)),
+			(_, None, None) => None,
+		};
+
+		if status != gl::FRAMEBUFFER_COMPLETE {
+			return Err(FramebufferError::Incomplete(status));
+		}
+
+		Ok(())
+	}
+
+	/// Recreates the attachments at the window's new size.
+	pub fn on_window_resize(&mut self, width: u32, height: u32) -> Result<(), FramebufferError> {
+		self.create_attachments((width, height))
+	}
+
+	/// Binds the framebuffer for drawing and sets the viewport to cover it.
+	pub fn bind(&self) {
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, self.id);
+			gl::Viewport(0, 0, self.width as i32, self.height as i32);
+		};
+	}
+
+	/// Binds the window's framebuffer and sets the viewport to cover it.
+	pub fn bind_default(width: u32, height: u32) {
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+			gl::Viewport(0, 0, width as i32, height as i32);
+		};
+	}
+
+	/// Copies color attachment `src_index` and, if asked, depth/stencil into `dst`, or into the
+	/// window when `dst` is `None`. Scales with linear filtering if the sizes differ, which
+	/// multisampled framebuffers can't do; `resolve` them into a single-sampled one first.
+	pub fn blit(&self, src_index: usize, dst: Option<(&Framebuffer, usize)>,
+				dst_size: (u32, u32), depth: bool) -> Result<(), FramebufferError> {
+		let dst_id = dst.map_or(0, |(fbo, _)| fbo.id);
+
+		let mut mask = gl::COLOR_BUFFER_BIT;
+		if depth {
+			mask |= gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT;
+		}
+
+		let same_size = (self.width, self.height) == dst_size;
+		if !same_size && self.desc.samples > 1 {
+			return Err(FramebufferError::ScaledMultisampleBlit);
+		}
+
+		let filter = if same_size || depth { gl::NEAREST } else { gl::LINEAR };
+
+		unsafe {
+			gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.id);
+			gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, dst_id);
+			gl::ReadBuffer(gl::COLOR_ATTACHMENT0 + src_index as GLenum);
+
+			// The window's buffers aren't valid for glDrawBuffers
+			match dst {
+				Some((_, index)) => gl::DrawBuffers(1, &(gl::COLOR_ATTACHMENT0 + index as GLenum)),
+				None => gl::DrawBuffer(gl::BACK),
+			};
+
+			gl::BlitFramebuffer(0, 0, self.width as i32, self.height as i32,
+								0, 0, dst_size.0 as i32, dst_size.1 as i32,
+								mask, filter);
+
+			self.restore_buffers();
+			if let Some((fbo, _)) = dst {
+				fbo.restore_buffers();
+			}
+
+			gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+		};
+
+		Ok(())
+	}
+
+	/// Resolves a multisampled framebuffer into a single-sampled one of the same size and
+	/// layout, attachment by attachment.
+	pub fn resolve(&self, dst: &Framebuffer) -> Result<(), FramebufferError> {
+		for i in 0..self.colors.len().min(dst.colors.len()) {
+			self.blit(i, Some((dst, i)), (dst.width, dst.height), i == 0 && self.depth.is_some() && dst.depth.is_some())?;
+		};
+
+		Ok(())
+	}
+
+	fn restore_buffers(&self) {
+		let draw_buffers = (0..self.colors.len()).
+			map(|i| gl::COLOR_ATTACHMENT0 + i as GLenum).
+			collect::<Vec<_>>();
+
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, self.id);
+			if !draw_buffers.is_empty() {
+				gl::DrawBuffers(draw_buffers.len() as i32, draw_buffers.as_ptr());
+				gl::ReadBuffer(gl::COLOR_ATTACHMENT0);
+			}
+		};
+	}
+}
+
+impl Drop for Framebuffer {
+	fn drop(&mut self) {
+		accounting::untrack(Category::Framebuffer, self.id);
+
+		unsafe {
+			gl::DeleteFramebuffers(1, &self.id);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use gl;
+	use gl::types::*;
+	use gfx::lowlevel::test_context;
+	use super::*;
+
+	fn desc(samples: u32, depth: bool) -> FramebufferDesc {
+		FramebufferDesc {
+			scale: 1.0,
+			samples,
+			colors: vec![TextureFormat::Rgba16F],
+			depth: if depth {
+				Some(DepthDesc { format: TextureFormat::Depth24Stencil8, renderbuffer: true })
+			} else {
+				None
+			},
+		}
+	}
+
+	fn clear(fbo: &Framebuffer, color: [f32; 4]) {
+		fbo.bind();
+
+		unsafe {
+			gl::ClearColor(color[0], color[1], color[2], color[3]);
+			gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+		};
+	}
+
+	fn read_color(fbo: &Framebuffer) -> Vec<u8> {
+		let tex = fbo.colors[0].borrow();
+		let mut pixels = vec![0u8; (tex.width * tex.height * 4) as usize];
+
+		unsafe {
+			gl::GetTextureImage(tex.id, 0, gl::RGBA, gl::UNSIGNED_BYTE, pixels.len() as i32,
+								pixels.as_mut_ptr() as *mut _);
+		};
+
+		pixels
+	}
+
+	#[test]
+	fn resolves_and_scales() {
+		test_context::make_current();
+
+		let multisampled = Framebuffer::new("multisampled", desc(4, true), (8, 8)).unwrap();
+		let resolved = Framebuffer::new("resolved", desc(1, true), (8, 8)).unwrap();
+		let half = Framebuffer::new("half", FramebufferDesc { scale: 0.5, ..desc(1, false) }, (8, 8)).unwrap();
+		assert_eq!((half.width, half.height), (4, 4));
+
+		clear(&multisampled, [1.0, 0.0, 0.0, 1.0]);
+		multisampled.resolve(&resolved).unwrap();
+		assert_eq!(read_color(&resolved), [255, 0, 0, 255].repeat(64));
+
+		resolved.blit(0, Some((&half, 0)), (half.width, half.height), false).unwrap();
+		assert_eq!(read_color(&half), [255, 0, 0, 255].repeat(16));
+
+		match multisampled.blit(0, Some((&half, 0)), (half.width, half.height), false) {
+			Err(FramebufferError::ScaledMultisampleBlit) => {},
+			_ => panic!("scaled a multisampled blit"),
+		};
+
+		unsafe { assert_eq!(gl::GetError(), gl::NO_ERROR) };
+	}
+
+	#[test]
+	fn resizing_keeps_the_color_handles() {
+		test_context::make_current();
+
+		let mut fbo = Framebuffer::new("resized", desc(1, true), (8, 8)).unwrap();
+		let color = fbo.colors[0].clone();
+
+		fbo.on_window_resize(16, 4).unwrap();
+		assert_eq!((fbo.width, fbo.height), (16, 4));
+		assert!(color.ptr_eq(&fbo.colors[0]));
+		assert_eq!(color.borrow().width, 16);
+
+		assert!(fbo.on_window_resize(0, 4).is_err());
+	}
+
+	#[test]
+	fn renderbuffers_leave_the_binding_alone() {
+		test_context::make_current();
+
+		let bound = Renderbuffer::new("bound", TextureFormat::Depth24Stencil8, 4, 4, 1);
+		unsafe { gl::BindRenderbuffer(gl::RENDERBUFFER, bound.id) };
+
+		let _other = Renderbuffer::new("other", TextureFormat::Depth24Stencil8, 4, 4, 1);
+
+		let mut binding = 0;
+		unsafe { gl::GetIntegerv(gl::RENDERBUFFER_BINDING, &mut binding) };
+		assert_eq!(binding as GLuint, bound.id);
+	}
+}
@@ -0,0 +1,57 @@
+use std::cell::{Ref, RefCell, RefMut};
+use std::rc::{Rc, Weak};
+
+/// A shared, swappable GL object. Clones refer to the same object, which is freed when the
+/// last handle drops. `replace` swaps in a new object for every holder at once, which is how
+/// reloaded assets and resized render targets reach the materials that use them.
+pub struct Handle<T> {
+	inner: Rc<RefCell<T>>,
+}
+
+pub struct WeakHandle<T> {
+	inner: Weak<RefCell<T>>,
+}
+
+impl<T> Handle<T> {
+	pub fn new(object: T) -> Handle<T> {
+		Handle { inner: Rc::new(RefCell::new(object)) }
+	}
+
+	pub fn borrow(&self) -> Ref<'_, T> {
+		self.inner.borrow()
+	}
+
+	/// For per-frame state kept in the object, such as streamed buffers.
+	pub fn borrow_mut(&self) -> RefMut<'_, T> {
+		self.inner.borrow_mut()
+	}
+
+	/// Swaps in `object`, returning the old one. Dropping the result frees its GL object.
+	pub fn replace(&self, object: T) -> T {
+		self.inner.replace(object)
+	}
+
+	pub fn downgrade(&self) -> WeakHandle<T> {
+		WeakHandle { inner: Rc::downgrade(&self.inner) }
+	}
+
+	pub fn handle_count(&self) -> usize {
+		Rc::strong_count(&self.inner)
+	}
+
+	pub fn ptr_eq(&self, other: &Handle<T>) -> bool {
+		Rc::ptr_eq(&self.inner, &other.inner)
+	}
+}
+
+impl<T> Clone for Handle<T> {
+	fn clone(&self) -> Handle<T> {
+		Handle { inner: self.inner.clone() }
+	}
+}
+
+impl<T> WeakHandle<T> {
+	pub fn upgrade(&self) -> Option<Handle<T>> {
+		self.inner.upgrade().map(|inner| Handle { inner })
+	}
+}
@@ -1,120 +1,215 @@
-use std::mem;
-use std::ptr;
-use std::str;
-use std::ffi::CString;
+use std::convert::TryFrom;
+use std::ops::{Deref, DerefMut};
+use std::{slice, str};
 use gl;
 use gl::types::*;
-use gfx::image::Image;
+use gfx::lowlevel::accounting::Category;
 
+pub mod accounting;
+pub mod buffer;
+pub mod caps;
+pub mod debug;
 pub mod program;
+pub mod program_cache;
+pub mod shader;
+pub mod preprocessor;
+pub mod reflection;
 pub mod uniform;
+#[macro_use]
+pub mod uniform_buffer;
+pub mod storage_buffer;
+#[cfg(test)]
+pub mod test_context;
 pub mod texture;
+pub mod sampler;
+pub mod framebuffer;
+pub mod handle;
+#[macro_use]
+pub mod vertex;
+pub use self::buffer::*;
+pub use self::caps::*;
+pub use self::debug::*;
 pub use self::program::*;
+pub use self::program_cache::*;
+pub use self::shader::*;
+pub use self::preprocessor::*;
+pub use self::reflection::*;
 pub use self::uniform::*;
+pub use self::uniform_buffer::*;
+pub use self::storage_buffer::*;
 pub use self::texture::*;
+pub use self::sampler::*;
+pub use self::framebuffer::*;
+pub use self::handle::*;
+pub use self::vertex::*;
 
-pub struct VBO {
-	id: GLuint,
-}
+/// A vertex buffer.
+// The wrappers are named after the GL objects they hold
+#[allow(clippy::upper_case_acronyms)]
+pub struct VBO(Buffer);
 
 impl VBO {
 	pub fn new<T>(data: &[T]) -> Option<VBO> {
-		let mut vbo = VBO { id: 0 };
+		Some(VBO::with_usage(data, BufferUsage::Static))
+	}
+
+	pub fn with_usage<T>(data: &[T], usage: BufferUsage) -> VBO {
+		VBO(Buffer::new(gl::ARRAY_BUFFER, data, usage))
+	}
+
+	pub fn uninitialized(byte_size: usize, usage: BufferUsage) -> VBO {
+		VBO(Buffer::uninitialized(gl::ARRAY_BUFFER, byte_size, usage))
+	}
+}
+
+impl Deref for VBO {
+	type Target = Buffer;
+
+	fn deref(&self) -> &Buffer {
+		&self.0
+	}
+}
+
+impl DerefMut for VBO {
+	fn deref_mut(&mut self) -> &mut Buffer {
+		&mut self.0
+	}
+}
 
-		if data.len() == 0 {
-            panic!("Attempted to create invalid VBO");
+/// The width of the indices in an index buffer, narrowest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IndexType {
+	U16,
+	U32,
+}
+
+impl IndexType {
+	/// The narrowest type that can index `vertex_count` vertices. There are no byte indices,
+	/// which hardware tends to handle poorly.
+	pub fn for_vertex_count(vertex_count: usize) -> IndexType {
+		if vertex_count <= 1 << 16 {
+			IndexType::U16
+		} else {
+			IndexType::U32
 		}
-		
-		unsafe {
-			gl::GenBuffers(1, &mut vbo.id);
-			gl::BindBuffer(gl::ARRAY_BUFFER, vbo.id);
-			gl::BufferData(gl::ARRAY_BUFFER,
-						   (data.len() * mem::size_of::<T>()) as GLsizeiptr,
-						   mem::transmute(&data[0]),
-						   gl::STATIC_DRAW);
-		};
-		
-		Some(vbo)
 	}
-	
-	pub fn bind(&self) {
-		unsafe {
-			gl::BindBuffer(gl::ARRAY_BUFFER, self.id);
+
+	pub fn gl_enum(self) -> GLenum {
+		match self {
+			IndexType::U16 => gl::UNSIGNED_SHORT,
+			IndexType::U32 => gl::UNSIGNED_INT,
 		}
 	}
-}
 
-impl Drop for VBO {
-	fn drop(&mut self) {
-		unsafe {
-			gl::DeleteBuffers(1, &self.id);
+	pub fn size(self) -> usize {
+		match self {
+			IndexType::U16 => 2,
+			IndexType::U32 => 4,
 		}
 	}
 }
 
+/// An index buffer, which remembers the width of its indices for drawing.
+#[allow(clippy::upper_case_acronyms)]
 pub struct IBO {
-	id: GLuint,
+	buffer: Buffer,
+	pub index_type: IndexType,
 }
 
 impl IBO {
-	pub fn new<T>(data: &[T]) -> Option<IBO> {
-		let mut ibo = IBO { id: 0 };
+	/// Uploads `indices` as the narrowest type that can address `vertex_count` vertices.
+	pub fn narrowest(indices: &[u32], vertex_count: usize) -> IBO {
+		let ibo = IBO::uninitialized(IndexType::for_vertex_count(vertex_count), indices.len(), BufferUsage::Static);
+		ibo.write(0, indices);
+		ibo
+	}
 
-		if data.len() == 0 {
-            panic!("Attempted to create invalid IBO");
+	pub fn uninitialized(index_type: IndexType, len: usize, usage: BufferUsage) -> IBO {
+		IBO {
+			buffer: Buffer::uninitialized(gl::ELEMENT_ARRAY_BUFFER, len * index_type.size(), usage),
+			index_type,
 		}
-		
-		unsafe {
-			gl::GenBuffers(1, &mut ibo.id);
-			gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ibo.id);
-			gl::BufferData(gl::ELEMENT_ARRAY_BUFFER,
-						   (data.len() * mem::size_of::<T>()) as GLsizeiptr,
-						   mem::transmute(&data[0]),
-						   gl::STATIC_DRAW);
+	}
+
+	/// Number of indices.
+	pub fn len(&self) -> usize {
+		self.buffer.byte_size / self.index_type.size()
+	}
+
+	/// Overwrites indices from `first` on, converting them to the buffer's type. Panics if one
+	/// doesn't fit.
+	pub fn write(&self, first: usize, indices: &[u32]) {
+		fn narrow<I: TryFrom<u32>>(indices: &[u32]) -> Vec<I> {
+			indices.iter().
+				map(|&index| I::try_from(index).unwrap_or_else(|_| panic!("index {} too wide for its buffer", index))).
+				collect()
+		}
+
+		match self.index_type {
+			IndexType::U16 => self.buffer.update(first, &narrow::<u16>(indices)),
+			IndexType::U32 => self.buffer.update(first, indices),
 		};
-		
-		Some(ibo)
 	}
-	
-	pub fn bind(&self) {
-		unsafe {
-			gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.id);
+
+	/// Reads the indices back, widened to `u32`. It stalls until the GPU is done with the
+	/// buffer.
+	pub fn read(&self) -> Vec<u32> {
+		let data = self.buffer.map(gl::MAP_READ_BIT);
+		if data.is_null() {
+			return Vec::new();
 		}
+
+		let len = self.len();
+		let indices = unsafe {
+			match self.index_type {
+				IndexType::U16 => slice::from_raw_parts(data as *const u16, len).iter().map(|&index| index as u32).collect(),
+				IndexType::U32 => slice::from_raw_parts(data as *const u32, len).to_vec(),
+			}
+		};
+		self.buffer.unmap();
+
+		indices
 	}
 }
 
-impl Drop for IBO {
-	fn drop(&mut self) {
-		unsafe {
-			gl::DeleteBuffers(1, &self.id);
-		}
+impl Deref for IBO {
+	type Target = Buffer;
+
+	fn deref(&self) -> &Buffer {
+		&self.buffer
+	}
+}
+
+impl DerefMut for IBO {
+	fn deref_mut(&mut self) -> &mut Buffer {
+		&mut self.buffer
 	}
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct VAO {
 	id: GLuint,
-	verts: VBO,
-    normals: VBO,
-	texcoords: VBO,
-    tangents: VBO,
+	vbos: Vec<VBO>,
+	/// Each binding's buffer and layout, in the order they were added to the builder.
+	buffers: Vec<(GLuint, VertexLayout)>,
+	/// Each enabled location's binding, attribute and offset within a vertex.
+	attributes: Vec<(GLuint, GLuint, VertexAttribute, usize)>,
 }
 
 impl VAO {
-	pub fn new(verts: VBO, normals: VBO, texcoords: VBO, tangents: VBO,
-               program: &Program) -> VAO {
-		let mut vao = VAO { id: 0, verts, normals, texcoords, tangents };
+	/// A vertex array with no attributes, for passes that generate vertices from `gl_VertexID`.
+	pub fn empty() -> VAO {
+		let mut vao = VAO { id: 0, vbos: Vec::new(), buffers: Vec::new(), attributes: Vec::new() };
 
 		unsafe {
-			gl::GenVertexArrays(1, &mut vao.id);
-			gl::BindVertexArray(vao.id);
+			if caps().direct_state_access {
+				gl::CreateVertexArrays(1, &mut vao.id);
+			} else {
+				gl::GenVertexArrays(1, &mut vao.id);
+			}
 		};
 
-		VAO::bind_attribute("position", &vao.verts, 3, program);
-        VAO::bind_attribute("normal", &vao.normals, 3, program);
-		VAO::bind_attribute("tangent", &vao.tangents, 3, program);
-		VAO::bind_attribute("texcoord", &vao.texcoords, 2, program);
-
-		VAO::set_frag_data_name("out_color", program);
+		accounting::track(Category::VertexArray, vao.id, &format!("vertex array {}", vao.id), 0);
 
 		vao
 	}
@@ -125,32 +220,255 @@ impl VAO {
 		}
 	}
 
-	fn bind_attribute(name: &str, vbo: &VBO, num_components: u16, program: &Program) {
-		unsafe {
-			let attr = gl::GetAttribLocation(program.id, CString::new(name).unwrap().as_ptr());
-			gl::EnableVertexAttribArray(attr as GLuint);
-			
-			vbo.bind();
-			gl::VertexAttribPointer(attr as GLuint,
-									num_components as i32,
-									gl::FLOAT,
-									gl::FALSE as GLboolean,
-									0,
-									ptr::null());
-		};
+	/// Names the vertex array in debug output and captures. It must have been bound or built
+	/// by then.
+	pub fn set_label(&self, name: &str) {
+		label_object(gl::VERTEX_ARRAY, self.id, name);
 	}
 
-	fn set_frag_data_name(name: &str, program: &Program) {
-		unsafe {
-			gl::BindFragDataLocation(program.id, 0, CString::new(name).unwrap().as_ptr());
+	/// Makes the buffer added `binding`th to the builder start `offset` bytes in, for reading
+	/// data pushed partway into a stream buffer. Binds the array on the pre-4.5 path.
+	pub fn set_buffer_offset(&self, binding: usize, offset: usize) {
+		let (vbo, ref layout) = self.buffers[binding];
+
+		if caps().direct_state_access {
+			unsafe {
+				gl::VertexArrayVertexBuffer(self.id, binding as GLuint, vbo, offset as GLintptr,
+				                            layout.stride as GLsizei);
+			};
+			return;
+		}
+
+		self.bind();
+		for &(location, attribute_binding, ref attribute, attribute_offset) in &self.attributes {
+			if attribute_binding == binding as GLuint {
+				VAOBuilder::bind_attribute(location, vbo, layout, attribute, offset + attribute_offset);
+			}
 		};
+		unsafe { gl::BindBuffer(gl::ARRAY_BUFFER, 0) };
 	}
 }
 
 impl Drop for VAO {
 	fn drop(&mut self) {
+		accounting::untrack(Category::VertexArray, self.id);
+
 		unsafe {
 			gl::DeleteVertexArrays(1, &self.id);
 		}
 	}
 }
+
+/// Collects vertex buffers and their layouts, then builds a `VAO` that feeds `program` every
+/// attribute it shares with the layouts.
+pub struct VAOBuilder<'a> {
+	program: &'a Program,
+	buffers: Vec<(GLuint, VertexLayout)>,
+	owned: Vec<VBO>,
+	index_buffer: Option<GLuint>,
+}
+
+impl<'a> VAOBuilder<'a> {
+	pub fn new(program: &'a Program) -> VAOBuilder<'a> {
+		VAOBuilder { program, buffers: Vec::new(), owned: Vec::new(), index_buffer: None }
+	}
+
+	/// Adds a buffer the vertex array takes ownership of.
+	pub fn buffer(mut self, vbo: VBO, layout: VertexLayout) -> VAOBuilder<'a> {
+		self.buffers.push((vbo.id(), layout));
+		self.owned.push(vbo);
+		self
+	}
+
+	/// Adds a buffer shared with other vertex arrays, such as a mesh's vertices under an
+	/// instanced array. The caller must keep it alive as long as the array.
+	pub fn attach(mut self, vbo: &VBO, layout: VertexLayout) -> VAOBuilder<'a> {
+		self.buffers.push((vbo.id(), layout));
+		self
+	}
+
+	/// Adds a buffer of per-frame data, which is rewritten without the array noticing. The
+	/// caller must keep it alive as long as the array.
+	pub fn attach_stream<T: Copy>(mut self, buffer: &StreamBuffer<T>, layout: VertexLayout) -> VAOBuilder<'a> {
+		self.buffers.push((buffer.id(), layout));
+		self
+	}
+
+	/// Records `ibo` in the vertex array, so binding the array is enough to draw. The caller
+	/// keeps ownership and must keep it alive as long as the array.
+	pub fn index_buffer(mut self, ibo: &IBO) -> VAOBuilder<'a> {
+		self.index_buffer = Some(ibo.id());
+		self
+	}
+
+	/// Attributes only in a layout are left disabled. Ones the program reads but no layout
+	/// provides are reported; they read as the current generic attribute value.
+	pub fn build(self) -> VAO {
+		let mut vao = VAO::empty();
+		let dsa = caps().direct_state_access;
+
+		if dsa {
+			for (binding, &(vbo, ref layout)) in self.buffers.iter().enumerate() {
+				unsafe {
+					gl::VertexArrayVertexBuffer(vao.id, binding as GLuint, vbo, 0, layout.stride as GLsizei);
+					gl::VertexArrayBindingDivisor(vao.id, binding as GLuint, layout.divisor);
+				};
+			};
+		} else {
+			unsafe { gl::BindVertexArray(vao.id) };
+		}
+
+		for (name, info) in &self.program.reflection.attributes {
+			let found = self.buffers.iter().
+				enumerate().
+				filter_map(|(binding, &(vbo, ref layout))| {
+					layout.attribute(name).map(|attribute| (binding as GLuint, vbo, layout, attribute))
+				}).
+				next();
+
+			match found {
+				Some((binding, vbo, layout, attribute)) => {
+					// Matrices take one location per column
+					for column in 0..attribute.locations {
+						let location = info.location as GLuint + column;
+						let offset = attribute.offset + column as usize * attribute.location_size();
+
+						if dsa {
+							VAOBuilder::format_attribute(vao.id, location, binding, attribute, offset);
+						} else {
+							VAOBuilder::bind_attribute(location, vbo, layout, attribute, offset);
+						}
+						vao.attributes.push((location, binding, attribute.clone(), offset));
+					};
+				},
+				None => {
+					println!("{}: no vertex data for {} attribute \"{}\"", self.program.label(),
+							 reflection::type_name(info.ty), name);
+				},
+			};
+		};
+
+		unsafe {
+			if let Some(ibo) = self.index_buffer {
+				if dsa {
+					gl::VertexArrayElementBuffer(vao.id, ibo);
+				} else {
+					gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ibo);
+				}
+			}
+
+			if !dsa {
+				gl::BindVertexArray(0);
+				gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+			}
+		};
+
+		vao.vbos = self.owned;
+		vao.buffers = self.buffers;
+		vao
+	}
+
+	fn format_attribute(vao: GLuint, location: GLuint, binding: GLuint, attribute: &VertexAttribute, offset: usize) {
+		unsafe {
+			gl::EnableVertexArrayAttrib(vao, location);
+
+			if attribute.integer {
+				gl::VertexArrayAttribIFormat(vao, location, attribute.components, attribute.ty, offset as GLuint);
+			} else {
+				gl::VertexArrayAttribFormat(vao, location, attribute.components, attribute.ty,
+				                            attribute.normalized as GLboolean, offset as GLuint);
+			}
+
+			gl::VertexArrayAttribBinding(vao, location, binding);
+		};
+	}
+
+	// Pre-4.5 path, with the vertex array bound
+	fn bind_attribute(location: GLuint, vbo: GLuint, layout: &VertexLayout, attribute: &VertexAttribute, offset: usize) {
+		unsafe {
+			gl::EnableVertexAttribArray(location);
+			
+			gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+			if attribute.integer {
+				gl::VertexAttribIPointer(location,
+										 attribute.components,
+										 attribute.ty,
+										 layout.stride as GLsizei,
+										 offset as *const GLvoid);
+			} else {
+				gl::VertexAttribPointer(location,
+										attribute.components,
+										attribute.ty,
+										attribute.normalized as GLboolean,
+										layout.stride as GLsizei,
+										offset as *const GLvoid);
+			}
+			gl::VertexAttribDivisor(location, layout.divisor);
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use cgmath::Vector4;
+	use gfx::lowlevel::test_context;
+	use super::*;
+
+	#[test]
+	fn automatic_index_types_start_at_u16() {
+		assert_eq!(IndexType::for_vertex_count(3), IndexType::U16);
+		assert_eq!(IndexType::for_vertex_count(1 << 16), IndexType::U16);
+		assert_eq!(IndexType::for_vertex_count((1 << 16) + 1), IndexType::U32);
+	}
+
+	#[test]
+	fn indices_round_trip() {
+		test_context::make_current();
+
+		let ibo = IBO::narrowest(&[0, 1, 2, 2, 1, 3], 4);
+		assert_eq!(ibo.index_type, IndexType::U16);
+		assert_eq!(ibo.read(), vec![0, 1, 2, 2, 1, 3]);
+
+		let wide = IBO::uninitialized(IndexType::U32, 4, BufferUsage::Static);
+		wide.write(0, &[1 << 20, 0]);
+		wide.write(2, &ibo.read()[..2]);
+		assert_eq!(wide.read(), vec![1 << 20, 0, 0, 1]);
+	}
+
+	vertex_format! {
+		pub struct TestVertex {
+			pub value: Vector4<GLfloat>,
+		}
+	}
+
+	fn binding_offset(vao: &VAO, binding: GLuint) -> i64 {
+		let mut offset = -1;
+		unsafe { gl::GetVertexArrayIndexed64iv(vao.id, binding, gl::VERTEX_BINDING_OFFSET, &mut offset) };
+		offset
+	}
+
+	#[test]
+	fn buffers_can_start_partway_in() {
+		test_context::make_current();
+
+		let vertex = ShaderSource::new(None, "#version 330 core\nin vec4 value;\nvoid main() { gl_Position = value; }\n");
+		let fragment = ShaderSource::new(None, "#version 330 core\nout vec4 color;\nvoid main() { color = vec4(1.0); }\n");
+		let program = Program::from_stages_cached(&[(ShaderStage::Vertex, &vertex), (ShaderStage::Fragment, &fragment)],
+		                                          None).unwrap();
+
+		let vbo = VBO::new(&[TestVertex { value: Vector4::new(0.0, 0.0, 0.0, 1.0) }; 4]).unwrap();
+		let vao = VAOBuilder::new(&program).attach(&vbo, TestVertex::layout()).build();
+		assert_eq!(binding_offset(&vao, 0), 0);
+
+		vao.set_buffer_offset(0, 32);
+		assert_eq!(binding_offset(&vao, 0), 32);
+	}
+
+	#[test]
+	#[should_panic(expected = "too wide")]
+	fn writing_a_wide_index_panics() {
+		test_context::make_current();
+
+		IBO::uninitialized(IndexType::U16, 1, BufferUsage::Static).write(0, &[1 << 16]);
+	}
+}
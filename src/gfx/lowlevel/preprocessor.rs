@@ -0,0 +1,451 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use gfx::lowlevel::shader::*;
+
+/// `#define`s injected into a shader variant, by name. Ordered, so a set of defines can key a
+/// cache of compiled permutations.
+pub type Defines = BTreeMap<String, String>;
+
+struct Preprocessor<'a> {
+	defines: &'a Defines,
+	/// The injected defines plus those from `#define`s in active code, for deciding which
+	/// conditional branches are live.
+	defined: HashMap<String, String>,
+	files: Vec<SourceFile>,
+	lines: Vec<Option<(usize, u32)>>,
+	text: String,
+	/// Every file expanded in code that's certainly active; a file is only pulled in once, so
+	/// includes are guarded whether or not the file has its own guard.
+	included: HashSet<PathBuf>,
+	/// The chain of files currently being expanded, for cycle detection.
+	stack: Vec<PathBuf>,
+}
+
+/// Whether code is compiled: `Some` when the preprocessor can tell, `None` when it depends on
+/// an `#if` expression only the driver evaluates.
+type Active = Option<bool>;
+
+fn and(a: Active, b: Active) -> Active {
+	match (a, b) {
+		(Some(false), _) | (_, Some(false)) => Some(false),
+		(Some(true), Some(true)) => Some(true),
+		_ => None,
+	}
+}
+
+fn or(a: Active, b: Active) -> Active {
+	match (a, b) {
+		(Some(true), _) | (_, Some(true)) => Some(true),
+		(Some(false), Some(false)) => Some(false),
+		_ => None,
+	}
+}
+
+/// One `#if`/`#ifdef` group in the file being expanded.
+struct Conditional {
+	/// Whether the code around the group is active.
+	enclosing: Active,
+	/// Whether an earlier branch of the group was taken.
+	taken: Active,
+	/// Whether the current branch is taken.
+	current: Active,
+}
+
+impl Conditional {
+	fn active(&self) -> Active {
+		and(self.enclosing, self.current)
+	}
+
+	fn branch(&mut self, condition: Active) {
+		self.current = and(self.taken.map(|taken| !taken), condition);
+		self.taken = or(self.taken, condition);
+	}
+}
+
+fn canonical(path: &Path) -> PathBuf {
+	fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Parses the file name out of `#include "file"`.
+fn include_target(line: &str) -> Option<&str> {
+	let rest = line.trim_start()["#include".len()..].trim();
+
+	if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+		Some(&rest[1..(rest.len() - 1)])
+	} else {
+		None
+	}
+}
+
+/// The name after `#ifdef`, `#define` and the like.
+fn directive_name(rest: &str) -> &str {
+	rest.split(|c: char| c.is_whitespace() || c == '(').next().unwrap_or("")
+}
+
+impl<'a> Preprocessor<'a> {
+	/// Evaluates the simple `#if` conditions: integers, `NAME`, `defined(NAME)` and their
+	/// negations. Anything else is left to the driver.
+	fn evaluate(&self, condition: &str) -> Active {
+		let condition = condition.split("//").next().unwrap().trim();
+
+		if let Some(negated) = condition.strip_prefix('!') {
+			return self.evaluate(negated).map(|value| !value);
+		}
+
+		if let Some(name) = condition.strip_prefix("defined") {
+			let name = name.trim().
+				trim_start_matches('(').
+				trim_end_matches(')').
+				trim();
+			return if directive_name(name) == name && !name.is_empty() {
+				Some(self.defined.contains_key(name))
+			} else {
+				None
+			};
+		}
+
+		if let Ok(value) = condition.parse::<i64>() {
+			return Some(value != 0);
+		}
+
+		let is_name = !condition.is_empty() && condition.chars().all(|c| c.is_alphanumeric() || c == '_');
+		if !is_name {
+			return None;
+		}
+
+		// Undefined names are 0, as in C
+		match self.defined.get(condition) {
+			Some(value) => value.trim().parse::<i64>().ok().map(|value| value != 0),
+			None => Some(false),
+		}
+	}
+
+	fn emit(&mut self, line: &str, origin: Option<(usize, u32)>) {
+		self.text.push_str(line);
+		self.text.push('\n');
+		self.lines.push(origin);
+	}
+
+	fn emit_defines(&mut self) {
+		let defines = self.defines;
+
+		for (name, value) in defines {
+			self.emit(&format!("#define {} {}", name, value), None);
+		};
+	}
+
+	/// Expands the file at `path`, whose text is `text`; `enclosing` says whether the include
+	/// directive that pulled it in is active.
+	fn expand(&mut self, path: &Path, text: String, enclosing: Active) -> Result<(), ShaderError> {
+		let file_index = self.files.len();
+		let top_level = self.stack.is_empty();
+		let canonical_path = canonical(path);
+
+		self.files.push(SourceFile { path: Some(path.to_path_buf()), text: text.clone() });
+		if enclosing == Some(true) {
+			self.included.insert(canonical_path.clone());
+		}
+		self.stack.push(canonical_path);
+		let mut conditionals: Vec<Conditional> = Vec::new();
+
+		// Defines go right after #version, which has to come first
+		let has_version = text.lines().any(|line| line.trim_start().starts_with("#version"));
+		if top_level && !has_version {
+			self.emit_defines();
+		}
+
+		for (i, line) in text.lines().enumerate() {
+			let line_number = i as u32 + 1;
+			let origin = Some((file_index, line_number));
+			let trimmed = line.trim_start();
+			let active = conditionals.last().map_or(enclosing, Conditional::active);
+
+			// Conditionals are passed on to the driver, but tracked to know which includes
+			// and defines count
+			let directive = trimmed.strip_prefix('#').map_or("", str::trim_start);
+			let (keyword, rest) = match directive.find(|c: char| c.is_whitespace()) {
+				Some(end) => (&directive[..end], directive[end..].trim()),
+				None => (directive, ""),
+			};
+
+			let error = |preprocessor: &Preprocessor, message: &str| {
+				ShaderError::preprocess(&preprocessor.files[file_index], line_number, message)
+			};
+
+			match keyword {
+				"ifdef" | "ifndef" | "if" => {
+					let condition = match keyword {
+						"ifdef" => Some(self.defined.contains_key(directive_name(rest))),
+						"ifndef" => Some(!self.defined.contains_key(directive_name(rest))),
+						_ => self.evaluate(rest),
+					};
+					conditionals.push(Conditional { enclosing: active, taken: condition, current: condition });
+				},
+				"elif" | "else" => {
+					let condition = if keyword == "elif" { self.evaluate(rest) } else { Some(true) };
+					match conditionals.last_mut() {
+						Some(conditional) => conditional.branch(condition),
+						None => return Err(error(self, &format!("#{} without #if", keyword))),
+					};
+				},
+				"endif" if conditionals.pop().is_none() => {
+					return Err(error(self, "#endif without #if"));
+				},
+				"define" if active == Some(true) => {
+					let name = directive_name(rest);
+					let value = rest[name.len()..].trim();
+					self.defined.insert(String::from(name), String::from(value));
+				},
+				"undef" if active == Some(true) => {
+					self.defined.remove(directive_name(rest));
+				},
+				_ => (),
+			};
+
+			if trimmed.starts_with("#pragma once") {
+				self.emit("", origin);
+			} else if trimmed.starts_with("#include") && active == Some(false) {
+				// The driver skips the branch, so it mustn't pull anything in either
+				self.emit("", origin);
+			} else if trimmed.starts_with("#include") {
+				let target = include_target(trimmed).ok_or_else(|| {
+					ShaderError::preprocess(&self.files[file_index], line_number,
+											"expected #include \"file\"")
+				})?;
+
+				let include_path = path.parent().unwrap_or(Path::new("")).join(target);
+				let canonical_include = canonical(&include_path);
+
+				if self.stack.contains(&canonical_include) {
+					let chain = self.stack.iter().
+						chain(Some(&canonical_include)).
+						map(|path| path.display().to_string()).
+						collect::<Vec<_>>().
+						join(" -> ");
+
+					return Err(ShaderError::preprocess(&self.files[file_index], line_number,
+													   &format!("include cycle: {}", chain)));
+				}
+
+				// Keep the line so the numbering of what follows is unaffected by a skip
+				self.emit("", origin);
+
+				if !self.included.contains(&canonical_include) {
+					let include_text = read_file(&include_path).map_err(|err| {
+						ShaderError::preprocess(&self.files[file_index], line_number,
+												&format!("{}: {}", include_path.display(), err))
+					})?;
+
+					self.expand(&include_path, include_text, active)?;
+				}
+			} else {
+				self.emit(line, origin);
+
+				if top_level && trimmed.starts_with("#version") {
+					self.emit_defines();
+				}
+			}
+		};
+
+		if !conditionals.is_empty() {
+			return Err(ShaderError::preprocess(&self.files[file_index], text.lines().count() as u32,
+											   "#if without #endif"));
+		}
+
+		self.stack.pop();
+
+		Ok(())
+	}
+}
+
+/// Loads a shader, expanding `#include "file"` directives relative to the including file and
+/// injecting `defines` after the `#version` line. The result's source map points every line
+/// back to the file it came from.
+pub fn preprocess(path: &Path, defines: &Defines) -> Result<ShaderSource, ShaderError> {
+	let text = read_file(path).map_err(|err| ShaderError::io(path, err))?;
+
+	let mut preprocessor = Preprocessor {
+		defines,
+		defined: defines.iter().map(|(name, value)| (name.clone(), value.clone())).collect(),
+		files: Vec::new(),
+		lines: Vec::new(),
+		text: String::new(),
+		included: HashSet::new(),
+		stack: Vec::new(),
+	};
+
+	preprocessor.expand(path, text, Some(true))?;
+
+	Ok(ShaderSource {
+		text: preprocessor.text,
+		map: SourceMap {
+			files: preprocessor.files,
+			lines: preprocessor.lines,
+		},
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::env;
+	use std::fs;
+	use std::path::PathBuf;
+	use std::process;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use gfx::lowlevel::shader::ShaderErrorKind;
+	use super::*;
+
+	static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+	/// Writes `files` into a fresh directory and returns it.
+	fn write_files(files: &[(&str, &str)]) -> PathBuf {
+		let dir = env::temp_dir().join(format!("four-kb-preprocessor-{}-{}", process::id(),
+											   NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		for &(name, text) in files {
+			fs::write(dir.join(name), text).unwrap();
+		};
+
+		dir
+	}
+
+	/// The non-blank lines of the preprocessed `main.glsl`.
+	fn lines(files: &[(&str, &str)], defines: &Defines) -> Result<Vec<String>, ShaderError> {
+		let dir = write_files(files);
+		let result = preprocess(&dir.join("main.glsl"), defines);
+		let _ = fs::remove_dir_all(&dir);
+
+		result.map(|source| {
+			source.text.lines().filter(|line| !line.trim().is_empty()).map(String::from).collect()
+		})
+	}
+
+	#[test]
+	fn nested_includes() {
+		let result = lines(&[
+			("main.glsl", "#version 330\n#include \"a.glsl\"\nmain\n"),
+			("a.glsl", "a\n#include \"b.glsl\"\n"),
+			("b.glsl", "b\n"),
+		], &Defines::new()).unwrap();
+
+		assert_eq!(result, ["#version 330", "a", "b", "main"]);
+	}
+
+	#[test]
+	fn source_map_points_at_includes() {
+		let dir = write_files(&[
+			("main.glsl", "#version 330\n#include \"a.glsl\"\nmain\n"),
+			("a.glsl", "a\n"),
+		]);
+		let source = preprocess(&dir.join("main.glsl"), &Defines::new()).unwrap();
+		let _ = fs::remove_dir_all(&dir);
+
+		let position = source.text.lines().position(|line| line == "a").unwrap();
+		let (file, line) = source.map.lines[position].unwrap();
+		assert!(source.map.files[file].path.as_ref().unwrap().ends_with("a.glsl"));
+		assert_eq!(line, 1);
+	}
+
+	#[test]
+	fn include_cycle() {
+		let err = lines(&[
+			("main.glsl", "#include \"a.glsl\"\n"),
+			("a.glsl", "#include \"b.glsl\"\n"),
+			("b.glsl", "#include \"a.glsl\"\n"),
+		], &Defines::new()).unwrap_err();
+
+		assert!(matches!(err.kind, ShaderErrorKind::Preprocess));
+		assert!(err.log.starts_with("include cycle"), "{}", err.log);
+		assert!(err.path.unwrap().ends_with("b.glsl"));
+	}
+
+	#[test]
+	fn included_once() {
+		let result = lines(&[
+			("main.glsl", "#include \"a.glsl\"\n#include \"b.glsl\"\n#include \"a.glsl\"\n"),
+			("a.glsl", "#pragma once\na\n"),
+			("b.glsl", "#include \"a.glsl\"\nb\n"),
+		], &Defines::new()).unwrap();
+
+		assert_eq!(result, ["a", "b"]);
+	}
+
+	#[test]
+	fn defines_follow_version() {
+		let mut defines = Defines::new();
+		defines.insert(String::from("NORMAL_MAP"), String::from("1"));
+
+		let result = lines(&[
+			("main.glsl", "// comment\n#version 330\nmain\n"),
+		], &defines).unwrap();
+
+		assert_eq!(result, ["// comment", "#version 330", "#define NORMAL_MAP 1", "main"]);
+	}
+
+	#[test]
+	fn defines_without_version() {
+		let mut defines = Defines::new();
+		defines.insert(String::from("NORMAL_MAP"), String::from("1"));
+
+		let result = lines(&[("main.glsl", "main\n")], &defines).unwrap();
+
+		assert_eq!(result, ["#define NORMAL_MAP 1", "main"]);
+	}
+
+	#[test]
+	fn includes_in_inactive_branches_are_skipped() {
+		let files = [
+			("main.glsl", concat!("#ifdef A\n#include \"a.glsl\"\n",
+								  "#else\n#include \"b.glsl\"\n",
+								  "#endif\n#include \"a.glsl\"\n")),
+			("a.glsl", "a\n"),
+			("b.glsl", "b\n"),
+		];
+
+		let result = lines(&files, &Defines::new()).unwrap();
+		assert_eq!(result, ["#ifdef A", "#else", "b", "#endif", "a"]);
+
+		let mut defines = Defines::new();
+		defines.insert(String::from("A"), String::from("1"));
+		let result = lines(&files, &defines).unwrap();
+		assert_eq!(result, ["#define A 1", "#ifdef A", "a", "#else", "#endif"]);
+	}
+
+	#[test]
+	fn conditionals_see_source_defines() {
+		let result = lines(&[
+			("main.glsl", concat!("#define B\n",
+								  "#if defined(B) && 1\n#include \"a.glsl\"\n",
+								  "#elif !defined(B)\n#include \"b.glsl\"\n",
+								  "#endif\n")),
+			("a.glsl", "a\n"),
+			("b.glsl", "b\n"),
+		], &Defines::new()).unwrap();
+
+		// The driver decides `#if` expressions the preprocessor can't, so the first branch is
+		// expanded; the `#elif` can only be taken if it wasn't, and B is defined either way
+		assert_eq!(result, ["#define B", "#if defined(B) && 1", "a", "#elif !defined(B)", "#endif"]);
+	}
+
+	#[test]
+	fn undecided_branches_are_not_guarded() {
+		let result = lines(&[
+			("main.glsl", "#if X > 1\n#include \"a.glsl\"\n#else\n#include \"a.glsl\"\n#endif\n"),
+			("a.glsl", "a\n"),
+		], &Defines::new()).unwrap();
+
+		assert_eq!(result, ["#if X > 1", "a", "#else", "a", "#endif"]);
+	}
+
+	#[test]
+	fn unbalanced_conditionals() {
+		let err = lines(&[("main.glsl", "#ifdef A\n")], &Defines::new()).unwrap_err();
+		assert_eq!(err.log, "#if without #endif");
+
+		let err = lines(&[("main.glsl", "#endif\n")], &Defines::new()).unwrap_err();
+		assert_eq!(err.log, "#endif without #if");
+	}
+}
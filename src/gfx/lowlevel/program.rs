@@ -2,26 +2,39 @@ use gl;
 use gl::types::*;
 use std::ptr;
 use std::str;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::ffi::CString;
-use std::fs::File;
-use std::path::Path;
-use std::io::Read;
-use cgmath::{Matrix,Matrix4};
+use std::path::{Path, PathBuf};
+use gfx::lowlevel::shader::*;
+use gfx::lowlevel::preprocessor::{preprocess, Defines};
+use gfx::lowlevel::{label_object, reflect, type_name, Reflection};
+use gfx::lowlevel::accounting::{self, Category};
+use gfx::lowlevel::caps::caps;
+use gfx::lowlevel::uniform::Uniformable;
+use gfx::lowlevel::program_cache::ProgramCache;
 
 pub struct Program {
-	pub vs: GLuint,
-	pub fs: GLuint,
+	/// The compiled stages, kept attached for the life of the program.
+	pub shaders: Vec<GLuint>,
+	/// The stage of each shader in `shaders`.
+	pub stages: Vec<ShaderStage>,
 	pub id: GLuint,
+	/// Every file the program was built from, includes too.
+	pub source_paths: Vec<PathBuf>,
+	pub reflection: Reflection,
+	/// Uniforms already warned about, so a bad name is reported once rather than every draw.
+	warned: RefCell<HashSet<String>>,
 }
 
 impl Program {
-	fn compile_shader(src: &str, ty: GLenum) -> GLuint {
+	fn compile_shader(src: &ShaderSource, stage: ShaderStage) -> Result<GLuint, ShaderError> {
 		let shader;
 		unsafe {
-			shader = gl::CreateShader(ty);
+			shader = gl::CreateShader(stage.gl_enum());
 			
 			// Attempt to compile the shader
-			let c_str = CString::new(src.as_bytes()).unwrap();
+			let c_str = CString::new(src.text.as_bytes()).unwrap();
 			gl::ShaderSource(shader, 1, &c_str.as_ptr(), ptr::null());
 			gl::CompileShader(shader);
 
@@ -33,34 +46,68 @@ impl Program {
             let mut len = 0;
             gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
 
+            let mut log = String::new();
             if len > 0 {
-				let mut buf = Vec::with_capacity(len as usize);
-				buf.set_len((len as usize) - 1); // subtract 1 to skip the trailing null character
+				let mut buf = vec![0u8; len as usize];
 				gl::GetShaderInfoLog(shader,
 									 len,
 									 ptr::null_mut(),
 									 buf.as_mut_ptr() as *mut GLchar);
-				println!("{}",
-					   str::from_utf8(&buf)
-						   .ok()
-						   .expect("ShaderInfoLog not valid utf8"));
+				buf.truncate((len as usize) - 1); // skip the trailing null character
+				log = String::from_utf8_lossy(&buf).into_owned();
             };
 
 			if status != (gl::TRUE as GLint) {
-                panic!("Shader failed to link");
+				gl::DeleteShader(shader);
+                return Err(ShaderError::compile(stage, src, &log));
+			};
+
+			// Warnings only
+			if !log.is_empty() {
+				for entry in parse_log(&log, Some(&src.map)) {
+					let path = entry.path.map(|path| path.display().to_string()).unwrap_or_default();
+					println!("{}:{}: {}: {}", path, entry.line.unwrap_or(0), entry.severity, entry.message);
+				};
 			};
 		};
-		shader
+		Ok(shader)
 	}
 
-	pub fn new(vs_text: &str, fs_text: &str) -> Program {
-		let vs = Program::compile_shader(vs_text, gl::VERTEX_SHADER);
-		let fs = Program::compile_shader(fs_text, gl::FRAGMENT_SHADER);
+	/// Compiles each source as its stage and links them, for any combination of stages GL
+	/// accepts. Shaders compiled before a failure are cleaned up. With a `cache`, loads the
+	/// linked binary from it when there is a usable one, and stores it there after linking when
+	/// there isn't.
+	pub fn from_stages_cached(stages: &[(ShaderStage, &ShaderSource)], cache: Option<&ProgramCache>)
+			-> Result<Program, ShaderError> {
+		let cache = cache.map(|cache| (cache, cache.key(stages)));
+
+		if let Some((cache, key)) = cache {
+			if let Some(program) = Program::from_binary(stages, cache, key) {
+				return Ok(program);
+			}
+		}
+
+		let mut shaders = Vec::with_capacity(stages.len());
+		for &(stage, src) in stages {
+			match Program::compile_shader(src, stage) {
+				Ok(shader) => shaders.push(shader),
+				Err(err) => {
+					for shader in shaders {
+						unsafe { gl::DeleteShader(shader) };
+					};
+					return Err(err);
+				},
+			};
+		};
 	
 		unsafe {
 			let program = gl::CreateProgram();
-			gl::AttachShader(program, vs);
-			gl::AttachShader(program, fs);
+			for &shader in &shaders {
+				gl::AttachShader(program, shader);
+			};
+			if cache.is_some() {
+				gl::ProgramParameteri(program, gl::PROGRAM_BINARY_RETRIEVABLE_HINT, gl::TRUE as GLint);
+			}
 			gl::LinkProgram(program);
 			
 			// Get the link status
@@ -71,62 +118,273 @@ impl Program {
             let mut len: GLint = 0;
             gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
             
+            let mut log = String::new();
             if len > 0 {
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1); // subtract 1 to skip the trailing null character
+                let mut buf = vec![0u8; len as usize];
                 
                 gl::GetProgramInfoLog(program,
                                       len,
                                       ptr::null_mut(),
                                       buf.as_mut_ptr() as *mut GLchar);
+                buf.truncate((len as usize) - 1); // skip the trailing null character
                                       
-                println!("{}",
-                       str::from_utf8(&buf)
-                           .ok()
-                           .expect("ProgramInfoLog not valid utf8"));
+                log = String::from_utf8_lossy(&buf).into_owned();
             }
 
+			// Dropping it cleans up the shaders and the program
+			let program = Program::linked(program, shaders, stages, status == (gl::TRUE as GLint));
+
 			if status != (gl::TRUE as GLint) {
-                panic!("Shader failed to compile");
+                return Err(ShaderError::link(&log));
+			}
+
+			if !log.is_empty() {
+				println!("{}", log);
+			}
+
+			if let Some((cache, key)) = cache {
+				program.store_binary(cache, key);
 			}
 			
-			Program { vs, fs, id: program }
+			Ok(program)
 		}
 	}
-	
-	pub fn from_path(vs_path: &Path, fs_path: &Path) -> Program {
-		let vs_text = {
-			let mut f = File::open(vs_path).unwrap();
-			let mut vs_text = String::new();
-			f.read_to_string(&mut vs_text).unwrap();
-			
-			vs_text
+
+	fn linked(id: GLuint, shaders: Vec<GLuint>, stages: &[(ShaderStage, &ShaderSource)], ok: bool) -> Program {
+		let source_paths = stages.iter().
+			flat_map(|&(_, src)| src.paths()).
+			map(Path::to_path_buf).
+			collect();
+		let reflection = if ok { reflect(id) } else { Reflection::default() };
+		let stages = stages.iter().map(|&(stage, _)| stage).collect();
+
+		let program = Program { shaders, stages, id, source_paths, reflection, warned: RefCell::new(HashSet::new()) };
+		// The linked binary is the closest thing to its memory use we can query
+		accounting::track(Category::Program, id, &program.label(), if ok { program.binary_size() } else { 0 });
+		label_object(gl::PROGRAM, id, &program.label());
+		program
+	}
+
+	/// A program from a cached binary, or `None` if there is none or the driver rejects it.
+	fn from_binary(stages: &[(ShaderStage, &ShaderSource)], cache: &ProgramCache, key: u64) -> Option<Program> {
+		let (format, data) = cache.load(key)?;
+
+		unsafe {
+			let program = gl::CreateProgram();
+			gl::ProgramBinary(program, format, data.as_ptr() as *const GLvoid, data.len() as GLsizei);
+
+			let mut status = gl::FALSE as GLint;
+			gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
+
+			if status != (gl::TRUE as GLint) {
+				gl::DeleteProgram(program);
+				cache.remove(key);
+				return None;
+			}
+
+			Some(Program::linked(program, Vec::new(), stages, true))
+		}
+	}
+
+	fn store_binary(&self, cache: &ProgramCache, key: u64) {
+		let mut len: GLint = 0;
+		unsafe {
+			gl::GetProgramiv(self.id, gl::PROGRAM_BINARY_LENGTH, &mut len);
 		};
-		
-		let fs_text = {
-			let mut f = File::open(fs_path).unwrap();
-			let mut fs_text = String::new();
-			f.read_to_string(&mut fs_text).unwrap();
-			
-			fs_text
+
+		if len <= 0 {
+			return;
+		}
+
+		let mut data = vec![0u8; len as usize];
+		let mut format = 0;
+		let mut written = 0;
+		unsafe {
+			gl::GetProgramBinary(self.id, len, &mut written, &mut format, data.as_mut_ptr() as *mut GLvoid);
+		};
+		data.truncate(written as usize);
+
+		if let Err(err) = cache.store(key, format, &data) {
+			println!("Failed to cache {}: {}", self.label(), err);
+		}
+	}
+	
+	/// Loads, preprocesses and links one file per stage, through `cache` if given.
+	pub fn from_stage_paths(stages: &[(ShaderStage, &Path)], defines: &Defines, cache: Option<&ProgramCache>)
+			-> Result<Program, ShaderError> {
+		let mut sources = Vec::with_capacity(stages.len());
+		for &(stage, path) in stages {
+			sources.push((stage, preprocess(path, defines)?));
 		};
-		
-		Program::new(&vs_text, &fs_text)
+
+		let stages: Vec<_> = sources.iter().map(|&(stage, ref src)| (stage, src)).collect();
+		Program::from_stages_cached(&stages, cache)
 	}
+
 	
 	pub fn bind(&self) {
 		unsafe {
 			gl::UseProgram(self.id);
 		};
 	}
+
+	pub fn has_stage(&self, stage: ShaderStage) -> bool {
+		self.stages.contains(&stage)
+	}
+
+	/// Whether draws with the program must be made of patches.
+	pub fn is_tessellated(&self) -> bool {
+		self.has_stage(ShaderStage::TessEvaluation)
+	}
+
+	/// The `local_size` a compute program was declared with.
+	pub fn work_group_size(&self) -> [GLint; 3] {
+		let mut size = [0; 3];
+		unsafe {
+			gl::GetProgramiv(self.id, gl::COMPUTE_WORK_GROUP_SIZE, size.as_mut_ptr());
+		};
+		size
+	}
+
+	/// Binds a compute program and runs `x * y * z` work groups. Results written to buffers or
+	/// images need a `memory_barrier` before anything reads them.
+	pub fn dispatch(&self, x: GLuint, y: GLuint, z: GLuint) {
+		self.bind();
+		unsafe {
+			gl::DispatchCompute(x, y, z);
+		};
+	}
+
+	/// Enough work groups to cover `count` invocations along x, for one-dimensional jobs.
+	pub fn dispatch_1d(&self, count: usize) {
+		let local_size = self.work_group_size()[0].max(1) as usize;
+		self.dispatch(count.div_ceil(local_size) as GLuint, 1, 1);
+	}
+
+	/// A short name for messages: the main file of each stage, or the GL name.
+	pub fn label(&self) -> String {
+		if self.source_paths.is_empty() {
+			return format!("program {}", self.id);
+		}
+
+		self.source_paths.iter().
+			filter(|path| path.extension().is_none_or(|ext| ext != "glsl")).
+			map(|path| path.display().to_string()).
+			collect::<Vec<_>>().
+			join(" + ")
+	}
+
+	fn warn_once(&self, name: &str, message: String) {
+		if self.warned.borrow_mut().insert(String::from(name)) {
+			println!("{}: {}", self.label(), message);
+		}
+	}
+
+	/// Uploads `value` to the uniform `name` of this program, whichever program is bound, using
+	/// the location found at link time. A uniform that doesn't exist (or was optimized out), or
+	/// whose GLSL type doesn't match the value, is skipped with a warning the first time.
+	pub fn set_uniform<U: Uniformable + ?Sized>(&self, name: &str, value: &U) {
+		let info = match self.reflection.uniforms.get(name) {
+			Some(info) => info,
+			None => {
+				self.warn_once(name, format!("no active uniform named \"{}\"", name));
+				return;
+			},
+		};
+
+		if info.block_index.is_some() {
+			self.warn_once(name, format!("uniform \"{}\" is in a uniform block", name));
+		} else if !value.accepts(info.ty) {
+			self.warn_once(name, format!("uniform \"{}\" is a {}, which {} can't be assigned to",
+			                             name, type_name(info.ty), value.type_name()));
+		} else if value.count() > info.size as usize {
+			self.warn_once(name, format!("uniform \"{}\" holds {} elements, got {}",
+			                             name, info.size, value.count()));
+		} else if caps().direct_state_access {
+			value.upload(Some(self.id), info.location);
+		} else {
+			self.while_bound(|| value.upload(None, info.location));
+		}
+	}
+
+	/// Pre-4.5 path: makes the program current for `edit`, then puts back whichever was.
+	fn while_bound<F: FnOnce()>(&self, edit: F) {
+		let mut previous = 0;
+
+		unsafe {
+			gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut previous);
+			gl::UseProgram(self.id);
+			edit();
+			gl::UseProgram(previous as GLuint);
+		};
+	}
+
+	/// Size of the linked program as the driver reports it; a rough stand-in for its memory use.
+	pub fn binary_size(&self) -> usize {
+		let mut len: GLint = 0;
+		unsafe {
+			gl::GetProgramiv(self.id, gl::PROGRAM_BINARY_LENGTH, &mut len);
+		};
+		len as usize
+	}
 }
 
 impl Drop for Program {
 	fn drop(&mut self) {
+		accounting::untrack(Category::Program, self.id);
+
 		unsafe {
-			gl::DeleteShader(self.fs);
-			gl::DeleteShader(self.vs);
+			for &shader in &self.shaders {
+				gl::DeleteShader(shader);
+			};
 			gl::DeleteProgram(self.id);
 		};
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use gl;
+	use gfx::lowlevel::test_context;
+	use super::*;
+
+	const VERTEX: &str = "#version 330 core\nvoid main() { gl_Position = vec4(0.0); }\n";
+	const FRAGMENT: &str = "#version 330 core\nuniform float scale;\nout vec4 color;\n\
+	                        void main() { color = vec4(scale); }\n";
+
+	fn program() -> Program {
+		let vertex = ShaderSource::new(None, VERTEX);
+		let fragment = ShaderSource::new(None, FRAGMENT);
+		Program::from_stages_cached(&[(ShaderStage::Vertex, &vertex), (ShaderStage::Fragment, &fragment)], None).
+			unwrap()
+	}
+
+	fn scale_of(program: &Program) -> f32 {
+		let location = program.reflection.uniforms["scale"].location;
+		let mut value = 0.0;
+		unsafe { gl::GetUniformfv(program.id, location, &mut value) };
+		value
+	}
+
+	#[test]
+	fn uniforms_go_to_their_own_program() {
+		test_context::make_current();
+
+		let bound = program();
+		let other = program();
+		bound.bind();
+
+		other.set_uniform("scale", &2.0f32);
+		assert_eq!(scale_of(&other), 2.0);
+		assert_eq!(scale_of(&bound), 0.0);
+
+		let mut current = 0;
+		unsafe { gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut current) };
+		assert_eq!(current as GLuint, bound.id);
+
+		other.while_bound(|| 3.0f32.upload(None, other.reflection.uniforms["scale"].location));
+		assert_eq!(scale_of(&other), 3.0);
+		unsafe { gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut current) };
+		assert_eq!(current as GLuint, bound.id);
+	}
+}
@@ -0,0 +1,164 @@
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use gl;
+use gl::types::*;
+use gfx::lowlevel::shader::{ShaderSource, ShaderStage};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+	bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+fn gl_string(name: GLenum) -> String {
+	unsafe {
+		let string = gl::GetString(name);
+		if string.is_null() {
+			String::new()
+		} else {
+			CStr::from_ptr(string as *const _).to_string_lossy().into_owned()
+		}
+	}
+}
+
+/// Linked program binaries on disk, so unchanged programs skip compiling on the next run.
+///
+/// Entries are keyed by a hash of every stage's preprocessed text (which has the defines
+/// injected) and the driver's vendor, renderer and version, so a driver update misses
+/// rather than handing GL a binary it can't use.
+pub struct ProgramCache {
+	dir: PathBuf,
+	driver: String,
+}
+
+impl ProgramCache {
+	/// A cache in `dir`, created on the first store. `None` when the driver doesn't support
+	/// any binary formats. Must be called with a current GL context.
+	pub fn new(dir: &Path) -> Option<ProgramCache> {
+		let mut formats = 0;
+		unsafe {
+			gl::GetIntegerv(gl::NUM_PROGRAM_BINARY_FORMATS, &mut formats);
+		};
+
+		if formats == 0 {
+			return None;
+		}
+
+		let driver = format!("{}\n{}\n{}", gl_string(gl::VENDOR), gl_string(gl::RENDERER), gl_string(gl::VERSION));
+
+		Some(ProgramCache { dir: dir.to_path_buf(), driver })
+	}
+
+	pub fn key(&self, stages: &[(ShaderStage, &ShaderSource)]) -> u64 {
+		let mut hash = fnv1a(FNV_OFFSET, self.driver.as_bytes());
+
+		for &(stage, src) in stages {
+			hash = fnv1a(hash, stage.name().as_bytes());
+			hash = fnv1a(hash, &[0]);
+			hash = fnv1a(hash, src.text.as_bytes());
+			hash = fnv1a(hash, &[0]);
+		};
+
+		hash
+	}
+
+	fn path(&self, key: u64) -> PathBuf {
+		self.dir.join(format!("{:016x}.bin", key))
+	}
+
+	/// The binary format and data stored under `key`, if any.
+	pub fn load(&self, key: u64) -> Option<(GLenum, Vec<u8>)> {
+		let mut file = File::open(self.path(key)).ok()?;
+
+		let format = file.read_u32::<LittleEndian>().ok()?;
+		let mut data = Vec::new();
+		file.read_to_end(&mut data).ok()?;
+
+		Some((format, data))
+	}
+
+	pub fn store(&self, key: u64, format: GLenum, data: &[u8]) -> io::Result<()> {
+		fs::create_dir_all(&self.dir)?;
+
+		// Written aside and renamed, so a crash never leaves a truncated entry behind
+		let path = self.path(key);
+		let temp_path = path.with_extension("tmp");
+		{
+			let mut file = File::create(&temp_path)?;
+			file.write_u32::<LittleEndian>(format)?;
+			file.write_all(data)?;
+		}
+
+		fs::rename(temp_path, path)
+	}
+
+	/// Drops an entry the driver refused, so it isn't tried again.
+	pub fn remove(&self, key: u64) {
+		let _ = fs::remove_file(self.path(key));
+	}
+
+	/// Deletes every cached binary.
+	pub fn clear(&self) -> io::Result<()> {
+		match fs::remove_dir_all(&self.dir) {
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+			result => result,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::env;
+	use std::process;
+	use super::*;
+
+	fn cache(driver: &str) -> ProgramCache {
+		ProgramCache {
+			dir: env::temp_dir().join(format!("four-kb-program-cache-{}-{}", process::id(), driver)),
+			driver: String::from(driver),
+		}
+	}
+
+	#[test]
+	fn fnv1a_matches_reference_values() {
+		assert_eq!(fnv1a(FNV_OFFSET, b""), 0xcbf29ce484222325);
+		assert_eq!(fnv1a(FNV_OFFSET, b"a"), 0xaf63dc4c8601ec8c);
+		assert_eq!(fnv1a(FNV_OFFSET, b"foobar"), 0x85944171f73967e8);
+
+		// Hashing in pieces is the same as hashing at once
+		assert_eq!(fnv1a(fnv1a(FNV_OFFSET, b"foo"), b"bar"), fnv1a(FNV_OFFSET, b"foobar"));
+	}
+
+	#[test]
+	fn keys_cover_driver_stages_and_text() {
+		let vertex = ShaderSource::new(None, "void main() {}\n");
+		let other = ShaderSource::new(None, "void main() { }\n");
+
+		let mesa = cache("mesa");
+		let base = mesa.key(&[(ShaderStage::Vertex, &vertex), (ShaderStage::Fragment, &vertex)]);
+
+		assert_eq!(mesa.key(&[(ShaderStage::Vertex, &vertex), (ShaderStage::Fragment, &vertex)]), base);
+		assert_ne!(cache("nvidia").key(&[(ShaderStage::Vertex, &vertex), (ShaderStage::Fragment, &vertex)]), base);
+		assert_ne!(mesa.key(&[(ShaderStage::Vertex, &vertex), (ShaderStage::Fragment, &other)]), base);
+		assert_ne!(mesa.key(&[(ShaderStage::Vertex, &vertex), (ShaderStage::Geometry, &vertex)]), base);
+		assert_ne!(mesa.key(&[(ShaderStage::Vertex, &vertex)]), base);
+	}
+
+	#[test]
+	fn entries_round_trip() {
+		let cache = cache("round-trip");
+		let _ = cache.clear();
+
+		assert_eq!(cache.load(7), None);
+		cache.store(7, 0x8e11, &[1, 2, 3]).unwrap();
+		assert_eq!(cache.load(7), Some((0x8e11, vec![1, 2, 3])));
+
+		cache.remove(7);
+		assert_eq!(cache.load(7), None);
+		cache.clear().unwrap();
+	}
+}
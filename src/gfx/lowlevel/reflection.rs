@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use gl;
+use gl::types::*;
+
+#[derive(Clone, Debug)]
+pub struct UniformInfo {
+	/// GLSL type, e.g. `gl::FLOAT_VEC3` or `gl::SAMPLER_2D`.
+	pub ty: GLenum,
+	/// Element count; above 1 for arrays.
+	pub size: GLint,
+	/// -1 for members of uniform blocks.
+	pub location: GLint,
+	/// The block the uniform lives in, if any.
+	pub block_index: Option<GLuint>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UniformBlockInfo {
+	pub data_size: GLint,
+	pub binding: GLint,
+}
+
+#[derive(Clone, Debug)]
+pub struct AttributeInfo {
+	/// GLSL type, e.g. `gl::FLOAT_VEC3` or `gl::FLOAT_MAT4`.
+	pub ty: GLenum,
+	pub location: GLint,
+}
+
+/// Everything a linked program exposes, by name.
+#[derive(Clone, Debug, Default)]
+pub struct Reflection {
+	pub uniforms: HashMap<String, UniformInfo>,
+	pub uniform_blocks: HashMap<String, UniformBlockInfo>,
+	pub attributes: HashMap<String, AttributeInfo>,
+}
+
+pub fn type_name(ty: GLenum) -> &'static str {
+	match ty {
+		gl::FLOAT => "float",
+		gl::FLOAT_VEC2 => "vec2",
+		gl::FLOAT_VEC3 => "vec3",
+		gl::FLOAT_VEC4 => "vec4",
+		gl::INT => "int",
+		gl::INT_VEC2 => "ivec2",
+		gl::INT_VEC3 => "ivec3",
+		gl::INT_VEC4 => "ivec4",
+		gl::UNSIGNED_INT => "uint",
+		gl::UNSIGNED_INT_VEC2 => "uvec2",
+		gl::UNSIGNED_INT_VEC3 => "uvec3",
+		gl::UNSIGNED_INT_VEC4 => "uvec4",
+		gl::BOOL => "bool",
+		gl::BOOL_VEC2 => "bvec2",
+		gl::BOOL_VEC3 => "bvec3",
+		gl::BOOL_VEC4 => "bvec4",
+		gl::FLOAT_MAT2 => "mat2",
+		gl::FLOAT_MAT3 => "mat3",
+		gl::FLOAT_MAT4 => "mat4",
+		gl::SAMPLER_2D => "sampler2D",
+		gl::SAMPLER_3D => "sampler3D",
+		gl::SAMPLER_CUBE => "samplerCube",
+		gl::SAMPLER_2D_SHADOW => "sampler2DShadow",
+		gl::SAMPLER_2D_ARRAY => "sampler2DArray",
+		_ if is_sampler_type(ty) => "sampler",
+		_ => "unknown",
+	}
+}
+
+/// Whether `ty` is an opaque type that takes a texture or image unit.
+pub fn is_sampler_type(ty: GLenum) -> bool {
+	matches!(ty,
+		gl::SAMPLER_1D | gl::SAMPLER_2D | gl::SAMPLER_3D | gl::SAMPLER_CUBE |
+		gl::SAMPLER_1D_SHADOW | gl::SAMPLER_2D_SHADOW | gl::SAMPLER_CUBE_SHADOW |
+		gl::SAMPLER_1D_ARRAY | gl::SAMPLER_2D_ARRAY | gl::SAMPLER_CUBE_MAP_ARRAY |
+		gl::SAMPLER_1D_ARRAY_SHADOW | gl::SAMPLER_2D_ARRAY_SHADOW | gl::SAMPLER_CUBE_MAP_ARRAY_SHADOW |
+		gl::SAMPLER_2D_MULTISAMPLE | gl::SAMPLER_2D_MULTISAMPLE_ARRAY |
+		gl::SAMPLER_BUFFER | gl::SAMPLER_2D_RECT | gl::SAMPLER_2D_RECT_SHADOW |
+		gl::INT_SAMPLER_2D | gl::INT_SAMPLER_3D | gl::INT_SAMPLER_CUBE | gl::INT_SAMPLER_2D_ARRAY |
+		gl::UNSIGNED_INT_SAMPLER_2D | gl::UNSIGNED_INT_SAMPLER_3D |
+		gl::UNSIGNED_INT_SAMPLER_CUBE | gl::UNSIGNED_INT_SAMPLER_2D_ARRAY |
+		gl::IMAGE_2D | gl::IMAGE_3D | gl::IMAGE_CUBE | gl::IMAGE_2D_ARRAY |
+		gl::INT_IMAGE_2D | gl::UNSIGNED_INT_IMAGE_2D)
+}
+
+// Array uniforms are reported as "name[0]"; they're looked up by the bare name
+fn strip_array_suffix(name: String) -> String {
+	match name.find("[0]") {
+		Some(pos) if pos + 3 == name.len() => String::from(&name[..pos]),
+		_ => name,
+	}
+}
+
+fn name_from_buf(mut buf: Vec<u8>, len: GLsizei) -> String {
+	buf.truncate(len as usize);
+	String::from_utf8_lossy(&buf).into_owned()
+}
+
+/// Queries the active uniforms, uniform blocks and attributes of a linked program.
+pub fn reflect(program: GLuint) -> Reflection {
+	let mut reflection = Reflection::default();
+
+	unsafe {
+		let mut count = 0;
+		let mut max_len = 0;
+
+		gl::GetProgramiv(program, gl::ACTIVE_UNIFORMS, &mut count);
+		gl::GetProgramiv(program, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_len);
+
+		for i in 0..(count as GLuint) {
+			let mut buf = vec![0u8; max_len.max(1) as usize];
+			let mut len = 0;
+			let mut size = 0;
+			let mut ty = 0;
+			gl::GetActiveUniform(program, i, max_len, &mut len, &mut size, &mut ty,
+								 buf.as_mut_ptr() as *mut GLchar);
+			let full_name = name_from_buf(buf, len);
+
+			let mut block_index = -1;
+			gl::GetActiveUniformsiv(program, 1, &i, gl::UNIFORM_BLOCK_INDEX, &mut block_index);
+
+			let location = gl::GetUniformLocation(program, CString::new(full_name.as_str()).unwrap().as_ptr());
+			let name = strip_array_suffix(full_name);
+
+			reflection.uniforms.insert(name, UniformInfo {
+				ty,
+				size,
+				location,
+				block_index: if block_index < 0 { None } else { Some(block_index as GLuint) },
+			});
+		};
+
+		gl::GetProgramiv(program, gl::ACTIVE_UNIFORM_BLOCKS, &mut count);
+		gl::GetProgramiv(program, gl::ACTIVE_UNIFORM_BLOCK_MAX_NAME_LENGTH, &mut max_len);
+
+		for index in 0..(count as GLuint) {
+			let mut buf = vec![0u8; max_len.max(1) as usize];
+			let mut len = 0;
+			gl::GetActiveUniformBlockName(program, index, max_len, &mut len,
+										  buf.as_mut_ptr() as *mut GLchar);
+			let name = name_from_buf(buf, len);
+
+			let mut data_size = 0;
+			let mut binding = 0;
+			gl::GetActiveUniformBlockiv(program, index, gl::UNIFORM_BLOCK_DATA_SIZE, &mut data_size);
+			gl::GetActiveUniformBlockiv(program, index, gl::UNIFORM_BLOCK_BINDING, &mut binding);
+
+			reflection.uniform_blocks.insert(name, UniformBlockInfo { data_size, binding });
+		};
+
+		gl::GetProgramiv(program, gl::ACTIVE_ATTRIBUTES, &mut count);
+		gl::GetProgramiv(program, gl::ACTIVE_ATTRIBUTE_MAX_LENGTH, &mut max_len);
+
+		for i in 0..(count as GLuint) {
+			let mut buf = vec![0u8; max_len.max(1) as usize];
+			let mut len = 0;
+			let mut size = 0;
+			let mut ty = 0;
+			gl::GetActiveAttrib(program, i, max_len, &mut len, &mut size, &mut ty,
+								buf.as_mut_ptr() as *mut GLchar);
+			let name = name_from_buf(buf, len);
+
+			// Built-ins like gl_VertexID are active but have no location
+			if name.starts_with("gl_") {
+				continue;
+			}
+
+			let location = gl::GetAttribLocation(program, CString::new(name.as_str()).unwrap().as_ptr());
+			if location < 0 {
+				println!("program {}: active attribute \"{}\" has no location", program, name);
+				continue;
+			}
+
+			reflection.attributes.insert(name, AttributeInfo { ty, location });
+		};
+	};
+
+	reflection
+}
@@ -0,0 +1,217 @@
+use gl;
+use gl::types::*;
+use gfx::lowlevel::caps::caps;
+
+// EXT_texture_filter_anisotropic isn't part of the core bindings until 4.6.
+const TEXTURE_MAX_ANISOTROPY: GLenum = 0x84FE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+	Nearest,
+	Linear,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+	Repeat,
+	MirroredRepeat,
+	ClampToEdge,
+	ClampToBorder,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareFunc {
+	Never,
+	Less,
+	Equal,
+	LessEqual,
+	Greater,
+	NotEqual,
+	GreaterEqual,
+	Always,
+}
+
+impl Wrap {
+	fn gl_enum(self) -> GLenum {
+		match self {
+			Wrap::Repeat => gl::REPEAT,
+			Wrap::MirroredRepeat => gl::MIRRORED_REPEAT,
+			Wrap::ClampToEdge => gl::CLAMP_TO_EDGE,
+			Wrap::ClampToBorder => gl::CLAMP_TO_BORDER,
+		}
+	}
+}
+
+impl CompareFunc {
+	/// The `compare=` sampler option in material files.
+	pub fn parse(word: &str) -> Option<CompareFunc> {
+		match word {
+			"never" => Some(CompareFunc::Never),
+			"less" => Some(CompareFunc::Less),
+			"equal" => Some(CompareFunc::Equal),
+			"less_equal" => Some(CompareFunc::LessEqual),
+			"greater" => Some(CompareFunc::Greater),
+			"not_equal" => Some(CompareFunc::NotEqual),
+			"greater_equal" => Some(CompareFunc::GreaterEqual),
+			"always" => Some(CompareFunc::Always),
+			_ => None,
+		}
+	}
+
+	fn gl_enum(self) -> GLenum {
+		match self {
+			CompareFunc::Never => gl::NEVER,
+			CompareFunc::Less => gl::LESS,
+			CompareFunc::Equal => gl::EQUAL,
+			CompareFunc::LessEqual => gl::LEQUAL,
+			CompareFunc::Greater => gl::GREATER,
+			CompareFunc::NotEqual => gl::NOTEQUAL,
+			CompareFunc::GreaterEqual => gl::GEQUAL,
+			CompareFunc::Always => gl::ALWAYS,
+		}
+	}
+}
+
+/// Sampling state, independent of any particular texture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerParams {
+	pub min_filter: Filter,
+	pub mag_filter: Filter,
+	/// Filter used between mip levels, or `None` to only sample the base level.
+	pub mip_filter: Option<Filter>,
+	pub wrap_s: Wrap,
+	pub wrap_t: Wrap,
+	pub wrap_r: Wrap,
+	pub lod_bias: f32,
+	/// Clamped to what the driver supports; 1.0 disables anisotropic filtering.
+	pub max_anisotropy: f32,
+	pub border_color: [f32; 4],
+	/// Turns the sampler into a depth-compare (shadow) sampler.
+	pub compare: Option<CompareFunc>,
+}
+
+impl Default for SamplerParams {
+	fn default() -> SamplerParams {
+		SamplerParams {
+			min_filter: Filter::Linear,
+			mag_filter: Filter::Linear,
+			mip_filter: None,
+			wrap_s: Wrap::ClampToEdge,
+			wrap_t: Wrap::ClampToEdge,
+			wrap_r: Wrap::ClampToEdge,
+			lod_bias: 0.0,
+			max_anisotropy: 1.0,
+			border_color: [0.0, 0.0, 0.0, 0.0],
+			compare: None,
+		}
+	}
+}
+
+impl SamplerParams {
+	pub fn clamp() -> SamplerParams {
+		SamplerParams {
+			mip_filter: Some(Filter::Linear),
+			..Default::default()
+		}
+	}
+
+	/// Depth-compare sampler for `sampler2DShadow` lookups.
+	pub fn shadow() -> SamplerParams {
+		SamplerParams {
+			wrap_s: Wrap::ClampToBorder,
+			wrap_t: Wrap::ClampToBorder,
+			border_color: [1.0, 1.0, 1.0, 1.0],
+			compare: Some(CompareFunc::LessEqual),
+			..Default::default()
+		}
+	}
+
+	fn min_filter_enum(&self) -> GLenum {
+		match (self.min_filter, self.mip_filter) {
+			(Filter::Nearest, None) => gl::NEAREST,
+			(Filter::Linear, None) => gl::LINEAR,
+			(Filter::Nearest, Some(Filter::Nearest)) => gl::NEAREST_MIPMAP_NEAREST,
+			(Filter::Nearest, Some(Filter::Linear)) => gl::NEAREST_MIPMAP_LINEAR,
+			(Filter::Linear, Some(Filter::Nearest)) => gl::LINEAR_MIPMAP_NEAREST,
+			(Filter::Linear, Some(Filter::Linear)) => gl::LINEAR_MIPMAP_LINEAR,
+		}
+	}
+
+	fn mag_filter_enum(&self) -> GLenum {
+		match self.mag_filter {
+			Filter::Nearest => gl::NEAREST,
+			Filter::Linear => gl::LINEAR,
+		}
+	}
+}
+
+pub struct Sampler {
+	pub id: GLuint,
+	pub params: SamplerParams,
+}
+
+impl Sampler {
+	pub fn new(params: SamplerParams) -> Sampler {
+		let mut sampler = Sampler { id: 0, params };
+
+		unsafe {
+			gl::GenSamplers(1, &mut sampler.id);
+		};
+
+		sampler.apply();
+
+		sampler
+	}
+
+	fn apply(&self) {
+		let params = &self.params;
+
+		unsafe {
+			gl::SamplerParameteri(self.id, gl::TEXTURE_MIN_FILTER, params.min_filter_enum() as i32);
+			gl::SamplerParameteri(self.id, gl::TEXTURE_MAG_FILTER, params.mag_filter_enum() as i32);
+
+			gl::SamplerParameteri(self.id, gl::TEXTURE_WRAP_S, params.wrap_s.gl_enum() as i32);
+			gl::SamplerParameteri(self.id, gl::TEXTURE_WRAP_T, params.wrap_t.gl_enum() as i32);
+			gl::SamplerParameteri(self.id, gl::TEXTURE_WRAP_R, params.wrap_r.gl_enum() as i32);
+
+			gl::SamplerParameterf(self.id, gl::TEXTURE_LOD_BIAS, params.lod_bias);
+			gl::SamplerParameterfv(self.id, gl::TEXTURE_BORDER_COLOR, params.border_color.as_ptr());
+
+			if let Some(max_supported) = caps().max_anisotropy {
+				let anisotropy = params.max_anisotropy.max(1.0).min(max_supported);
+				gl::SamplerParameterf(self.id, TEXTURE_MAX_ANISOTROPY, anisotropy);
+			};
+
+			match params.compare {
+				Some(func) => {
+					gl::SamplerParameteri(self.id, gl::TEXTURE_COMPARE_MODE, gl::COMPARE_REF_TO_TEXTURE as i32);
+					gl::SamplerParameteri(self.id, gl::TEXTURE_COMPARE_FUNC, func.gl_enum() as i32);
+				},
+				None => {
+					gl::SamplerParameteri(self.id, gl::TEXTURE_COMPARE_MODE, gl::NONE as i32);
+				},
+			};
+		};
+	}
+
+	pub fn bind(&self, tex_unit: GLuint) {
+		unsafe {
+			gl::BindSampler(tex_unit, self.id);
+		};
+	}
+
+	/// Leaves `tex_unit` sampling with the state of whatever texture is bound to it.
+	pub fn unbind(tex_unit: GLuint) {
+		unsafe {
+			gl::BindSampler(tex_unit, 0);
+		};
+	}
+}
+
+impl Drop for Sampler {
+	fn drop(&mut self) {
+		unsafe {
+			gl::DeleteSamplers(1, &self.id);
+		}
+	}
+}
@@ -0,0 +1,405 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use gl;
+use gl::types::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShaderStage {
+	Vertex,
+	TessControl,
+	TessEvaluation,
+	Geometry,
+	Fragment,
+	Compute,
+}
+
+impl ShaderStage {
+	pub fn gl_enum(self) -> GLenum {
+		match self {
+			ShaderStage::Vertex => gl::VERTEX_SHADER,
+			ShaderStage::TessControl => gl::TESS_CONTROL_SHADER,
+			ShaderStage::TessEvaluation => gl::TESS_EVALUATION_SHADER,
+			ShaderStage::Geometry => gl::GEOMETRY_SHADER,
+			ShaderStage::Fragment => gl::FRAGMENT_SHADER,
+			ShaderStage::Compute => gl::COMPUTE_SHADER,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			ShaderStage::Vertex => "vertex",
+			ShaderStage::TessControl => "tessellation control",
+			ShaderStage::TessEvaluation => "tessellation evaluation",
+			ShaderStage::Geometry => "geometry",
+			ShaderStage::Fragment => "fragment",
+			ShaderStage::Compute => "compute",
+		}
+	}
+}
+
+/// A file that contributed lines to a shader source.
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+	pub path: Option<PathBuf>,
+	pub text: String,
+}
+
+/// Maps each line of the text handed to the driver back to the file and line it came from.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+	pub files: Vec<SourceFile>,
+	/// Per output line: index into `files`, and the 1-based line within that file.
+	/// `None` for lines that were generated rather than read from a file.
+	pub lines: Vec<Option<(usize, u32)>>,
+}
+
+impl SourceMap {
+	/// Where 1-based output `line` came from.
+	pub fn lookup(&self, line: u32) -> Option<(&SourceFile, u32)> {
+		if line == 0 {
+			return None;
+		}
+
+		self.lines.get(line as usize - 1).
+			and_then(|entry| *entry).
+			map(|(file, line)| (&self.files[file], line))
+	}
+}
+
+/// GLSL text ready to compile, along with where its lines came from.
+#[derive(Clone, Debug)]
+pub struct ShaderSource {
+	pub text: String,
+	pub map: SourceMap,
+}
+
+impl ShaderSource {
+	/// Unprocessed text, for tests that don't need files on disk.
+	#[cfg(test)]
+	pub fn new(path: Option<&Path>, text: &str) -> ShaderSource {
+		let lines = (0..text.lines().count()).
+			map(|line| Some((0, line as u32 + 1))).
+			collect();
+
+		ShaderSource {
+			text: String::from(text),
+			map: SourceMap {
+				files: vec![SourceFile { path: path.map(Path::to_path_buf), text: String::from(text) }],
+				lines,
+			},
+		}
+	}
+
+	/// The main file, when the source was loaded from disk.
+	pub fn path(&self) -> Option<&Path> {
+		self.map.files.first().and_then(|file| file.path.as_deref())
+	}
+
+	/// The main file and everything it included.
+	pub fn paths(&self) -> Vec<&Path> {
+		self.map.files.iter().
+			filter_map(|file| file.path.as_deref()).
+			collect()
+	}
+}
+
+pub fn read_file(path: &Path) -> io::Result<String> {
+	let mut text = String::new();
+	File::open(path)?.read_to_string(&mut text)?;
+
+	Ok(text)
+}
+
+#[derive(Debug)]
+pub enum ShaderErrorKind {
+	Io(io::Error),
+	Preprocess,
+	Compile,
+	Link,
+}
+
+/// One message from the driver's info log, mapped back to the original source.
+#[derive(Clone, Debug)]
+pub struct LogEntry {
+	/// "error" or "warning", as the driver spelled it.
+	pub severity: String,
+	pub path: Option<PathBuf>,
+	/// 1-based line in `path`.
+	pub line: Option<u32>,
+	pub column: Option<u32>,
+	pub message: String,
+	/// The offending line with one line of context each side, as (line number, text).
+	pub snippet: Vec<(u32, String)>,
+}
+
+#[derive(Debug)]
+pub struct ShaderError {
+	pub kind: ShaderErrorKind,
+	/// Unset for link errors, which belong to the whole program.
+	pub stage: Option<ShaderStage>,
+	pub path: Option<PathBuf>,
+	pub log: String,
+	pub entries: Vec<LogEntry>,
+}
+
+impl ShaderError {
+	pub fn io(path: &Path, err: io::Error) -> ShaderError {
+		ShaderError {
+			kind: ShaderErrorKind::Io(err),
+			stage: None,
+			path: Some(path.to_path_buf()),
+			log: String::new(),
+			entries: Vec::new(),
+		}
+	}
+
+	/// An error in a preprocessor directive at `line` of `file`.
+	pub fn preprocess(file: &SourceFile, line: u32, message: &str) -> ShaderError {
+		ShaderError {
+			kind: ShaderErrorKind::Preprocess,
+			stage: None,
+			path: file.path.clone(),
+			log: String::from(message),
+			entries: vec![LogEntry {
+				severity: String::from("error"),
+				path: file.path.clone(),
+				line: Some(line),
+				column: None,
+				message: String::from(message),
+				snippet: snippet(file, line),
+			}],
+		}
+	}
+
+	pub fn compile(stage: ShaderStage, source: &ShaderSource, log: &str) -> ShaderError {
+		ShaderError {
+			kind: ShaderErrorKind::Compile,
+			stage: Some(stage),
+			path: source.path().map(Path::to_path_buf),
+			log: String::from(log),
+			entries: parse_log(log, Some(&source.map)),
+		}
+	}
+
+	pub fn link(log: &str) -> ShaderError {
+		ShaderError {
+			kind: ShaderErrorKind::Link,
+			stage: None,
+			path: None,
+			log: String::from(log),
+			entries: parse_log(log, None),
+		}
+	}
+}
+
+impl fmt::Display for ShaderError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let path = self.path.as_ref().
+			map(|path| path.display().to_string()).
+			unwrap_or_else(|| String::from("<inline>"));
+
+		match self.kind {
+			ShaderErrorKind::Io(ref err) => return write!(f, "{}: {}", path, err),
+			ShaderErrorKind::Preprocess => writeln!(f, "failed to preprocess {}", path)?,
+			ShaderErrorKind::Compile => {
+				let stage = self.stage.map(ShaderStage::name).unwrap_or("unknown");
+				writeln!(f, "{} shader {} failed to compile", stage, path)?;
+			},
+			ShaderErrorKind::Link => writeln!(f, "program failed to link")?,
+		};
+
+		if self.entries.is_empty() {
+			return write!(f, "{}", self.log);
+		}
+
+		for entry in &self.entries {
+			let location = match (&entry.path, entry.line, entry.column) {
+				(Some(path), Some(line), Some(column)) => format!("{}:{}:{}: ", path.display(), line, column),
+				(Some(path), Some(line), None) => format!("{}:{}: ", path.display(), line),
+				(&None, Some(line), _) => format!("line {}: ", line),
+				_ => String::new(),
+			};
+			writeln!(f, "{}{}: {}", location, entry.severity, entry.message)?;
+
+			for &(line, ref text) in &entry.snippet {
+				let marker = if Some(line) == entry.line { ">" } else { " " };
+				writeln!(f, "{} {:5} | {}", marker, line, text)?;
+			};
+		};
+
+		Ok(())
+	}
+}
+
+impl Error for ShaderError {}
+
+fn parse_number(text: &str) -> Option<(u32, &str)> {
+	let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+	if end == 0 {
+		return None;
+	}
+
+	text[..end].parse().ok().map(|number| (number, &text[end..]))
+}
+
+/// Parses the location prefix drivers put on log lines, returning (line, column, rest):
+///
+/// * Mesa: `0:12(5): error: ...`
+/// * NVIDIA: `0(12) : error C0000: ...`
+/// * AMD, Intel and Apple: `ERROR: 0:12: ...`
+fn parse_location(text: &str) -> Option<(u32, Option<u32>, &str)> {
+	let (_source_string, rest) = parse_number(text)?;
+
+	if let Some(rest) = rest.strip_prefix(':') {
+		let (line, rest) = parse_number(rest)?;
+
+		let (column, rest) = match rest.strip_prefix('(') {
+			Some(rest) => {
+				let (column, rest) = parse_number(rest)?;
+				(Some(column), rest.trim_start_matches(')'))
+			},
+			None => (None, rest),
+		};
+
+		Some((line, column, rest.trim_start_matches([':', ' '])))
+	} else if let Some(rest) = rest.strip_prefix('(') {
+		let (line, rest) = parse_number(rest)?;
+		let rest = rest.trim_start_matches(')').trim_start_matches([':', ' ']);
+
+		Some((line, None, rest))
+	} else {
+		None
+	}
+}
+
+fn parse_log_line(text: &str) -> (String, Option<u32>, Option<u32>, String) {
+	let mut severity = String::from("error");
+	let mut rest = text.trim();
+
+	for &prefix in &["ERROR:", "WARNING:"] {
+		if rest.starts_with(prefix) {
+			severity = prefix.trim_end_matches(':').to_lowercase();
+			rest = rest[prefix.len()..].trim_start();
+		}
+	};
+
+	let (line, column, rest) = match parse_location(rest) {
+		Some((line, column, rest)) => (Some(line), column, rest),
+		None => (None, None, rest),
+	};
+
+	let lower = rest.to_lowercase();
+	let mut message = rest;
+	for &word in &["error", "warning"] {
+		if lower.starts_with(word) {
+			severity = String::from(word);
+			message = rest[word.len()..].trim_start_matches([':', ' ']);
+		}
+	};
+
+	(severity, line, column, String::from(message))
+}
+
+fn snippet(file: &SourceFile, line: u32) -> Vec<(u32, String)> {
+	let first = if line > 1 { line - 1 } else { 1 };
+
+	file.text.lines().
+		enumerate().
+		map(|(i, text)| (i as u32 + 1, text)).
+		skip(first as usize - 1).
+		take((line - first + 2) as usize).
+		map(|(i, text)| (i, String::from(text))).
+		collect()
+}
+
+/// Splits a driver info log into entries. With a source map, line numbers are translated
+/// from the compiled text back to the file they were written in.
+pub fn parse_log(log: &str, map: Option<&SourceMap>) -> Vec<LogEntry> {
+	log.lines().
+		filter(|line| !line.trim().is_empty()).
+		map(|text| {
+			let (severity, line, column, message) = parse_log_line(text);
+
+			let mapped = match (map, line) {
+				(Some(map), Some(line)) => map.lookup(line),
+				_ => None,
+			};
+
+			match mapped {
+				Some((file, orig_line)) => LogEntry {
+					severity,
+					path: file.path.clone(),
+					line: Some(orig_line),
+					column,
+					message,
+					snippet: snippet(file, orig_line),
+				},
+				None => LogEntry {
+					severity,
+					path: None,
+					line,
+					column,
+					message,
+					snippet: Vec::new(),
+				},
+			}
+		}).
+		collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::{Path, PathBuf};
+	use super::*;
+
+	#[test]
+	fn parses_driver_locations() {
+		assert_eq!(parse_location("0:12(5): error: syntax error"), Some((12, Some(5), "error: syntax error")));
+		assert_eq!(parse_location("0(12) : error C0000: syntax error"), Some((12, None, "error C0000: syntax error")));
+		assert_eq!(parse_location("0:12: 'x' : undeclared identifier"), Some((12, None, "'x' : undeclared identifier")));
+		assert_eq!(parse_location("error: no location"), None);
+	}
+
+	#[test]
+	fn parses_severities() {
+		let entries = parse_log("0:3(1): warning: unused variable\n\n\
+								 ERROR: 0:4: 'x' : undeclared identifier\n\
+								 0(5) : error C1008: undefined variable\n\
+								 link failed\n", None);
+
+		let summary = entries.iter().
+			map(|entry| (entry.severity.as_str(), entry.line, entry.column, entry.message.as_str())).
+			collect::<Vec<_>>();
+		assert_eq!(summary, vec![
+			("warning", Some(3), Some(1), "unused variable"),
+			("error", Some(4), None, "'x' : undeclared identifier"),
+			("error", Some(5), None, "C1008: undefined variable"),
+			("error", None, None, "link failed"),
+		]);
+	}
+
+	#[test]
+	fn maps_lines_back_to_their_files() {
+		let main = SourceFile { path: Some(PathBuf::from("main.vert")), text: String::from("a\nb\n") };
+		let common = SourceFile { path: Some(PathBuf::from("common.glsl")), text: String::from("x\ny\nz\n") };
+		let map = SourceMap {
+			files: vec![main, common],
+			lines: vec![Some((0, 1)), None, Some((1, 2)), Some((0, 2))],
+		};
+
+		let entries = parse_log("0:3(7): error: bad\n0:2(1): error: generated\n", Some(&map));
+
+		assert_eq!(entries[0].path.as_deref(), Some(Path::new("common.glsl")));
+		assert_eq!(entries[0].line, Some(2));
+		assert_eq!(entries[0].column, Some(7));
+		assert_eq!(entries[0].snippet, vec![(1, String::from("x")), (2, String::from("y")), (3, String::from("z"))]);
+
+		// Generated lines keep the driver's numbering
+		assert_eq!(entries[1].path, None);
+		assert_eq!(entries[1].line, Some(2));
+		assert!(entries[1].snippet.is_empty());
+	}
+}
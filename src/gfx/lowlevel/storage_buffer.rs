@@ -0,0 +1,182 @@
+use std::marker::PhantomData;
+use std::ops::{BitOr, Deref, DerefMut};
+use std::ptr;
+use std::slice;
+use gl;
+use gl::types::*;
+use gfx::lowlevel::buffer::{Buffer, BufferUsage};
+
+/// Which kinds of access must see shader writes made before a `memory_barrier`. Combine with `|`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Barriers(pub GLbitfield);
+
+impl Barriers {
+	/// Later shader reads and writes of storage buffers.
+	pub const STORAGE: Barriers = Barriers(gl::SHADER_STORAGE_BARRIER_BIT);
+	/// Reading back or updating the buffer from the CPU, mapped or not.
+	pub const BUFFER_UPDATE: Barriers = Barriers(gl::BUFFER_UPDATE_BARRIER_BIT);
+}
+
+impl BitOr for Barriers {
+	type Output = Barriers;
+
+	fn bitor(self, other: Barriers) -> Barriers {
+		Barriers(self.0 | other.0)
+	}
+}
+
+/// Orders incoherent shader writes (storage buffers, images) before the accesses in `barriers`.
+pub fn memory_barrier(barriers: Barriers) {
+	unsafe {
+		gl::MemoryBarrier(barriers.0);
+	};
+}
+
+/// A shader storage buffer holding `len` elements of `T`.
+///
+/// `T` must have the layout the shader declares for the buffer's elements, normally std430;
+/// note that a `vec3` there still takes 16 bytes, so pad Rust structs to match.
+pub struct StorageBuffer<T: Copy> {
+	buffer: Buffer,
+	len: usize,
+	_element: PhantomData<T>,
+}
+
+impl<T: Copy> StorageBuffer<T> {
+	pub fn new(data: &[T]) -> StorageBuffer<T> {
+		StorageBuffer {
+			buffer: Buffer::new(gl::SHADER_STORAGE_BUFFER, data, BufferUsage::GpuWritten),
+			len: data.len(),
+			_element: PhantomData,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Attaches the buffer to `layout(binding = ...)` point `binding`.
+	pub fn bind(&self, binding: GLuint) {
+		self.buffer.bind_base(binding);
+	}
+
+	/// Overwrites elements starting at `offset`.
+	pub fn write(&self, offset: usize, data: &[T]) {
+		assert!(offset + data.len() <= self.len, "write past the end of a storage buffer");
+		self.buffer.update(offset, data);
+	}
+
+	/// Copies the contents back, after a barrier so earlier shader writes are included.
+	pub fn read(&mut self) -> Vec<T> {
+		memory_barrier(Barriers::BUFFER_UPDATE);
+		self.map().to_vec()
+	}
+
+	/// Maps the buffer for reading and writing; it's unmapped when the returned guard is
+	/// dropped. Taking `self` mutably keeps a second mapping, which GL would refuse, from being
+	/// made while the first is alive.
+	pub fn map(&mut self) -> Mapped<'_, T> {
+		let data = if self.len == 0 {
+			ptr::NonNull::dangling().as_ptr()
+		} else {
+			let data = self.buffer.map(gl::MAP_READ_BIT | gl::MAP_WRITE_BIT);
+			assert!(!data.is_null(), "failed to map storage buffer {}", self.buffer.id());
+			data as *mut T
+		};
+
+		Mapped { buffer: self, data }
+	}
+}
+
+pub struct Mapped<'a, T: Copy + 'a> {
+	buffer: &'a mut StorageBuffer<T>,
+	data: *mut T,
+}
+
+impl<'a, T: Copy> Deref for Mapped<'a, T> {
+	type Target = [T];
+
+	fn deref(&self) -> &[T] {
+		unsafe { slice::from_raw_parts(self.data, self.buffer.len) }
+	}
+}
+
+impl<'a, T: Copy> DerefMut for Mapped<'a, T> {
+	fn deref_mut(&mut self) -> &mut [T] {
+		unsafe { slice::from_raw_parts_mut(self.data, self.buffer.len) }
+	}
+}
+
+impl<'a, T: Copy> Drop for Mapped<'a, T> {
+	fn drop(&mut self) {
+		self.buffer.buffer.unmap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use gfx::lowlevel::program::Program;
+	use gfx::lowlevel::shader::{ShaderSource, ShaderStage};
+	use gfx::lowlevel::test_context;
+	use super::*;
+
+	const DOUBLE: &str = "
+		#version 430
+		layout(local_size_x = 4) in;
+		layout(std430, binding = 3) buffer Values { uint values[]; };
+
+		void main() {
+			uint i = gl_GlobalInvocationID.x;
+			if (i < uint(values.length())) {
+				values[i] = values[i] * 2u + i;
+			}
+		}
+	";
+
+	#[test]
+	fn compute_writes_are_read_back() {
+		test_context::make_current();
+
+		let source = ShaderSource::new(None, DOUBLE);
+		let program = Program::from_stages_cached(&[(ShaderStage::Compute, &source)], None).unwrap();
+		assert_eq!(program.work_group_size(), [4, 1, 1]);
+
+		let mut buffer = StorageBuffer::new(&[1u32, 2, 3, 4, 5, 6]);
+		buffer.bind(3);
+		program.dispatch_1d(buffer.len());
+
+		assert_eq!(buffer.read(), [2, 5, 8, 11, 14, 17]);
+	}
+
+	#[test]
+	fn writes_and_mapping() {
+		test_context::make_current();
+
+		let mut buffer = StorageBuffer::new(&[0u32; 4]);
+		buffer.write(1, &[7, 8]);
+
+		{
+			let mut mapped = buffer.map();
+			assert_eq!(*mapped, [0, 7, 8, 0]);
+			mapped[3] = 9;
+		}
+
+		assert_eq!(buffer.read(), [0, 7, 8, 9]);
+	}
+
+	#[test]
+	fn empty() {
+		test_context::make_current();
+
+		let mut buffer = StorageBuffer::<u32>::new(&[]);
+		assert_eq!(buffer.read(), []);
+	}
+
+	#[test]
+	#[should_panic(expected = "write past the end")]
+	fn write_past_the_end() {
+		test_context::make_current();
+
+		StorageBuffer::new(&[0u32; 2]).write(1, &[1, 2]);
+	}
+}
@@ -0,0 +1,75 @@
+// A headless GL context for tests, made through EGL without a window or surface. Mesa's
+// llvmpipe provides one wherever Mesa is installed, GPU or not.
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::Once;
+use gl;
+
+type EGLDisplay = *mut c_void;
+type EGLContext = *mut c_void;
+type EGLBoolean = u32;
+
+const EGL_PLATFORM_SURFACELESS_MESA: u32 = 0x31DD;
+const EGL_OPENGL_API: u32 = 0x30A2;
+const EGL_CONTEXT_MAJOR_VERSION: i32 = 0x3098;
+const EGL_CONTEXT_MINOR_VERSION: i32 = 0x30FB;
+const EGL_CONTEXT_OPENGL_PROFILE_MASK: i32 = 0x30FD;
+const EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT: i32 = 1;
+const EGL_NONE: i32 = 0x3038;
+
+#[link(name = "EGL")]
+extern "C" {
+	fn eglGetPlatformDisplay(platform: u32, native_display: *mut c_void, attribs: *const isize) -> EGLDisplay;
+	fn eglInitialize(display: EGLDisplay, major: *mut i32, minor: *mut i32) -> EGLBoolean;
+	fn eglBindAPI(api: u32) -> EGLBoolean;
+	fn eglCreateContext(display: EGLDisplay, config: *mut c_void, share: EGLContext, attribs: *const i32)
+		-> EGLContext;
+	fn eglMakeCurrent(display: EGLDisplay, draw: *mut c_void, read: *mut c_void, context: EGLContext)
+		-> EGLBoolean;
+	fn eglGetProcAddress(name: *const c_char) -> *const c_void;
+}
+
+static LOAD: Once = Once::new();
+
+thread_local! {
+	static CURRENT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes a GL 4.5 core context current on the calling thread, once per thread, and loads the
+/// function pointers. Panics if there's no EGL driver to make one with.
+pub fn make_current() {
+	if CURRENT.with(Cell::get) {
+		return;
+	}
+
+	unsafe {
+		let display = eglGetPlatformDisplay(EGL_PLATFORM_SURFACELESS_MESA, ptr::null_mut(), ptr::null());
+		assert!(!display.is_null(), "no surfaceless EGL display");
+		assert!(eglInitialize(display, ptr::null_mut(), ptr::null_mut()) != 0, "failed to initialize EGL");
+		assert!(eglBindAPI(EGL_OPENGL_API) != 0, "EGL has no desktop GL");
+
+		let attribs = [
+			EGL_CONTEXT_MAJOR_VERSION, 4,
+			EGL_CONTEXT_MINOR_VERSION, 5,
+			EGL_CONTEXT_OPENGL_PROFILE_MASK, EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT,
+			EGL_NONE,
+		];
+		let context = eglCreateContext(display, ptr::null_mut(), ptr::null_mut(), attribs.as_ptr());
+		assert!(!context.is_null(), "failed to create a GL 4.5 core context");
+		assert!(eglMakeCurrent(display, ptr::null_mut(), ptr::null_mut(), context) != 0,
+				"failed to make the GL context current");
+	};
+
+	// The pointers are the same for every context of the driver
+	LOAD.call_once(|| {
+		gl::load_with(|symbol| {
+			let symbol = CString::new(symbol).unwrap();
+			unsafe { eglGetProcAddress(symbol.as_ptr()) }
+		});
+	});
+
+	CURRENT.with(|current| current.set(true));
+}
@@ -1,61 +1,425 @@
-use std::mem;
 use std::ptr;
-use std::str;
-use std::ffi::CString;
 use gl;
 use gl::types::*;
-use gfx::image::Image;
+use gfx::image::{Image, CubeFaces};
+use gfx::lowlevel::accounting::{self, Category};
+use gfx::lowlevel::caps::caps;
+use gfx::lowlevel::debug::label_object;
+
+/// Sized formats for textures that are rendered to rather than loaded from images.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureFormat {
+	Rgba16F,
+	Depth24Stencil8,
+}
+
+impl TextureFormat {
+	pub fn internal_format(self) -> GLenum {
+		match self {
+			TextureFormat::Rgba16F => gl::RGBA16F,
+			TextureFormat::Depth24Stencil8 => gl::DEPTH24_STENCIL8,
+		}
+	}
+
+	pub fn bytes_per_pixel(self) -> u32 {
+		match self {
+			TextureFormat::Rgba16F => 8,
+			TextureFormat::Depth24Stencil8 => 4,
+		}
+	}
+
+	pub fn is_depth(self) -> bool {
+		self == TextureFormat::Depth24Stencil8
+	}
+
+	pub fn has_stencil(self) -> bool {
+		self == TextureFormat::Depth24Stencil8
+	}
+
+	// The pixel format and type `glTexImage*` wants alongside the internal format, even
+	// without data to upload
+	fn pixel_format(self) -> (GLenum, GLenum) {
+		match self {
+			TextureFormat::Rgba16F => (gl::RGBA, gl::FLOAT),
+			TextureFormat::Depth24Stencil8 => (gl::DEPTH_STENCIL, gl::UNSIGNED_INT_24_8),
+		}
+	}
+}
 
 pub struct Texture {
     pub name: String,
 	pub id: GLuint,
-	pub tex_unit: GLuint,
+	pub target: GLenum,
+	pub width: u32,
+	pub height: u32,
+	/// Layer count for arrays, slice count for 3D textures, otherwise 1.
+	pub depth: u32,
+	/// Estimated GPU memory, including the mip chain.
+	pub byte_size: usize,
+}
+
+// A full mip chain adds roughly a third on top of the base level
+fn with_mips(base_size: usize) -> usize {
+	base_size + base_size / 3
 }
 
-static mut TEX_UNITS_USED: GLuint = 0;
+// The binding query for each target the textures are created with
+fn binding_of(target: GLenum) -> GLenum {
+	match target {
+		gl::TEXTURE_2D => gl::TEXTURE_BINDING_2D,
+		gl::TEXTURE_2D_MULTISAMPLE => gl::TEXTURE_BINDING_2D_MULTISAMPLE,
+		gl::TEXTURE_CUBE_MAP => gl::TEXTURE_BINDING_CUBE_MAP,
+		gl::TEXTURE_2D_ARRAY => gl::TEXTURE_BINDING_2D_ARRAY,
+		gl::TEXTURE_3D => gl::TEXTURE_BINDING_3D,
+		_ => panic!("Unsupported texture target {:#x}", target),
+	}
+}
+
+// Image data is 8 bits per channel, RGB or RGBA
+fn sized_format(color_type: GLenum) -> GLenum {
+	match color_type {
+		gl::RGBA => gl::RGBA8,
+		_ => gl::RGB8,
+	}
+}
 
 impl Texture {
+	/// Creates the GL object, which has no storage yet.
+	fn create(name: &str, target: GLenum, width: u32, height: u32, depth: u32, byte_size: usize) -> Texture {
+		let mut tex = Texture { name: String::from(name), id: 0, target, width, height, depth, byte_size };
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::CreateTextures(target, 1, &mut tex.id);
+			} else {
+				// The name only becomes a texture once bound
+				gl::GenTextures(1, &mut tex.id);
+				tex.edit(|| {});
+			}
+		};
+
+		accounting::track(Category::Texture, tex.id, name, byte_size);
+		label_object(gl::TEXTURE, tex.id, name);
+		tex
+	}
+
+	/// Pre-4.5 path: binds the texture to the active unit for `edit`, then puts back whatever
+	/// was bound there before.
+	fn edit<F: FnOnce()>(&self, edit: F) {
+		let mut previous = 0;
+
+		unsafe {
+			gl::GetIntegerv(binding_of(self.target), &mut previous);
+			gl::BindTexture(self.target, self.id);
+			edit();
+			gl::BindTexture(self.target, previous as GLuint);
+		};
+	}
+
+	fn parameter(&self, pname: GLenum, value: GLenum) {
+		if caps().direct_state_access {
+			unsafe { gl::TextureParameteri(self.id, pname, value as GLint) };
+		} else {
+			self.edit(|| unsafe { gl::TexParameteri(self.target, pname, value as GLint) });
+		}
+	}
+
+	// Defaults for when no sampler is bound alongside the texture
+	fn default_parameters(&self) {
+		self.parameter(gl::TEXTURE_MIN_FILTER, gl::LINEAR);
+		self.parameter(gl::TEXTURE_MAG_FILTER, gl::LINEAR);
+
+		self.parameter(gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE);
+		self.parameter(gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE);
+		if self.target != gl::TEXTURE_2D {
+			self.parameter(gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE);
+		}
+	}
+
 	pub fn new(name: &str, image: &Image) -> Texture {
-		let tex_unit = Texture::allocate_tex_unit();
-		let mut tex = Texture { name: String::from(name), id: 0, tex_unit };
-		let slice = &image.data[0..];
+		let tex = Texture::create(name, gl::TEXTURE_2D, image.width, image.height, 1,
+		                          with_mips(image.data.len()));
 		
 		unsafe {
-			gl::GenTextures(1, &mut tex.id);
-			tex.bind();
-			
-			gl::TexImage2D(gl::TEXTURE_2D, 0, image.color_type as i32, image.width as i32, image.height as i32, 0, image.color_type, gl::UNSIGNED_BYTE, mem::transmute(&slice[0]));
-			
-			gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
-			gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
-			
-			gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
-			gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+			if caps().direct_state_access {
+				gl::TextureStorage2D(tex.id, tex.mip_levels() as i32, sized_format(image.color_type),
+				                     image.width as i32, image.height as i32);
+				gl::TextureSubImage2D(tex.id, 0, 0, 0, image.width as i32, image.height as i32,
+				                      image.color_type, gl::UNSIGNED_BYTE, image.data.as_ptr() as *const _);
+			} else {
+				tex.edit(|| {
+					gl::TexImage2D(gl::TEXTURE_2D, 0, image.color_type as i32, image.width as i32, image.height as i32, 0, image.color_type, gl::UNSIGNED_BYTE, image.data.as_ptr() as *const _);
+				});
+			}
 		};
+		tex.generate_mipmaps();
+		tex.default_parameters();
 		
 		tex
 	}
 
-    fn allocate_tex_unit() -> GLuint {
+	/// Creates an uninitialized texture to render into. With `samples` above 1 the texture is
+	/// multisampled and can't be sampled normally; resolve it into a single-sampled one first.
+	pub fn new_empty(name: &str, format: TextureFormat, width: u32, height: u32, samples: u32) -> Texture {
+		let target = if samples > 1 { gl::TEXTURE_2D_MULTISAMPLE } else { gl::TEXTURE_2D };
+		let byte_size = (format.bytes_per_pixel() * width * height * samples.max(1)) as usize;
+		let tex = Texture::create(name, target, width, height, 1, byte_size);
+
+		let caps = caps();
+		let (pixel_format, pixel_type) = format.pixel_format();
+
+		unsafe {
+			match (caps.direct_state_access, samples > 1) {
+				(true, true) => gl::TextureStorage2DMultisample(tex.id, samples as i32, format.internal_format(),
+				                                                width as i32, height as i32, gl::TRUE),
+				(true, false) => gl::TextureStorage2D(tex.id, 1, format.internal_format(), width as i32, height as i32),
+				(false, true) => tex.edit(|| {
+					if caps.texture_storage_multisample {
+						gl::TexStorage2DMultisample(target, samples as i32, format.internal_format(),
+						                            width as i32, height as i32, gl::TRUE);
+					} else {
+						gl::TexImage2DMultisample(target, samples as i32, format.internal_format(),
+						                          width as i32, height as i32, gl::TRUE);
+					}
+				}),
+				(false, false) => tex.edit(|| {
+					if caps.texture_storage {
+						gl::TexStorage2D(target, 1, format.internal_format(), width as i32, height as i32);
+					} else {
+						gl::TexImage2D(target, 0, format.internal_format() as i32, width as i32, height as i32, 0,
+						               pixel_format, pixel_type, ptr::null());
+					}
+				}),
+			};
+		};
+
+		// Multisampled textures have no sampler state
+		if samples <= 1 {
+			tex.default_parameters();
+		}
+
+		tex
+	}
+
+	/// Creates a cube map from faces in GL order (+X, -X, +Y, -Y, +Z, -Z).
+	pub fn new_cube(name: &str, faces: &CubeFaces) -> Texture {
+		let tex = Texture::create(name, gl::TEXTURE_CUBE_MAP, faces[0].width, faces[0].height, 1,
+		                          faces.iter().map(|face| with_mips(face.data.len())).sum());
+
+		unsafe {
+			if caps().direct_state_access {
+				gl::TextureStorage2D(tex.id, tex.mip_levels() as i32, sized_format(faces[0].color_type),
+				                     tex.width as i32, tex.height as i32);
+
+				// Faces are the layers of a cube map
+				for (i, face) in faces.iter().enumerate() {
+					gl::TextureSubImage3D(tex.id, 0, 0, 0, i as i32, face.width as i32, face.height as i32, 1,
+					                      face.color_type, gl::UNSIGNED_BYTE, face.data.as_ptr() as *const _);
+				};
+			} else {
+				tex.edit(|| {
+					for (i, face) in faces.iter().enumerate() {
+						gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X + i as GLenum, 0, face.color_type as i32, face.width as i32, face.height as i32, 0, face.color_type, gl::UNSIGNED_BYTE, face.data.as_ptr() as *const _);
+					};
+				});
+			}
+		};
+		tex.generate_mipmaps();
+		tex.default_parameters();
+
+		tex
+	}
+
+	/// Creates a `TEXTURE_2D_ARRAY` with one layer per image.
+	pub fn new_array(name: &str, layers: &[Image]) -> Texture {
+		Texture::new_layered(name, gl::TEXTURE_2D_ARRAY, layers)
+	}
+
+	/// Creates a `TEXTURE_3D` from its slices, front to back. See `image::load_volume`.
+	pub fn new_3d(name: &str, slices: &[Image]) -> Texture {
+		Texture::new_layered(name, gl::TEXTURE_3D, slices)
+	}
+
+	fn new_layered(name: &str, target: GLenum, layers: &[Image]) -> Texture {
+		if layers.is_empty() {
+			panic!("Attempted to create texture {} with no layers", name);
+		}
+
+		let first = &layers[0];
+		if layers.iter().any(|layer| layer.width != first.width ||
+		                             layer.height != first.height ||
+		                             layer.color_type != first.color_type) {
+			panic!("Layers of texture {} differ in size or format", name);
+		}
+
+		let tex = Texture::create(name, target, first.width, first.height, layers.len() as u32,
+		                          with_mips(layers.iter().map(|layer| layer.data.len()).sum()));
+		let internal_format = sized_format(first.color_type);
+
+		let caps = caps();
+
+		unsafe {
+			if caps.direct_state_access {
+				gl::TextureStorage3D(tex.id, tex.mip_levels() as i32, internal_format,
+				                     tex.width as i32, tex.height as i32, tex.depth as i32);
+			} else if caps.texture_storage {
+				tex.edit(|| {
+					gl::TexStorage3D(target, tex.mip_levels() as i32, internal_format,
+					                 tex.width as i32, tex.height as i32, tex.depth as i32);
+				});
+			} else {
+				// Every level has to be specified for the texture to be complete; array layers
+				// don't shrink along with them
+				tex.edit(|| {
+					for level in 0..tex.mip_levels() {
+						let depth = if target == gl::TEXTURE_3D { (tex.depth >> level).max(1) } else { tex.depth };
+						gl::TexImage3D(target, level as i32, internal_format as i32,
+						               (tex.width >> level).max(1) as i32, (tex.height >> level).max(1) as i32,
+						               depth as i32, 0, first.color_type, gl::UNSIGNED_BYTE, ptr::null());
+					};
+				});
+			}
+		};
+		tex.default_parameters();
+
+		for (layer, image) in layers.iter().enumerate() {
+			tex.upload_layer(layer as u32, image);
+		};
+		tex.generate_mipmaps();
+
+		tex
+	}
+
+	fn mip_levels(&self) -> u32 {
+		let mut largest = self.width.max(self.height);
+		if self.target == gl::TEXTURE_3D {
+			largest = largest.max(self.depth);
+		}
+
+		32 - largest.leading_zeros()
+	}
+
+	/// Replaces one layer of an array texture, or one slice of a 3D texture.
+	// Nothing edits layers at runtime yet; asset reloads replace the whole texture
+	#[allow(dead_code)]
+	pub fn update_layer(&self, layer: u32, image: &Image) {
+		self.upload_layer(layer, image);
+		self.generate_mipmaps();
+	}
+
+	fn upload_layer(&self, layer: u32, image: &Image) {
+		if self.target != gl::TEXTURE_2D_ARRAY && self.target != gl::TEXTURE_3D {
+			panic!("Texture {} has no layers", self.name);
+		}
+
+		if layer >= self.depth || image.width != self.width || image.height != self.height {
+			panic!("Layer {} doesn't fit texture {}", layer, self.name);
+		}
+
 		unsafe {
-			let tex_unit = TEX_UNITS_USED;
-			TEX_UNITS_USED += 1;
-			tex_unit
+			if caps().direct_state_access {
+				gl::TextureSubImage3D(self.id, 0, 0, 0, layer as i32,
+				                      image.width as i32, image.height as i32, 1,
+				                      image.color_type, gl::UNSIGNED_BYTE, image.data.as_ptr() as *const _);
+			} else {
+				self.edit(|| {
+					gl::TexSubImage3D(self.target, 0, 0, 0, layer as i32,
+					                  image.width as i32, image.height as i32, 1,
+					                  image.color_type, gl::UNSIGNED_BYTE, image.data.as_ptr() as *const _);
+				});
+			}
+		};
+	}
+
+	fn generate_mipmaps(&self) {
+		if caps().direct_state_access {
+			unsafe { gl::GenerateTextureMipmap(self.id) };
+		} else {
+			self.edit(|| unsafe { gl::GenerateMipmap(self.target) });
 		}
-    }
-	
-	pub fn bind(&self) {
+	}
+
+	pub fn bind(&self, tex_unit: GLuint) {
 		unsafe {
-			gl::ActiveTexture(gl::TEXTURE0 + self.tex_unit);
-			gl::BindTexture(gl::TEXTURE_2D, self.id);
+			if caps().direct_state_access {
+				gl::BindTextureUnit(tex_unit, self.id);
+			} else {
+				gl::ActiveTexture(gl::TEXTURE0 + tex_unit);
+				gl::BindTexture(self.target, self.id);
+			}
 		};
 	}
 }
 
 impl Drop for Texture {
 	fn drop(&mut self) {
+		accounting::untrack(Category::Texture, self.id);
+
 		unsafe {
 			gl::DeleteTextures(1, &self.id);
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use gl;
+	use gfx::lowlevel::test_context;
+	use super::*;
+
+	fn bound_2d() -> GLuint {
+		let mut bound = 0;
+		unsafe { gl::GetIntegerv(gl::TEXTURE_BINDING_2D, &mut bound) };
+		bound as GLuint
+	}
+
+	#[test]
+	fn editing_restores_the_binding() {
+		test_context::make_current();
+
+		let bound = Texture::new_empty("bound", TextureFormat::Rgba16F, 4, 4, 1);
+		let edited = Texture::new_empty("edited", TextureFormat::Rgba16F, 4, 4, 1);
+		bound.bind(0);
+
+		edited.edit(|| assert_eq!(bound_2d(), edited.id));
+		assert_eq!(bound_2d(), bound.id);
+	}
+
+	fn read_layer(tex: &Texture, layer: u32) -> Vec<u8> {
+		let mut pixels = vec![0u8; (tex.width * tex.height * 4) as usize];
+
+		unsafe {
+			gl::GetTextureSubImage(tex.id, 0, 0, 0, layer as i32, tex.width as i32, tex.height as i32, 1,
+			                       gl::RGBA, gl::UNSIGNED_BYTE, pixels.len() as i32, pixels.as_mut_ptr() as *mut _);
+		};
+
+		pixels
+	}
+
+	#[test]
+	fn array_layers_read_back() {
+		test_context::make_current();
+
+		let red = [255, 0, 0, 255];
+		let green = [0, 255, 0, 255];
+		let blue = [0, 0, 255, 255];
+
+		let tex = Texture::new_array("layers", &[Image::solid(2, 2, red), Image::solid(2, 2, green)]);
+		assert_eq!((tex.target, tex.depth), (gl::TEXTURE_2D_ARRAY, 2));
+		assert_eq!(read_layer(&tex, 0), red.repeat(4));
+		assert_eq!(read_layer(&tex, 1), green.repeat(4));
+
+		tex.update_layer(1, &Image::solid(2, 2, blue));
+		assert_eq!(read_layer(&tex, 0), red.repeat(4));
+		assert_eq!(read_layer(&tex, 1), blue.repeat(4));
+	}
+
+	#[test]
+	#[should_panic(expected = "Layer 2 doesn't fit texture")]
+	fn layers_past_the_end_panic() {
+		test_context::make_current();
+
+		let tex = Texture::new_array("layers", &[Image::solid(2, 2, [0; 4])]);
+		tex.update_layer(2, &Image::solid(2, 2, [0; 4]));
+	}
+}
@@ -1,52 +1,210 @@
-use std::ptr;
-use std::str;
-use std::ffi::CString;
-use std::fs::File;
-use std::path::Path;
-use std::io::Read;
-use cgmath::{Matrix,Matrix4};
+use std::slice;
+use cgmath::{Matrix2, Matrix3, Matrix4, Point3, Quaternion, Vector2, Vector3, Vector4};
 use gfx::lowlevel::Program;
+use gfx::lowlevel::is_sampler_type;
 use gl;
 use gl::types::*;
 
 pub trait Uniformable {
-    fn bind(&self, name: &str, program: &Program);
-}
-	
-impl Uniformable for Matrix4<f32> {
+    /// Whether the value can be uploaded to a uniform of GLSL type `ty`.
+    fn accepts(&self, ty: GLenum) -> bool;
+
+    /// The Rust type, for mismatch warnings.
+    fn type_name(&self) -> &'static str;
+
+    /// Number of array elements the value fills.
+    fn count(&self) -> usize {
+        1
+    }
+
+    /// Uploads to `location` of `program`, or of the currently bound program when `None`.
+    fn upload(&self, program: Option<GLuint>, location: GLint);
+
     fn bind(&self, name: &str, program: &Program) {
-        unsafe {
-            let loc = gl::GetUniformLocation(program.id, CString::new(name).unwrap().as_ptr());
-            gl::UniformMatrix4fv(loc, 1, gl::FALSE, self.as_ptr());
-        };
+        program.set_uniform(name, self);
     }
 }
 
-impl Uniformable for i32 {
-    fn bind(&self, name: &str, program: &Program) {
-        unsafe {
-            let loc = gl::GetUniformLocation(program.id, CString::new(name).unwrap().as_ptr());
-            gl::Uniform1i(loc, *self);
-        };
+/// A value that fills one element of a uniform. Anything implementing it is `Uniformable` on
+/// its own, and as a slice, array or `Vec` for GLSL arrays.
+pub trait UniformElement: Sized {
+    fn accepts(ty: GLenum) -> bool;
+
+    fn type_name() -> &'static str;
+
+    /// Uploads `values` to consecutive elements starting at `location`, as `upload` does.
+    fn upload_slice(program: Option<GLuint>, location: GLint, values: &[Self]);
+}
+
+impl<T: UniformElement> Uniformable for T {
+    fn accepts(&self, ty: GLenum) -> bool {
+        T::accepts(ty)
+    }
+
+    fn type_name(&self) -> &'static str {
+        T::type_name()
+    }
+
+    fn upload(&self, program: Option<GLuint>, location: GLint) {
+        T::upload_slice(program, location, slice::from_ref(self));
     }
 }
 
-impl Uniformable for u32 {
-    fn bind(&self, name: &str, program: &Program) {
-        unsafe {
-            let loc = gl::GetUniformLocation(program.id, CString::new(name).unwrap().as_ptr());
-            gl::Uniform1ui(loc, *self);
+impl<T: UniformElement> Uniformable for [T] {
+    fn accepts(&self, ty: GLenum) -> bool {
+        T::accepts(ty)
+    }
+
+    fn type_name(&self) -> &'static str {
+        T::type_name()
+    }
+
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn upload(&self, program: Option<GLuint>, location: GLint) {
+        T::upload_slice(program, location, self);
+    }
+}
+
+impl<T: UniformElement, const N: usize> Uniformable for [T; N] {
+    fn accepts(&self, ty: GLenum) -> bool {
+        T::accepts(ty)
+    }
+
+    fn type_name(&self) -> &'static str {
+        T::type_name()
+    }
+
+    fn count(&self) -> usize {
+        N
+    }
+
+    fn upload(&self, program: Option<GLuint>, location: GLint) {
+        T::upload_slice(program, location, self);
+    }
+}
+
+impl<T: UniformElement> Uniformable for Vec<T> {
+    fn accepts(&self, ty: GLenum) -> bool {
+        T::accepts(ty)
+    }
+
+    fn type_name(&self) -> &'static str {
+        T::type_name()
+    }
+
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn upload(&self, program: Option<GLuint>, location: GLint) {
+        T::upload_slice(program, location, self);
+    }
+}
+
+// For types laid out as a plain run of `$scalar`s (cgmath's vectors, points and matrices are
+// all #[repr(C)], matrices column-major like GL's), so a slice can be handed to GL directly.
+// Takes the glUniform* function and its glProgramUniform* twin, then any arguments that go
+// between the count and the pointer.
+macro_rules! uniform_element {
+    ($ty:ty, $scalar:ty, [$($gl_ty:path),+], $uniform:ident, $program_uniform:ident $(, $arg:expr)*) => {
+        impl UniformElement for $ty {
+            fn accepts(ty: GLenum) -> bool {
+                $(ty == $gl_ty)||+
+            }
+
+            fn type_name() -> &'static str {
+                stringify!($ty)
+            }
+
+            fn upload_slice(program: Option<GLuint>, location: GLint, values: &[$ty]) {
+                let count = values.len() as GLsizei;
+                let ptr = values.as_ptr() as *const $scalar;
+                unsafe {
+                    match program {
+                        Some(program) => gl::$program_uniform(program, location, count, $($arg,)* ptr),
+                        None => gl::$uniform(location, count, $($arg,)* ptr),
+                    };
+                };
+            }
+        }
+    }
+}
+
+uniform_element!(f32, f32, [gl::FLOAT], Uniform1fv, ProgramUniform1fv);
+uniform_element!(u32, u32, [gl::UNSIGNED_INT, gl::BOOL], Uniform1uiv, ProgramUniform1uiv);
+uniform_element!(Vector2<f32>, f32, [gl::FLOAT_VEC2], Uniform2fv, ProgramUniform2fv);
+uniform_element!(Vector3<f32>, f32, [gl::FLOAT_VEC3], Uniform3fv, ProgramUniform3fv);
+uniform_element!(Vector4<f32>, f32, [gl::FLOAT_VEC4], Uniform4fv, ProgramUniform4fv);
+uniform_element!(Point3<f32>, f32, [gl::FLOAT_VEC3], Uniform3fv, ProgramUniform3fv);
+uniform_element!(Matrix2<f32>, f32, [gl::FLOAT_MAT2], UniformMatrix2fv, ProgramUniformMatrix2fv, gl::FALSE);
+uniform_element!(Matrix3<f32>, f32, [gl::FLOAT_MAT3], UniformMatrix3fv, ProgramUniformMatrix3fv, gl::FALSE);
+uniform_element!(Matrix4<f32>, f32, [gl::FLOAT_MAT4], UniformMatrix4fv, ProgramUniformMatrix4fv, gl::FALSE);
+
+fn upload_ints(program: Option<GLuint>, location: GLint, values: &[GLint]) {
+    unsafe {
+        match program {
+            Some(program) => gl::ProgramUniform1iv(program, location, values.len() as GLsizei, values.as_ptr()),
+            None => gl::Uniform1iv(location, values.len() as GLsizei, values.as_ptr()),
         };
+    };
+}
+
+impl UniformElement for i32 {
+    // Sampler uniforms take the texture unit
+    fn accepts(ty: GLenum) -> bool {
+        ty == gl::INT || ty == gl::BOOL || is_sampler_type(ty)
+    }
+
+    fn type_name() -> &'static str {
+        "i32"
+    }
+
+    fn upload_slice(program: Option<GLuint>, location: GLint, values: &[i32]) {
+        upload_ints(program, location, values);
+    }
+}
+
+impl UniformElement for bool {
+    fn accepts(ty: GLenum) -> bool {
+        ty == gl::BOOL
+    }
+
+    fn type_name() -> &'static str {
+        "bool"
+    }
+
+    fn upload_slice(program: Option<GLuint>, location: GLint, values: &[bool]) {
+        let values: Vec<GLint> = values.iter().map(|&value| value as GLint).collect();
+        upload_ints(program, location, &values);
+    }
+}
+
+impl UniformElement for Quaternion<f32> {
+    fn accepts(ty: GLenum) -> bool {
+        ty == gl::FLOAT_VEC4
+    }
+
+    fn type_name() -> &'static str {
+        "Quaternion<f32>"
+    }
+
+    // cgmath stores the scalar part first; shaders expect it in w
+    fn upload_slice(program: Option<GLuint>, location: GLint, values: &[Quaternion<f32>]) {
+        let values: Vec<Vector4<f32>> = values.iter().map(|q| q.v.extend(q.s)).collect();
+        Vector4::upload_slice(program, location, &values);
     }
 }
 
 pub struct Uniform<'a> {
     pub name: &'static str,
-    pub value: &'a Uniformable,
+    pub value: &'a dyn Uniformable,
 }
 
 impl<'a> Uniform<'a> {
     pub fn bind(&self, program: &Program) {
-        self.value.bind(&self.name, program);
+        program.set_uniform(self.name, self.value);
     }
 }
@@ -0,0 +1,145 @@
+use std::mem;
+use std::slice;
+use cgmath::{Matrix4, Point3, Vector2, Vector3, Vector4};
+use gl;
+use gl::types::*;
+use gfx::lowlevel::Program;
+use gfx::lowlevel::buffer::StreamBuffer;
+
+/// A type that can sit in a std140 uniform block, with the base alignment and size the layout
+/// rules give it.
+///
+/// # Safety
+///
+/// The type's Rust size must be `SIZE`, and its alignment must divide `ALIGN`; the block is
+/// uploaded as raw bytes, so GLSL reads whatever the Rust layout puts there.
+pub unsafe trait Std140Field {
+	const ALIGN: usize;
+	const SIZE: usize;
+}
+
+unsafe impl Std140Field for f32 { const ALIGN: usize = 4; const SIZE: usize = 4; }
+unsafe impl Std140Field for i32 { const ALIGN: usize = 4; const SIZE: usize = 4; }
+unsafe impl Std140Field for u32 { const ALIGN: usize = 4; const SIZE: usize = 4; }
+unsafe impl Std140Field for Vector2<f32> { const ALIGN: usize = 8; const SIZE: usize = 8; }
+unsafe impl Std140Field for Vector3<f32> { const ALIGN: usize = 16; const SIZE: usize = 12; }
+unsafe impl Std140Field for Point3<f32> { const ALIGN: usize = 16; const SIZE: usize = 12; }
+unsafe impl Std140Field for Vector4<f32> { const ALIGN: usize = 16; const SIZE: usize = 16; }
+unsafe impl Std140Field for Matrix4<f32> { const ALIGN: usize = 16; const SIZE: usize = 64; }
+
+// std140 rounds array strides up to 16 bytes, so only arrays of vec4-sized things match Rust's
+// layout; the check below rejects anything else at compile time.
+unsafe impl<T: Std140Field, const N: usize> Std140Field for [T; N] {
+	const ALIGN: usize = 16;
+	const SIZE: usize = {
+		assert!(T::SIZE % 16 == 0, "std140 arrays need elements that are a multiple of 16 bytes");
+		T::SIZE * N
+	};
+}
+
+/// A struct laid out as a std140 uniform block. Implemented by `std140_block!`, which checks
+/// the layout when the crate is compiled.
+///
+/// # Safety
+///
+/// Every field must sit at the offset the std140 rules give it and the struct must have no
+/// other padding GLSL would read. Use `std140_block!` rather than implementing it by hand.
+pub unsafe trait Std140: Copy {
+	/// The block's name in GLSL.
+	const BLOCK_NAME: &'static str;
+}
+
+pub const fn std140_align(offset: usize, align: usize) -> usize {
+	offset.div_ceil(align) * align
+}
+
+/// Declares a #[repr(C)] struct mirroring a std140 uniform block and implements `Std140` for
+/// it. Each field's offset is checked against where std140 places it, so a field that needs
+/// explicit padding (or reordering) fails the build instead of reading garbage on the GPU.
+macro_rules! std140_block {
+	($(#[$attr:meta])* pub struct $name:ident : $block:literal { $($(#[$field_attr:meta])* pub $field:ident : $ty:ty,)+ }) => {
+		$(#[$attr])*
+		#[repr(C)]
+		#[derive(Clone, Copy)]
+		pub struct $name {
+			$($(#[$field_attr])* pub $field: $ty,)+
+		}
+
+		unsafe impl $crate::gfx::lowlevel::Std140 for $name {
+			const BLOCK_NAME: &'static str = $block;
+		}
+
+		const _: () = {
+			use $crate::gfx::lowlevel::{std140_align, Std140Field};
+
+			let mut offset = 0;
+			$(
+				offset = std140_align(offset, <$ty as Std140Field>::ALIGN);
+				assert!(offset == ::std::mem::offset_of!($name, $field),
+						concat!("std140 offset mismatch for ", stringify!($name), "::", stringify!($field)));
+				offset += <$ty as Std140Field>::SIZE;
+			)+
+			assert!(offset <= ::std::mem::size_of::<$name>());
+		};
+	}
+}
+
+/// A `T` bound to a uniform block binding point. Every update is written to a fresh part of a
+/// stream buffer, so it never waits for draws still reading an earlier value.
+pub struct UniformBuffer<T: Std140> {
+	buffer: StreamBuffer<T>,
+	pub binding: GLuint,
+	/// Where the latest value starts.
+	offset: usize,
+}
+
+impl<T: Std140> UniformBuffer<T> {
+	pub fn new(binding: GLuint, value: &T) -> UniformBuffer<T> {
+		let buffer = StreamBuffer::new(gl::UNIFORM_BUFFER, 1);
+		buffer.set_label(T::BLOCK_NAME);
+
+		let mut buffer = UniformBuffer { buffer, binding, offset: 0 };
+		buffer.update(value);
+		buffer
+	}
+
+	/// Writes `value` and binds it.
+	pub fn update(&mut self, value: &T) {
+		// A region per value, so the last one's draws keep theirs
+		self.buffer.begin_frame();
+		self.offset = self.buffer.push(slice::from_ref(value));
+		self.bind();
+	}
+
+	/// Attaches the latest value to the binding point. It stays bound until something else
+	/// takes the binding, so this is only needed when several buffers share one.
+	pub fn bind(&self) {
+		self.buffer.bind_range(self.binding, self.offset, 1);
+	}
+
+	/// Warns when `program` declares the block with a different size or binding than this
+	/// buffer's; a program that doesn't use the block is fine.
+	pub fn check(&self, program: &Program) -> bool {
+		let info = match program.reflection.uniform_blocks.get(T::BLOCK_NAME) {
+			Some(info) => info,
+			None => return true,
+		};
+
+		let size = std140_align(mem::size_of::<T>(), 16);
+		let mut ok = true;
+
+		if info.data_size as usize != size {
+			println!("{}: uniform block {} is {} bytes, but the buffer holds {}",
+					 program.label(), T::BLOCK_NAME, info.data_size, size);
+			ok = false;
+		}
+
+		if info.binding as GLuint != self.binding {
+			println!("{}: uniform block {} uses binding {}, but the buffer is on {}",
+					 program.label(), T::BLOCK_NAME, info.binding, self.binding);
+			ok = false;
+		}
+
+		ok
+	}
+}
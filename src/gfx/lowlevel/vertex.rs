@@ -0,0 +1,150 @@
+use cgmath::{Matrix4, Vector2, Vector3, Vector4};
+use gl;
+use gl::types::*;
+
+/// One attribute within an interleaved vertex.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexAttribute {
+	/// The attribute's name in GLSL.
+	pub name: &'static str,
+	pub components: GLint,
+	/// Component type, e.g. `gl::FLOAT` or `gl::UNSIGNED_BYTE`.
+	pub ty: GLenum,
+	/// Integer components read as [0, 1] (or [-1, 1]) floats.
+	pub normalized: bool,
+	/// Integer components read as integers, for `ivec`/`uvec` attributes.
+	pub integer: bool,
+	/// Byte offset within the vertex.
+	pub offset: usize,
+	/// Consecutive locations the attribute fills: one per column for matrices, otherwise 1.
+	pub locations: GLuint,
+}
+
+impl VertexAttribute {
+	/// Byte size of what one location reads.
+	pub fn location_size(&self) -> usize {
+		let component_size = match self.ty {
+			gl::BYTE | gl::UNSIGNED_BYTE => 1,
+			gl::SHORT | gl::UNSIGNED_SHORT | gl::HALF_FLOAT => 2,
+			_ => 4,
+		};
+
+		self.components as usize * component_size
+	}
+}
+
+/// How the attributes of one vertex buffer are laid out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexLayout {
+	pub stride: usize,
+	pub attributes: Vec<VertexAttribute>,
+	/// 0 to advance once per vertex, N to advance once every N instances.
+	pub divisor: GLuint,
+}
+
+impl VertexLayout {
+	/// The same layout read once per instance, for instanced draws.
+	pub fn per_instance(mut self) -> VertexLayout {
+		self.divisor = 1;
+		self
+	}
+
+	pub fn attribute(&self, name: &str) -> Option<&VertexAttribute> {
+		self.attributes.iter().find(|attribute| attribute.name == name)
+	}
+}
+
+/// A Rust vertex struct that can be uploaded to a buffer as is. Usually implemented by
+/// `vertex_format!`.
+pub trait Vertex: Copy {
+	fn layout() -> VertexLayout;
+}
+
+/// A field type usable in a vertex, with how GL should read it.
+pub trait VertexComponent {
+	const COMPONENTS: GLint;
+	const TYPE: GLenum;
+	const NORMALIZED: bool = false;
+	const INTEGER: bool = false;
+	const LOCATIONS: GLuint = 1;
+}
+
+impl VertexComponent for f32 { const COMPONENTS: GLint = 1; const TYPE: GLenum = gl::FLOAT; }
+impl VertexComponent for Vector2<f32> { const COMPONENTS: GLint = 2; const TYPE: GLenum = gl::FLOAT; }
+impl VertexComponent for Vector3<f32> { const COMPONENTS: GLint = 3; const TYPE: GLenum = gl::FLOAT; }
+impl VertexComponent for Vector4<f32> { const COMPONENTS: GLint = 4; const TYPE: GLenum = gl::FLOAT; }
+
+/// Per-instance transforms; a `mat4` attribute takes a location per column.
+impl VertexComponent for Matrix4<f32> {
+	const COMPONENTS: GLint = 4;
+	const TYPE: GLenum = gl::FLOAT;
+	const LOCATIONS: GLuint = 4;
+}
+
+/// Colors: four bytes read as a normalized `vec4`.
+impl VertexComponent for [u8; 4] {
+	const COMPONENTS: GLint = 4;
+	const TYPE: GLenum = gl::UNSIGNED_BYTE;
+	const NORMALIZED: bool = true;
+}
+
+/// Skin weights: four normalized shorts.
+impl VertexComponent for [u16; 4] {
+	const COMPONENTS: GLint = 4;
+	const TYPE: GLenum = gl::UNSIGNED_SHORT;
+	const NORMALIZED: bool = true;
+}
+
+impl VertexComponent for u32 {
+	const COMPONENTS: GLint = 1;
+	const TYPE: GLenum = gl::UNSIGNED_INT;
+	const INTEGER: bool = true;
+}
+
+impl VertexComponent for i32 {
+	const COMPONENTS: GLint = 1;
+	const TYPE: GLenum = gl::INT;
+	const INTEGER: bool = true;
+}
+
+/// Bone indices, read as a `uvec4`.
+impl VertexComponent for [u32; 4] {
+	const COMPONENTS: GLint = 4;
+	const TYPE: GLenum = gl::UNSIGNED_INT;
+	const INTEGER: bool = true;
+}
+
+/// Declares a #[repr(C)] vertex struct and implements `Vertex` for it. Each field becomes the
+/// attribute of the same name, laid out where the compiler put the field.
+macro_rules! vertex_format {
+	($(#[$attr:meta])* pub struct $name:ident { $($(#[$field_attr:meta])* pub $field:ident : $ty:ty,)+ }) => {
+		$(#[$attr])*
+		#[repr(C)]
+		#[derive(Clone, Copy, Debug)]
+		pub struct $name {
+			$($(#[$field_attr])* pub $field: $ty,)+
+		}
+
+		impl $crate::gfx::lowlevel::Vertex for $name {
+			fn layout() -> $crate::gfx::lowlevel::VertexLayout {
+				use $crate::gfx::lowlevel::{VertexAttribute, VertexComponent};
+
+				$crate::gfx::lowlevel::VertexLayout {
+					stride: ::std::mem::size_of::<$name>(),
+					attributes: vec![$(
+						VertexAttribute {
+							name: stringify!($field),
+							components: <$ty as VertexComponent>::COMPONENTS,
+							ty: <$ty as VertexComponent>::TYPE,
+							normalized: <$ty as VertexComponent>::NORMALIZED,
+							integer: <$ty as VertexComponent>::INTEGER,
+							offset: ::std::mem::offset_of!($name, $field),
+							locations: <$ty as VertexComponent>::LOCATIONS,
+						},
+					)+],
+					divisor: 0,
+				}
+			}
+		}
+	}
+}
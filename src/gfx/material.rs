@@ -1,44 +1,228 @@
-use std::ptr;
+use std::rc::Rc;
 use gl;
 use gl::types::*;
-use cgmath;
-use cgmath::prelude::*;
-use cgmath::{Matrix4, Vector3, Basis3, Vector2, Decomposed, PerspectiveFov};
-use gfx::image::Image;
+use cgmath::{Vector2, Vector3, Vector4};
 use gfx::lowlevel::*;
 use gfx::lowlevel::{Program, Uniform};
 
+/// A texture and the sampler it is read through, bound to one sampler uniform.
+#[derive(Clone)]
+pub struct TextureSlot {
+    pub uniform: String,
+    pub texture: Handle<Texture>,
+    pub sampler: Rc<Sampler>,
+}
+
+/// A uniform value a material sets on every bind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaterialParam {
+    Float(f32),
+    Vec2(Vector2<f32>),
+    Vec3(Vector3<f32>),
+    Vec4(Vector4<f32>),
+    Int(i32),
+    Bool(bool),
+}
+
+impl MaterialParam {
+    fn bind(&self, name: &str, program: &Program) {
+        match *self {
+            MaterialParam::Float(value) => program.set_uniform(name, &value),
+            MaterialParam::Vec2(value) => program.set_uniform(name, &value),
+            MaterialParam::Vec3(value) => program.set_uniform(name, &value),
+            MaterialParam::Vec4(value) => program.set_uniform(name, &value),
+            MaterialParam::Int(value) => program.set_uniform(name, &value),
+            MaterialParam::Bool(value) => program.set_uniform(name, &value),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cull {
+    None,
+    Back,
+    Front,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Blend {
+    Off,
+    /// Source over destination by source alpha.
+    Alpha,
+    Additive,
+}
+
+/// Fixed-function state a material draws with. Every bind sets all of it, so one material's
+/// state never leaks into the next draw.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RenderState {
+    pub cull: Cull,
+    pub blend: Blend,
+    pub depth_test: bool,
+    pub depth_write: bool,
+}
+
+impl Default for RenderState {
+    /// Opaque, back-face culled and depth tested: what everything outside materials expects.
+    fn default() -> RenderState {
+        RenderState { cull: Cull::Back, blend: Blend::Off, depth_test: true, depth_write: true }
+    }
+}
+
+impl RenderState {
+    pub fn apply(&self) {
+        unsafe {
+            match self.cull {
+                Cull::None => gl::Disable(gl::CULL_FACE),
+                Cull::Back | Cull::Front => {
+                    gl::Enable(gl::CULL_FACE);
+                    gl::CullFace(if self.cull == Cull::Back { gl::BACK } else { gl::FRONT });
+                },
+            };
+
+            match self.blend {
+                Blend::Off => gl::Disable(gl::BLEND),
+                Blend::Alpha => {
+                    gl::Enable(gl::BLEND);
+                    gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+                },
+                Blend::Additive => {
+                    gl::Enable(gl::BLEND);
+                    gl::BlendFunc(gl::ONE, gl::ONE);
+                },
+            };
+
+            if self.depth_test {
+                gl::Enable(gl::DEPTH_TEST);
+            } else {
+                gl::Disable(gl::DEPTH_TEST);
+            }
+
+            gl::DepthMask(if self.depth_write { gl::TRUE } else { gl::FALSE });
+        };
+    }
+}
+
+/// The scalar inputs of glTF's metallic-roughness model. shader.frag multiplies each map by
+/// its factor, and the defaults are glTF's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MetallicRoughness {
+    /// Linear RGB and alpha.
+    pub base_color: Vector4<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Linear RGB, added after lighting.
+    pub emissive: Vector3<f32>,
+    /// Scales the normal map's X and Y.
+    pub normal_scale: f32,
+    /// How much of the occlusion map applies, from none to all.
+    pub occlusion_strength: f32,
+}
+
+impl Default for MetallicRoughness {
+    fn default() -> MetallicRoughness {
+        MetallicRoughness {
+            base_color: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            metallic: 1.0,
+            roughness: 1.0,
+            emissive: Vector3::new(0.0, 0.0, 0.0),
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+        }
+    }
+}
+
+impl MetallicRoughness {
+    /// Sets the factors as the uniforms shader.frag reads, replacing the material's own.
+    pub fn set_params(&self, material: &mut Material) {
+        material.set_param("base_color_factor", MaterialParam::Vec4(self.base_color));
+        material.set_param("metallic_factor", MaterialParam::Float(self.metallic));
+        material.set_param("roughness_factor", MaterialParam::Float(self.roughness));
+        material.set_param("emissive_factor", MaterialParam::Vec3(self.emissive));
+        material.set_param("normal_scale", MaterialParam::Float(self.normal_scale));
+        material.set_param("occlusion_strength", MaterialParam::Float(self.occlusion_strength));
+    }
+}
+
+/// A program with the textures, uniform values and render state to draw it with. Usually
+/// loaded from a material file through `Assets::material`.
+#[derive(Clone)]
 pub struct Material {
-    pub program: Program,
-    pub diffuse_tex: Texture,
-    pub normal_tex: Texture,
-    // pub spec_tex: Texture,
-    pub disp_tex: Texture,
+    pub program: Handle<Program>,
+    pub textures: Vec<TextureSlot>,
+    pub params: Vec<(String, MaterialParam)>,
+    pub state: RenderState,
 }
 
 impl Material {
-    pub fn new(program: Program,
-               diffuse_image: &Image,
-               normal_image: &Image,
-               disp_image: &Image) -> Material {
-
+    pub fn with_program(program: Handle<Program>) -> Material {
         Material {
             program,
-            diffuse_tex: Texture::new("diffuse", diffuse_image),
-            normal_tex: Texture::new("normal", normal_image),
-            disp_tex: Texture::new("disp", normal_image),
+            textures: Vec::new(),
+            params: Vec::new(),
+            state: RenderState::default(),
         }
     }
 
+    /// Pairs `texture` with `sampler` for the sampler uniform `uniform`, replacing any
+    /// previous assignment to that uniform.
+    pub fn add_texture(&mut self, uniform: &str, texture: Handle<Texture>, sampler: Rc<Sampler>) {
+        self.textures.retain(|slot| slot.uniform != uniform);
+        self.textures.push(TextureSlot { uniform: String::from(uniform), texture, sampler });
+    }
+
+    /// Sets `value` on the uniform `name` whenever the material is bound, replacing any previous
+    /// value.
+    pub fn set_param(&mut self, name: &str, value: MaterialParam) {
+        self.params.retain(|(existing, _)| existing != name);
+        self.params.push((String::from(name), value));
+    }
+
+    /// Whether drawing with `other` needs no state changes after binding this material.
+    pub fn same_bindings(&self, other: &Material) -> bool {
+        self.program.ptr_eq(&other.program) &&
+            self.params == other.params &&
+            self.state == other.state &&
+            self.textures.len() == other.textures.len() &&
+            self.textures.iter().zip(&other.textures).all(|(a, b)| {
+                a.uniform == b.uniform && a.texture.ptr_eq(&b.texture) && Rc::ptr_eq(&a.sampler, &b.sampler)
+            })
+    }
+
+    /// Binds the program, textures, parameters and render state, then `uniforms` on top.
     pub fn bind(&self, uniforms: &[Uniform]) {
-		self.diffuse_tex.bind();
-		self.normal_tex.bind();
-		self.disp_tex.bind();
+        let program = self.program.borrow();
+        program.bind();
+
+        for (tex_unit, slot) in self.textures.iter().enumerate() {
+            let tex_unit = tex_unit as GLuint;
+
+            // Not every permutation reads every texture
+            if !program.reflection.uniforms.contains_key(&slot.uniform) {
+                continue;
+            }
+
+            slot.texture.borrow().bind(tex_unit);
+            slot.sampler.bind(tex_unit);
+            (tex_unit as i32).bind(&slot.uniform, &program);
+        };
+
+        for (name, param) in &self.params {
+            param.bind(name, &program);
+        };
 
-		self.program.bind();
+        self.state.apply();
 
         for uniform in uniforms {
-            uniform.bind(&self.program);
+            uniform.bind(&program);
+        };
+    }
+
+    /// Unbinds the samplers `bind` set, so later draws sample their textures with the
+    /// textures' own state. Call after drawing with the material.
+    pub fn unbind(&self) {
+        for tex_unit in 0..self.textures.len() {
+            Sampler::unbind(tex_unit as GLuint);
         };
     }
 }
@@ -0,0 +1,376 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use cgmath::{Vector2, Vector3, Vector4};
+use gfx::lowlevel::{CompareFunc, Defines, Filter, SamplerParams, ShaderStage, Wrap};
+use gfx::material::{Blend, Cull, MaterialParam, RenderState};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TextureSource {
+    Image(PathBuf),
+    CubeMap(PathBuf),
+    Volume(PathBuf),
+    Array(PathBuf),
+    /// The environment cube map shared with the skybox.
+    Environment,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextureDesc {
+    pub uniform: String,
+    pub source: TextureSource,
+    pub sampler: SamplerParams,
+}
+
+/// A parsed material file; `Assets::material` turns it into a `Material`.
+///
+/// Material files are plain text, one directive per line, with `#` starting a comment:
+///
+/// ```text
+/// vertex assets/shader.vert             # one line per stage: vertex, tess_control,
+/// fragment assets/shader.frag           # tess_evaluation, geometry, fragment
+/// define NORMAL_MAP                     # a permutation define, with an optional value
+///
+/// texture base_color_tex assets/diffuse.png repeat  # sampler uniform, file, then options
+/// cube_map env_tex environment clamp    # `environment` is the shared sky cube map
+/// array splat_tex assets/splat.png      # volumes and arrays are strips of square layers
+///
+/// uniform float roughness_factor 0.6    # float, vec2, vec3, vec4, int or bool
+///
+/// cull back                             # back, front or none
+/// blend off                             # off, alpha or additive
+/// depth_test on
+/// depth_write on
+/// ```
+///
+/// Sampler options are `repeat`, `mirror`, `clamp` or `border` for wrapping, `nearest` or
+/// `linear` for filtering, `no_mips`, and `anisotropy=N`. `shadow` makes a depth-compare sampler
+/// for `sampler2DShadow` lookups, and `compare=FUNC` (`less`, `less_equal`, `greater`, ...)
+/// changes its comparison. Paths are relative to the working directory,
+/// like every other asset path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaterialDesc {
+    pub stages: Vec<(ShaderStage, PathBuf)>,
+    pub defines: Defines,
+    pub textures: Vec<TextureDesc>,
+    pub params: Vec<(String, MaterialParam)>,
+    pub state: RenderState,
+}
+
+/// A syntax error, with the line it's on; `None` for problems with the file as a whole.
+#[derive(Debug)]
+pub struct MaterialError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for MaterialError {}
+
+fn parse_number<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse().map_err(|_| format!("\"{}\" isn't a number", word))
+}
+
+fn parse_switch(word: &str) -> Result<bool, String> {
+    match word {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(format!("expected on or off, not \"{}\"", word)),
+    }
+}
+
+fn parse_stage(word: &str) -> Option<ShaderStage> {
+    match word {
+        "vertex" => Some(ShaderStage::Vertex),
+        "tess_control" => Some(ShaderStage::TessControl),
+        "tess_evaluation" => Some(ShaderStage::TessEvaluation),
+        "geometry" => Some(ShaderStage::Geometry),
+        "fragment" => Some(ShaderStage::Fragment),
+        _ => None,
+    }
+}
+
+fn parse_sampler(options: &[&str]) -> Result<SamplerParams, String> {
+    // Shadow samplers start from different defaults, wherever the option is
+    let mut params = if options.contains(&"shadow") {
+        SamplerParams::shadow()
+    } else {
+        SamplerParams::clamp()
+    };
+
+    for &option in options {
+        let wrap = match option {
+            "repeat" => Some(Wrap::Repeat),
+            "mirror" => Some(Wrap::MirroredRepeat),
+            "clamp" => Some(Wrap::ClampToEdge),
+            "border" => Some(Wrap::ClampToBorder),
+            _ => None,
+        };
+
+        if let Some(wrap) = wrap {
+            params.wrap_s = wrap;
+            params.wrap_t = wrap;
+            params.wrap_r = wrap;
+            continue;
+        }
+
+        match option {
+            "nearest" => {
+                params.min_filter = Filter::Nearest;
+                params.mag_filter = Filter::Nearest;
+            },
+            "linear" => {
+                params.min_filter = Filter::Linear;
+                params.mag_filter = Filter::Linear;
+            },
+            "no_mips" => params.mip_filter = None,
+            "shadow" => {},
+            _ if option.starts_with("compare=") => {
+                let func = &option["compare=".len()..];
+                params.compare = Some(CompareFunc::parse(func).
+                    ok_or_else(|| format!("unknown comparison \"{}\"", func))?);
+            },
+            _ if option.starts_with("anisotropy=") => {
+                params.max_anisotropy = parse_number(&option["anisotropy=".len()..])?;
+            },
+            _ => return Err(format!("unknown sampler option \"{}\"", option)),
+        };
+    };
+
+    Ok(params)
+}
+
+fn parse_param(ty: &str, values: &[&str]) -> Result<MaterialParam, String> {
+    let expected = match ty {
+        "float" | "int" | "bool" => 1,
+        "vec2" => 2,
+        "vec3" => 3,
+        "vec4" => 4,
+        _ => return Err(format!("unsupported uniform type \"{}\"", ty)),
+    };
+
+    if values.len() != expected {
+        return Err(format!("a {} takes {} values, not {}", ty, expected, values.len()));
+    }
+
+    if ty == "int" {
+        return Ok(MaterialParam::Int(parse_number(values[0])?));
+    } else if ty == "bool" {
+        return Ok(MaterialParam::Bool(parse_switch(values[0])?));
+    }
+
+    let mut floats = [0.0; 4];
+    for (float, value) in floats.iter_mut().zip(values) {
+        *float = parse_number(value)?;
+    };
+
+    Ok(match expected {
+        1 => MaterialParam::Float(floats[0]),
+        2 => MaterialParam::Vec2(Vector2::new(floats[0], floats[1])),
+        3 => MaterialParam::Vec3(Vector3::new(floats[0], floats[1], floats[2])),
+        _ => MaterialParam::Vec4(Vector4::new(floats[0], floats[1], floats[2], floats[3])),
+    })
+}
+
+impl MaterialDesc {
+    pub fn parse(text: &str) -> Result<MaterialDesc, MaterialError> {
+        let mut desc = MaterialDesc::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap();
+            let words = line.split_whitespace().collect::<Vec<_>>();
+
+            if words.is_empty() {
+                continue;
+            }
+
+            desc.parse_directive(&words).map_err(|message| MaterialError { line: Some(index + 1), message })?;
+        };
+
+        if !desc.stages.iter().any(|&(stage, _)| stage == ShaderStage::Vertex) ||
+           !desc.stages.iter().any(|&(stage, _)| stage == ShaderStage::Fragment) {
+            return Err(MaterialError { line: None, message: String::from("needs a vertex and a fragment shader") });
+        }
+
+        Ok(desc)
+    }
+
+    fn parse_directive(&mut self, words: &[&str]) -> Result<(), String> {
+        let args = &words[1..];
+        let arg_count = |min: usize, max: usize| {
+            if args.len() < min || args.len() > max {
+                Err(format!("wrong number of arguments to {}", words[0]))
+            } else {
+                Ok(())
+            }
+        };
+
+        if let Some(stage) = parse_stage(words[0]) {
+            arg_count(1, 1)?;
+            if self.stages.iter().any(|&(existing, _)| existing == stage) {
+                return Err(format!("more than one {} shader", words[0]));
+            }
+
+            self.stages.push((stage, PathBuf::from(args[0])));
+            return Ok(());
+        }
+
+        match words[0] {
+            "define" => {
+                arg_count(1, 2)?;
+                let value = args.get(1).cloned().unwrap_or("1");
+                self.defines.insert(String::from(args[0]), String::from(value));
+            },
+
+            "texture" | "cube_map" | "volume" | "array" => {
+                arg_count(2, usize::MAX)?;
+                let path = PathBuf::from(args[1]);
+
+                let source = match words[0] {
+                    "cube_map" if args[1] == "environment" => TextureSource::Environment,
+                    "cube_map" => TextureSource::CubeMap(path),
+                    "volume" => TextureSource::Volume(path),
+                    "array" => TextureSource::Array(path),
+                    _ => TextureSource::Image(path),
+                };
+
+                self.textures.retain(|texture| texture.uniform != args[0]);
+                self.textures.push(TextureDesc {
+                    uniform: String::from(args[0]),
+                    source,
+                    sampler: parse_sampler(&args[2..])?,
+                });
+            },
+
+            "uniform" => {
+                arg_count(3, 6)?;
+                let param = parse_param(args[0], &args[2..])?;
+
+                self.params.retain(|(name, _)| name != args[1]);
+                self.params.push((String::from(args[1]), param));
+            },
+
+            "cull" => {
+                arg_count(1, 1)?;
+                self.state.cull = match args[0] {
+                    "back" => Cull::Back,
+                    "front" => Cull::Front,
+                    "none" => Cull::None,
+                    other => return Err(format!("unknown cull mode \"{}\"", other)),
+                };
+            },
+
+            "blend" => {
+                arg_count(1, 1)?;
+                self.state.blend = match args[0] {
+                    "off" => Blend::Off,
+                    "alpha" => Blend::Alpha,
+                    "additive" => Blend::Additive,
+                    other => return Err(format!("unknown blend mode \"{}\"", other)),
+                };
+            },
+
+            "depth_test" => {
+                arg_count(1, 1)?;
+                self.state.depth_test = parse_switch(args[0])?;
+            },
+
+            "depth_write" => {
+                arg_count(1, 1)?;
+                self.state.depth_write = parse_switch(args[0])?;
+            },
+
+            other => return Err(format!("unknown directive \"{}\"", other)),
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHADERS: &str = "vertex a.vert\nfragment a.frag\n";
+
+    #[test]
+    fn parses_every_directive() {
+        let desc = MaterialDesc::parse(
+            "# a comment\n\
+             vertex assets/shader.vert\n\
+             fragment assets/shader.frag  # trailing comment\n\
+             define NORMAL_MAP\n\
+             define LIGHTS 4\n\
+             texture base_color_tex assets/diffuse.png clamp\n\
+             cube_map env_tex environment\n\
+             array splat_tex assets/splat.png repeat\n\
+             uniform vec3 tint 1 0.5 0\n\
+             cull none\n\
+             blend additive\n\
+             depth_write off\n").unwrap();
+
+        assert_eq!(desc.stages, vec![(ShaderStage::Vertex, PathBuf::from("assets/shader.vert")),
+                                     (ShaderStage::Fragment, PathBuf::from("assets/shader.frag"))]);
+        assert_eq!(desc.defines["NORMAL_MAP"], "1");
+        assert_eq!(desc.defines["LIGHTS"], "4");
+
+        assert_eq!(desc.textures.len(), 3);
+        assert_eq!(desc.textures[0].source, TextureSource::Image(PathBuf::from("assets/diffuse.png")));
+        assert_eq!(desc.textures[0].sampler.wrap_s, Wrap::ClampToEdge);
+        assert_eq!(desc.textures[1].source, TextureSource::Environment);
+        assert_eq!(desc.textures[2].source, TextureSource::Array(PathBuf::from("assets/splat.png")));
+
+        assert_eq!(desc.params, vec![(String::from("tint"), MaterialParam::Vec3(Vector3::new(1.0, 0.5, 0.0)))]);
+        assert_eq!(desc.state.cull, Cull::None);
+        assert_eq!(desc.state.blend, Blend::Additive);
+        assert!(desc.state.depth_test);
+        assert!(!desc.state.depth_write);
+    }
+
+    #[test]
+    fn parses_shadow_samplers() {
+        let desc = MaterialDesc::parse(&format!("{}texture shadow_tex shadow.png linear shadow compare=less\n\
+                                                  texture lut_tex lut.png border\n", SHADERS)).unwrap();
+
+        let shadow = desc.textures[0].sampler;
+        assert_eq!(shadow.compare, Some(CompareFunc::Less));
+        assert_eq!(shadow.wrap_s, Wrap::ClampToBorder);
+        assert_eq!(shadow.border_color, [1.0; 4]);
+
+        let lut = desc.textures[1].sampler;
+        assert_eq!(lut.compare, None);
+        assert_eq!(lut.wrap_t, Wrap::ClampToBorder);
+
+        let err = MaterialDesc::parse(&format!("{}texture t t.png compare=sometimes\n", SHADERS)).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: unknown comparison \"sometimes\"");
+    }
+
+    #[test]
+    fn unknown_directives_report_their_line() {
+        let err = MaterialDesc::parse(&format!("{}\nshininess 4\n", SHADERS)).unwrap_err();
+        assert_eq!(err.line, Some(4));
+        assert_eq!(err.to_string(), "line 4: unknown directive \"shininess\"");
+
+        let err = MaterialDesc::parse(&format!("{}blend sometimes\n", SHADERS)).unwrap_err();
+        assert_eq!(err.line, Some(3));
+    }
+
+    #[test]
+    fn missing_stages_are_reported_for_the_file() {
+        let err = MaterialDesc::parse("vertex a.vert\ncull back\n").unwrap_err();
+        assert_eq!(err.line, None);
+        assert_eq!(err.to_string(), "needs a vertex and a fragment shader");
+
+        assert!(MaterialDesc::parse("").is_err());
+        assert!(MaterialDesc::parse(&format!("{}vertex b.vert\n", SHADERS)).is_err());
+    }
+}
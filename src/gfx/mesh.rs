@@ -1,71 +1,229 @@
-use std::ptr;
+use std::mem;
 use gl;
 use gl::types::*;
-use cgmath;
-use cgmath::prelude::*;
-use cgmath::{Matrix4, Vector3, Basis3, Vector2, Decomposed, PerspectiveFov};
-use gfx::image::Image;
+use cgmath::{Matrix4, Vector4, Vector3, Vector2};
 use gfx::lowlevel::*;
 use gfx::material::Material;
 
 pub struct Mesh {
-    pub materials: Vec<Material>,
+    /// For debug output and captures; the file it was loaded from, if any.
+    pub name: String,
+    pub materials: Vec<Handle<Material>>,
+    /// Index ranges drawn with one material each, in draw order.
+    pub submeshes: Vec<Submesh>,
 
     pub ibo: IBO,
+    pub vertices: VBO,
+    layout: VertexLayout,
 	pub vao: VAO,
-    pub transform: Matrix4<f32>,
+    /// Set up by `enable_instancing`.
+    instancing: Option<Instancing>,
+    /// Permutations of `materials` that read per-draw data from a storage buffer, for
+    /// `MultiDraw` batches; empty when the mesh can't be batched.
+    pub multi_draw_materials: Vec<Handle<Material>>,
+}
+
+/// A range of a mesh's index buffer and the material it's drawn with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Submesh {
+    pub first_index: u32,
+    pub count: u32,
+    /// Index into the mesh's `materials`.
+    pub material: usize,
+}
+
+impl Submesh {
+    /// Where the range starts in an index buffer of `index_type`, as the offset draw calls take.
+    pub fn index_offset(&self, index_type: IndexType) -> *const GLvoid {
+        (self.first_index as usize * index_type.size()) as *const GLvoid
+    }
+}
 
-	pub num_verts: u32,
+/// A second vertex array over the mesh's buffers plus a per-instance one, drawn with
+/// permutations of the materials whose programs read the instance attributes.
+struct Instancing {
+    materials: Vec<Handle<Material>>,
+    vao: VAO,
+    instances: StreamBuffer<InstanceData>,
+}
+
+/// Most instances drawn with one call; more take several.
+const INSTANCES_PER_DRAW: usize = 1024;
+/// Where the instance buffer is attached in the instanced vertex array, after the vertices.
+const INSTANCE_BINDING: usize = 1;
+
+vertex_format! {
+    /// The vertex format of meshes loaded from .mdl files.
+    pub struct MeshVertex {
+        pub position: Vector3<GLfloat>,
+        pub normal: Vector3<GLfloat>,
+        pub tangent: Vector3<GLfloat>,
+        pub texcoord: Vector2<GLfloat>,
+    }
+}
+
+vertex_format! {
+    /// What an instanced draw reads once per instance, in place of the Object block.
+    pub struct InstanceData {
+        pub instance_trans: Matrix4<GLfloat>,
+        pub instance_color: Vector4<GLfloat>,
+    }
 }
 
 impl Mesh {
-	pub fn new(program: Program, 
-               indices: &[u32],
-			   vertices: &[Vector3<GLfloat>],
-			   normals: &[Vector3<GLfloat>],
-			   texcoords: &[Vector2<GLfloat>],
-               tangents: &[Vector3<GLfloat>],
-			   image: &Image,
-               normal_map: &Image,
-               disp_map: &Image,
-               transform: Matrix4<f32>) -> Mesh {
+	/// Indices are stored as the narrowest type that can address `vertices`. Every submesh's
+	/// material must be in `materials`.
+	pub fn new<V: Vertex>(materials: Vec<Handle<Material>>,
+                          submeshes: Vec<Submesh>,
+                          indices: &[u32],
+                          vertices: &[V]) -> Mesh {
 		
-        let ibo = IBO::new(indices).unwrap();
-		let verts = VBO::new(vertices).unwrap();
-        let norms = VBO::new(normals).unwrap();
-		let texcoords = VBO::new(texcoords).unwrap();
-        let tangents = VBO::new(tangents).unwrap();
-		let vao = VAO::new(verts, norms, texcoords, tangents, &program);
+        let ibo = IBO::narrowest(indices, vertices.len());
+        let layout = V::layout();
+        let vertices = VBO::new(vertices).unwrap();
+        assert!(submeshes.iter().all(|submesh| submesh.material < materials.len()));
+
+		let vao = VAOBuilder::new(&materials[0].borrow().program.borrow()).
+            attach(&vertices, layout.clone()).
+            index_buffer(&ibo).
+            build();
+
+		Mesh {
+            name: String::from("mesh"),
+            ibo,
+            vertices,
+            layout,
+            vao,
+            instancing: None,
+            multi_draw_materials: Vec::new(),
+            materials,
+            submeshes,
+        }
+	}
+
+    /// Allows `draw_instanced`, with `materials` in place of the mesh's own, built around
+    /// programs that take their transform and color from `InstanceData` attributes.
+    pub fn enable_instancing(&mut self, materials: Vec<Handle<Material>>) {
+        assert_eq!(materials.len(), self.materials.len());
+
+        let instances = StreamBuffer::new(gl::ARRAY_BUFFER, INSTANCES_PER_DRAW);
+        let vao = VAOBuilder::new(&materials[0].borrow().program.borrow()).
+            attach(&self.vertices, self.layout.clone()).
+            attach_stream(&instances, InstanceData::layout().per_instance()).
+            index_buffer(&self.ibo).
+            build();
+
+        self.instancing = Some(Instancing { materials, vao, instances });
+        self.label_objects();
+    }
+
+    /// Renames the mesh and the GL objects behind it.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = String::from(name);
+        self.label_objects();
+    }
 
-        let materials = vec![Material::new(program, image, normal_map, disp_map)];
+    fn label_objects(&self) {
+        self.vertices.set_label(&format!("{} vertices", self.name));
+        self.ibo.set_label(&format!("{} indices", self.name));
+        self.vao.set_label(&self.name);
 
-		Mesh { ibo, vao, materials, transform, num_verts: indices.len() as u32, }
+        if let Some(ref instancing) = self.instancing {
+            instancing.instances.set_label(&format!("{} instances", self.name));
+            instancing.vao.set_label(&format!("{} instanced", self.name));
+        }
+    }
+
+    pub fn layout(&self) -> &VertexLayout {
+        &self.layout
+    }
+
+    pub fn is_instanced(&self) -> bool {
+        self.instancing.is_some()
+    }
+	
+	/// Size of the vertex, index and instance buffers.
+	pub fn byte_size(&self) -> usize {
+        let instances = self.instancing.as_ref().map_or(0, |instancing| instancing.instances.byte_size());
+		self.ibo.byte_size + self.vertices.byte_size + instances
 	}
+
+    /// Binds `material` and picks the primitive type its program takes.
+    pub fn bind_material(material: &Material) -> GLenum {
+        material.bind(&[]);
+
+        // Tessellated programs take each triangle as a patch
+        if material.program.borrow().is_tessellated() {
+            unsafe { gl::PatchParameteri(gl::PATCH_VERTICES, 3) };
+            gl::PATCHES
+        } else {
+            gl::TRIANGLES
+        }
+    }
 	
-	pub fn draw(&self, view: &Decomposed<Vector3<GLfloat>, Basis3<GLfloat>>, proj: &Matrix4<f32>) {
-		let view: Matrix4<f32> = view.clone().into();
+	/// Draws with whatever frame and object blocks are bound, one call per submesh.
+	pub fn draw(&self) {
+        let _group = debug_group(&self.name);
 
-        self.ibo.bind();
-		
-        // TODO: use glVertexAttribFormat, glVertexAttribBinding, and glBindVertexBuffers
+        // The vertex array records the index buffer too
 		self.vao.bind();
 
-        let material = &self.materials[0];
-		
-        let uniforms = [
-            Uniform { name: "trans", value: &self.transform as &Uniformable },
-            Uniform { name: "proj", value: proj as &Uniformable },
-            Uniform { name: "view", value: &view as &Uniformable },
-            
-            Uniform { name: "tex", value: &(material.diffuse_tex.tex_unit as i32) as &Uniformable },
-            Uniform { name: "normal_tex", value: &(material.normal_tex.tex_unit as i32) as &Uniformable },
-            Uniform { name: "disp_tex", value: &(material.disp_tex.tex_unit as i32) as &Uniformable },
-        ];
-
-        material.bind(&uniforms);
-		
-		unsafe {
-			gl::DrawElements(gl::TRIANGLES, self.num_verts as i32, gl::UNSIGNED_INT, ptr::null_mut());
-		};
+        for submesh in &self.submeshes {
+            let material = self.materials[submesh.material].borrow();
+            let mode = Mesh::bind_material(&material);
+
+            unsafe {
+                gl::DrawElements(mode, submesh.count as GLsizei, self.ibo.index_type.gl_enum(),
+                                 submesh.index_offset(self.ibo.index_type));
+            };
+
+            material.unbind();
+        };
 	}
+
+    /// Draws one copy per element of `instances`, with one call per submesh. Panics unless
+    /// `enable_instancing` was called.
+    pub fn draw_instanced(&mut self, instances: &[InstanceData]) {
+        let instancing = self.instancing.as_mut().expect("mesh isn't set up for instancing");
+        if instances.is_empty() {
+            return;
+        }
+
+        let name = &self.name;
+        let _group = debug_group_with(|| format!("{} x{}", name, instances.len()));
+        instancing.instances.begin_frame();
+        instancing.vao.bind();
+
+        for instances in instances.chunks(instancing.instances.capacity()) {
+            // The instance attributes start at the base instance, wherever the data landed.
+            // Without base instances the attributes are pointed at the data instead.
+            let offset = instancing.instances.push(instances);
+            let base_instance = if caps().base_instance {
+                offset / mem::size_of::<InstanceData>()
+            } else {
+                instancing.vao.set_buffer_offset(INSTANCE_BINDING, offset);
+                0
+            };
+
+            for submesh in &self.submeshes {
+                let material = instancing.materials[submesh.material].borrow();
+                let mode = Mesh::bind_material(&material);
+
+                let count = submesh.count as GLsizei;
+                let index_type = self.ibo.index_type.gl_enum();
+                let offset = submesh.index_offset(self.ibo.index_type);
+
+                unsafe {
+                    if caps().base_instance {
+                        gl::DrawElementsInstancedBaseInstance(mode, count, index_type, offset,
+                                                              instances.len() as GLsizei, base_instance as GLuint);
+                    } else {
+                        gl::DrawElementsInstanced(mode, count, index_type, offset, instances.len() as GLsizei);
+                    }
+                };
+
+                material.unbind();
+            };
+        };
+    }
 }
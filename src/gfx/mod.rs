@@ -1,5 +1,12 @@
-pub mod image;
+#[macro_use]
 pub mod lowlevel;
+pub mod assets;
+pub mod batch;
+pub mod blocks;
+pub mod image;
 pub mod material;
+pub mod material_file;
 pub mod mesh;
 pub mod model;
+pub mod particles;
+pub mod skybox;
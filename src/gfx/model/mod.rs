@@ -1,33 +1,33 @@
 use std::iter::Iterator;
-use std::path::Path;
-use cgmath::{Vector2, Vector3, Matrix4};
-use gfx::mesh::Mesh;
-use gfx::image;
-use gfx::lowlevel::program::Program;
+use std::path::PathBuf;
+use cgmath::{Vector2, Vector3};
+use gfx::assets::Assets;
+use gfx::lowlevel::{caps, Defines};
+use gfx::material;
+use gfx::lowlevel::Handle;
+use gfx::mesh::{Mesh, MeshVertex, Submesh};
 
 pub mod model_loader;
 
-#[derive(Debug)]
-pub struct Texture {
-	pub size: Vector2<u16>,
-	pub pixels: Box<[Vector3<u8>]>,
-}
+/// What .mdl meshes are drawn with when they don't name a material with a file of its own, and
+/// the same with `Assets::tessellate_meshes`.
+pub const DEFAULT_MATERIAL: &str = "assets/default.mat";
+pub const DISPLACED_MATERIAL: &str = "assets/displaced.mat";
 
-#[derive(Debug)]
+/// A material as exported: the name picks a material file, and the factors are glTF's
+/// metallic-roughness inputs.
+#[derive(Debug, Default)]
 pub struct Material {
 	pub name: String,
-	pub shader_vertex: String,
-	pub shader_fragment: String,
-	pub normals: Option<Texture>,
-	pub diffuse: Option<Texture>,
-	pub specular: Option<Texture>,
+	/// Unset when the file has none, leaving the material file's values.
+	pub factors: Option<material::MetallicRoughness>,
 }
 
 #[derive(Debug)]
 pub struct Model {
-	pub name: String,
-	pub transform: Matrix4<f32>,
 	pub materials: Box<[Material]>,
+    /// Index ranges by material, covering all of `indices`.
+    pub submeshes: Box<[Submesh]>,
     pub indices: Box<[u32]>,
 	pub vertices: Box<[Vector3<f32>]>,
 	pub normals: Box<[Vector3<f32>]>,
@@ -35,15 +35,87 @@ pub struct Model {
 	pub texcoords: Box<[Vector2<f32>]>,
 }
 
-impl From<Model> for Mesh {
-    fn from(model: Model) -> Mesh {
-        let image = image::load_image(&Path::new("assets/diffuse.png")).unwrap();
-        let normal_map = image::load_image(&Path::new("assets/normals.png")).unwrap();
-        let disp_map = image::load_image(&Path::new("assets/displacement.png")).unwrap();
-		let program = Program::from_path(&Path::new("assets/shader.vert"), &Path::new("assets/shader.frag"));
+impl Model {
+    /// The material file for `material` and the factors to draw it with. A material with a file
+    /// of its own, `assets/<name>.mat`, is used as it is; the rest get the default material with
+    /// their exported factors.
+    fn material_source(material: Option<&Material>, assets: &Assets) -> (PathBuf, Option<material::MetallicRoughness>) {
+        let named = material.
+            filter(|material| !material.name.is_empty()).
+            map(|material| PathBuf::from(format!("assets/{}.mat", material.name)));
+        let factors = material.and_then(|material| material.factors);
+
+        match named {
+            Some(ref path) if path.exists() => (path.clone(), None),
+            _ if assets.tessellate_meshes => (PathBuf::from(DISPLACED_MATERIAL), factors),
+            _ => (PathBuf::from(DEFAULT_MATERIAL), factors),
+        }
+    }
+
+    /// Builds a GPU mesh, sharing materials, textures and programs through `assets`.
+    pub fn into_mesh(self, assets: &mut Assets) -> Mesh {
+        // Models without materials still have a submesh referring to the first
+        let mut sources = self.materials.iter().
+            map(|material| Model::material_source(Some(material), assets)).
+            collect::<Vec<_>>();
+        if sources.is_empty() {
+            sources.push(Model::material_source(None, assets));
+        }
+
+        let variants = |assets: &mut Assets, define: Option<&str>| -> Vec<Handle<material::Material>> {
+            let mut defines = Defines::new();
+            if let Some(define) = define {
+                defines.insert(String::from(define), String::from("1"));
+            }
+
+            sources.iter().
+                map(|&(ref path, factors)| {
+                    assets.material(path, &defines, factors).unwrap_or_else(|err| panic!("{}", err))
+                }).
+                collect()
+        };
+
+        let materials = variants(assets, None);
+        let instanced_materials = variants(assets, Some("INSTANCED"));
+        let multi_draw_materials = if caps().multi_draw_indirect {
+            variants(assets, Some("MULTI_DRAW"))
+        } else {
+            Vec::new()
+        };
+
+        let vertices: Vec<MeshVertex> = (0..self.vertices.len()).
+            map(|i| MeshVertex {
+                position: self.vertices[i],
+                normal: self.normals[i],
+                tangent: self.tangents[i],
+                texcoord: self.texcoords[i],
+            }).
+            collect();
+
+        let mut mesh = Mesh::new(materials, self.submeshes.to_vec(), &self.indices[0..], &vertices);
+        mesh.enable_instancing(instanced_materials);
+        mesh.multi_draw_materials = multi_draw_materials;
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use gfx::assets::Assets;
+    use gfx::lowlevel::test_context;
+    use super::*;
+
+    #[test]
+    fn tessellation_switch_picks_displaced_material() {
+        test_context::make_current();
+
+        let mut assets = Assets::new();
+        let unnamed = Material::default();
+        assert_eq!(Model::material_source(Some(&unnamed), &assets).0, PathBuf::from(DEFAULT_MATERIAL));
 
-        Mesh::new(program, &model.indices[0..], &model.vertices[0..], &model.normals[0..], 
-                  &model.texcoords[0..], &model.tangents[0..], &image, 
-                  &normal_map, &disp_map, model.transform)
+        assets.tessellate_meshes = true;
+        assert_eq!(Model::material_source(Some(&unnamed), &assets).0, PathBuf::from(DISPLACED_MATERIAL));
+        assert_eq!(Model::material_source(None, &assets).0, PathBuf::from(DISPLACED_MATERIAL));
     }
 }
@@ -1,97 +1,55 @@
-use std::io::Read;
+use std::io::{self, Read};
 use std::mem;
-use std::iter::{Iterator, repeat};
+use gfx::material::MetallicRoughness;
+use gfx::mesh::Submesh;
 use gfx::model;
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
-use cgmath::{Matrix4,Vector2,Vector3,Decomposed,Basis3,Deg,Rotation3,Matrix,InnerSpace,Vector4,Zero};
+use byteorder::{BigEndian, ReadBytesExt};
+use cgmath::{Matrix4,Vector2,Vector3,InnerSpace,Vector4,Zero};
 
-fn read_bool(reader: &mut Read) -> bool {
-    let mut buf = [0];
-    reader.read(&mut buf);
+/// Files start with these bytes and a big-endian `u16` format version. Files from before
+/// versioning start straight with the transform, and are read as version 0.
+pub const MAGIC: &[u8; 4] = b"MDL\0";
+/// Version 1 follows each material name with its factors. Version 2 gives the index size a
+/// byte of its own ahead of the index count.
+pub const VERSION: u16 = 2;
 
-    buf[0] == 1
-}
-
-fn read_string(reader: &mut Read) -> String {
-    let string_len = reader.read_u16::<BigEndian>().unwrap() as usize;
-
-    let mut string_buf = Vec::with_capacity(string_len);
-
-    unsafe {
-        string_buf.set_len(string_len);
-    };
+fn read_string(reader: &mut dyn Read) -> io::Result<String> {
+    let string_len = reader.read_u16::<BigEndian>()? as usize;
 
-    reader.read(&mut string_buf).expect("Read failed");
+    let mut string_buf = vec![0; string_len];
+    reader.read_exact(&mut string_buf)?;
 
-    String::from_utf8(string_buf).unwrap()
+    String::from_utf8(string_buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
 }
 
-fn read_transform(reader: &mut Read) -> Matrix4<f32> {
-    let mut buf = Vec::with_capacity(mem::size_of::<Matrix4<f32>>());
+fn read_transform(reader: &mut dyn Read) -> Matrix4<f32> {
+    let mut matrix = Matrix4::zero();
 
     unsafe {
-        buf.set_len(mem::size_of::<Matrix4<f32>>());
+        let values: &mut [f32; 16] = matrix.as_mut();
+        reader.read_f32_into_unchecked::<BigEndian>(values).unwrap();
     };
 
-    reader.read(&mut buf);
-
-    let mut matrix: Matrix4<f32> = unsafe { mem::uninitialized() };
-    unsafe {
-        BigEndian::read_f32_into_unchecked(&buf, 
-                                           &mut matrix.as_mut() as &mut [f32; 16]);
-    }
-
     matrix
 }
 
-fn read_texture(reader: &mut Read) -> Option<model::Texture> {
-    if !read_bool(reader) {
-        return None;
-    };
-
-    let width = reader.read_u16::<BigEndian>().unwrap();
-    let height = reader.read_u16::<BigEndian>().unwrap();
-    let num_pixels = width as usize * height as usize;
-    let size = Vector2::<u16>::new(width, height);
-    
-    let mut pixels: Vec<Vector3<u8>> = Vec::with_capacity(num_pixels);
-
-    for _ in 0..pixels.capacity() {
-        let mut pixel: Vector3<u8> = unsafe { mem::uninitialized() };
-
-        reader.read(&mut pixel[0..3]).expect("Pixel read failed");
-
-        pixels.push(pixel);
+/// Base color, metallic, roughness and emissive, as the exporter writes them.
+fn read_factors(reader: &mut dyn Read) -> MetallicRoughness {
+    let mut values = [0.0; 9];
+    unsafe {
+        reader.read_f32_into_unchecked::<BigEndian>(&mut values).unwrap();
     };
 
-    Some(model::Texture {
-        size,
-        pixels: pixels.into_boxed_slice(),
-    })
-}
-
-fn read_material(reader: &mut Read) -> model::Material {
-    let name = read_string(reader);
-
-    // shaders go here
-    let shader_vertex = read_string(reader);
-    let shader_fragment = read_string(reader);
-    
-    let normals = read_texture(reader);
-    let diffuse = read_texture(reader);
-    let specular = read_texture(reader);
-
-    model::Material {
-        name,
-        shader_vertex,
-        shader_fragment,
-        normals,
-        diffuse,
-        specular,
+    MetallicRoughness {
+        base_color: Vector4::new(values[0], values[1], values[2], values[3]),
+        metallic: values[4],
+        roughness: values[5],
+        emissive: Vector3::new(values[6], values[7], values[8]),
+        ..Default::default()
     }
 }
 
-fn read_vertex(reader: &mut Read) -> Vector3<f32> {
+fn read_vertex(reader: &mut dyn Read) -> Vector3<f32> {
     let mut vertex: Vector3<f32> = unsafe { mem::zeroed() };
 
     unsafe {
@@ -101,7 +59,7 @@ fn read_vertex(reader: &mut Read) -> Vector3<f32> {
     vertex
 }
 
-fn read_normal(reader: &mut Read) -> Vector3<f32> {
+fn read_normal(reader: &mut dyn Read) -> Vector3<f32> {
     let mut normal: Vector3<f32> = unsafe { mem::zeroed() };
 
     unsafe {
@@ -111,7 +69,7 @@ fn read_normal(reader: &mut Read) -> Vector3<f32> {
     normal
 }
 
-fn read_texcoord(reader: &mut Read) -> Vector2<f32> {
+fn read_texcoord(reader: &mut dyn Read) -> Vector2<f32> {
     let mut texcoord: Vector2<f32> = unsafe { mem::zeroed() };
 
     unsafe {
@@ -122,12 +80,70 @@ fn read_texcoord(reader: &mut Read) -> Vector2<f32> {
     texcoord
 }
 
-fn read_index(reader: &mut Read) -> u32 {
-    reader.read_u32::<BigEndian>().unwrap()
+/// Indices are stored as narrow as the vertex count allows, in 2 or 4 bytes. From version 2 on
+/// their size comes in a byte before the count; before that it was packed into the count's top
+/// byte, with 0 there for files from before narrow indices, meaning 4.
+fn read_indices(reader: &mut dyn Read, version: u16) -> Box<[u32]> {
+    let (size, num_indices) = if version >= 2 {
+        let size = reader.read_u8().unwrap();
+        (size, reader.read_u32::<BigEndian>().unwrap() as usize)
+    } else {
+        let header = reader.read_u32::<BigEndian>().unwrap();
+        let size = match header >> 24 {
+            0 => 4,
+            size => size as u8,
+        };
+        (size, (header & 0x00ff_ffff) as usize)
+    };
+
+    // Byte indices aren't drawn natively by most hardware; the writer never emits them
+    let read_index = match size {
+        2 => |reader: &mut dyn Read| reader.read_u16::<BigEndian>().unwrap() as u32,
+        4 => |reader: &mut dyn Read| reader.read_u32::<BigEndian>().unwrap(),
+        size => panic!("Unsupported index size {}", size),
+    };
+
+    (0..num_indices).
+        map(|_| read_index(reader)).
+        collect::<Vec<_>>().
+        into_boxed_slice()
+}
+
+/// Material names, their factors from version 1 on, and the index range drawn with each.
+/// Files from before submeshes end before them; they get one submesh covering every index.
+fn read_submeshes(reader: &mut dyn Read, num_indices: usize, version: u16) -> (Box<[model::Material]>, Box<[Submesh]>) {
+    let num_materials = match reader.read_u32::<BigEndian>() {
+        Ok(num_materials) => num_materials,
+        Err(_) => {
+            let submesh = Submesh { first_index: 0, count: num_indices as u32, material: 0 };
+            return (Box::new([]), vec![submesh].into_boxed_slice());
+        },
+    };
+
+    let materials = (0..num_materials).
+        map(|_| {
+            let name = read_string(reader).expect("Material name read failed");
+            let factors = if version >= 1 { Some(read_factors(reader)) } else { None };
+            model::Material { name, factors }
+        }).
+        collect::<Vec<_>>();
+
+    let submeshes = read_and_box(reader, |reader| {
+        let first_index = reader.read_u32::<BigEndian>().unwrap();
+        let count = reader.read_u32::<BigEndian>().unwrap();
+        let material = reader.read_u16::<BigEndian>().unwrap() as usize;
+
+        assert!(material < materials.len().max(1), "Submesh material {} out of range", material);
+        assert!(first_index as usize + count as usize <= num_indices, "Submesh past the end of the indices");
+
+        Submesh { first_index, count, material }
+    });
+
+    (materials.into_boxed_slice(), submeshes)
 }
 
-fn read_and_box<T, F>(reader: &mut Read, read_fn: F) -> Box<[T]> 
-    where F: Fn(&mut Read) -> T {
+fn read_and_box<T, F>(reader: &mut dyn Read, read_fn: F) -> Box<[T]> 
+    where F: Fn(&mut dyn Read) -> T {
 
     let num_items = reader.read_u32::<BigEndian>().unwrap() as usize;
 
@@ -142,46 +158,45 @@ fn read_and_box<T, F>(reader: &mut Read, read_fn: F) -> Box<[T]>
     items.into_boxed_slice()
 }
 
-fn calc_tangents(indices: &[u32], vertices: &[Vector3<f32>], 
+fn calc_tangents(indices: &[u32], vertices: &[Vector3<f32>],
                  texcoords: &[Vector2<f32>], normals: &[Vector3<f32>])
         -> Box<[Vector3<f32>]> {
-    
-    let mut tangents = repeat(Vector4::zero()).
-        take(indices.len()).
-        collect::<Vec<_>>();
-
-    for tri in 0..(vertices.len() / 3) {
-        // FIXME FIXME FIXME: use indices to get triangles
-        let vert1 = vertices[indices[tri * 3] as usize];
-        let vert2 = vertices[indices[tri * 3 + 1] as usize];
-        let vert3 = vertices[indices[tri * 3 + 2] as usize];
-        let uv1 = texcoords[indices[tri * 3] as usize];
-        let uv2 = texcoords[indices[tri * 3 + 1] as usize];
-        let uv3 = texcoords[indices[tri * 3 + 2] as usize];
+    // Summed over every triangle sharing the vertex
+    let mut tangents = vec![Vector3::zero(); vertices.len()];
+
+    for tri in indices.chunks_exact(3) {
+        let vert1 = vertices[tri[0] as usize];
+        let vert2 = vertices[tri[1] as usize];
+        let vert3 = vertices[tri[2] as usize];
+        let uv1 = texcoords[tri[0] as usize];
+        let uv2 = texcoords[tri[1] as usize];
+        let uv3 = texcoords[tri[2] as usize];
         let edge1 = vert2 - vert1;
         let edge2 = vert3 - vert1;
         let delta_uv1 = uv2 - uv1;
         let delta_uv2 = uv3 - uv1;
 
-        let det = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv2.x * delta_uv1.y);
-        let tangent = Vector3::new(
-                det * (delta_uv2.y * edge1.x - delta_uv1.y * edge2.x),
-                det * (delta_uv2.y * edge1.y - delta_uv1.y * edge2.y),
-                det * (delta_uv2.y * edge1.z - delta_uv1.y * edge2.z)).
-            normalize().
-            extend(1.0);
+        // Triangles with degenerate texture coordinates have no texture space to contribute
+        let det = delta_uv1.x * delta_uv2.y - delta_uv2.x * delta_uv1.y;
+        if det == 0.0 {
+            continue;
+        }
 
-        for vert in 0..3 {
-            tangents[indices[tri * 3 + vert] as usize] += tangent;
+        let tangent = ((edge1 * delta_uv2.y - edge2 * delta_uv1.y) / det).normalize();
+
+        for &index in tri {
+            tangents[index as usize] += tangent;
         };
     };
 
-    let tangents = tangents.iter().
-        map(|tangent| {
-            if tangent.w == 0.0 {
-                Vector3::new(0.0, 0.0, 0.0)
+    // Made perpendicular to the normal, which averaging across triangles doesn't preserve
+    let tangents = tangents.iter().zip(normals.iter()).
+        map(|(&tangent, &normal)| {
+            let tangent = tangent - normal * normal.dot(tangent);
+            if tangent.magnitude2() == 0.0 {
+                tangent
             } else {
-                (tangent / tangent.w).truncate()
+                tangent.normalize()
             }
         }).
         collect::<Vec<_>>();
@@ -189,20 +204,37 @@ fn calc_tangents(indices: &[u32], vertices: &[Vector3<f32>],
     tangents.into()
 }
 
-pub fn load_model(reader: &mut Read) -> model::Model {
-    let name = String::from("");//read_string(reader);
-    let transform = read_transform(reader);
-    let materials = Box::new([]);//read_and_box(reader, read_material);
-    let indices = read_and_box(reader, read_index);
+pub fn load_model(reader: &mut dyn Read) -> model::Model {
+    let mut start = [0; 4];
+    reader.read_exact(&mut start).expect("Model read failed");
+
+    // The exporter's world transform comes first; scene objects place meshes themselves, so
+    // it's skipped
+    let version = if start == *MAGIC {
+        let version = reader.read_u16::<BigEndian>().unwrap();
+        assert!(version <= VERSION, "Unsupported model version {}", version);
+        read_transform(reader);
+        version
+    } else {
+        read_transform(&mut (&start[..]).chain(&mut *reader));
+        0
+    };
+
+    let indices = read_indices(reader, version);
     let vertices = read_and_box(reader, read_vertex);
     let texcoords = read_and_box(reader, read_texcoord);
     let normals = read_and_box(reader, read_normal);
+    let (materials, submeshes) = read_submeshes(reader, indices.len(), version);
+
+    assert!(texcoords.len() == vertices.len() && normals.len() == vertices.len(),
+            "Model has {} vertices but {} texture coordinates and {} normals",
+            vertices.len(), texcoords.len(), normals.len());
+    assert!(indices.iter().all(|&index| (index as usize) < vertices.len()), "Model index out of range");
     let tangents = calc_tangents(&indices, &vertices, &texcoords, &normals);
 
     model::Model {
-        name,
-        transform,
         materials,
+        submeshes,
         indices,
         vertices,
         texcoords,
@@ -210,3 +242,202 @@ pub fn load_model(reader: &mut Read) -> model::Model {
         tangents,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind};
+    use byteorder::WriteBytesExt;
+    use cgmath::Vector3;
+    use super::*;
+
+    /// Lays `model` out the way mdl_writer.py does. Version 0 is the layout from before
+    /// versioning; without `submeshes` it's the one from before materials, too.
+    fn write_model(model: &model::Model, version: u16, submeshes: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        if version > 0 {
+            data.extend_from_slice(MAGIC);
+            data.write_u16::<BigEndian>(version).unwrap();
+        }
+
+        let floats = |data: &mut Vec<u8>, values: &[f32]| {
+            for &value in values {
+                data.write_f32::<BigEndian>(value).unwrap();
+            };
+        };
+
+        let transform: Matrix4<f32> = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0));
+        let transform: &[f32; 16] = transform.as_ref();
+        floats(&mut data, transform);
+
+        if version >= 2 {
+            data.write_u8(2).unwrap();
+            data.write_u32::<BigEndian>(model.indices.len() as u32).unwrap();
+        } else {
+            data.write_u32::<BigEndian>(2 << 24 | model.indices.len() as u32).unwrap();
+        }
+        for &index in model.indices.iter() {
+            data.write_u16::<BigEndian>(index as u16).unwrap();
+        };
+
+        data.write_u32::<BigEndian>(model.vertices.len() as u32).unwrap();
+        for vertex in model.vertices.iter() {
+            floats(&mut data, &[vertex.x, vertex.y, vertex.z]);
+        };
+        data.write_u32::<BigEndian>(model.texcoords.len() as u32).unwrap();
+        for texcoord in model.texcoords.iter() {
+            floats(&mut data, &[texcoord.x, texcoord.y]);
+        };
+        data.write_u32::<BigEndian>(model.normals.len() as u32).unwrap();
+        for normal in model.normals.iter() {
+            floats(&mut data, &[normal.x, normal.y, normal.z]);
+        };
+
+        if !submeshes {
+            return data;
+        }
+
+        data.write_u32::<BigEndian>(model.materials.len() as u32).unwrap();
+        for material in model.materials.iter() {
+            data.write_u16::<BigEndian>(material.name.len() as u16).unwrap();
+            data.extend_from_slice(material.name.as_bytes());
+
+            if version >= 1 {
+                let factors = material.factors.unwrap();
+                floats(&mut data, &[factors.base_color.x, factors.base_color.y, factors.base_color.z,
+                                    factors.base_color.w, factors.metallic, factors.roughness,
+                                    factors.emissive.x, factors.emissive.y, factors.emissive.z]);
+            }
+        };
+
+        data.write_u32::<BigEndian>(model.submeshes.len() as u32).unwrap();
+        for submesh in model.submeshes.iter() {
+            data.write_u32::<BigEndian>(submesh.first_index).unwrap();
+            data.write_u32::<BigEndian>(submesh.count).unwrap();
+            data.write_u16::<BigEndian>(submesh.material as u16).unwrap();
+        };
+
+        data
+    }
+
+    /// Two triangles with a material each.
+    fn two_triangles() -> model::Model {
+        let glass = MetallicRoughness { base_color: Vector4::new(0.9, 0.9, 1.0, 0.2), roughness: 0.05,
+                                        metallic: 0.0, ..Default::default() };
+        let steel = MetallicRoughness { roughness: 0.4, emissive: Vector3::new(0.1, 0.0, 0.0),
+                                        ..Default::default() };
+
+        model::Model {
+            materials: vec![model::Material { name: String::from("glass"), factors: Some(glass) },
+                            model::Material { name: String::from("steel"), factors: Some(steel) }].
+                into_boxed_slice(),
+            submeshes: vec![Submesh { first_index: 0, count: 3, material: 1 },
+                            Submesh { first_index: 3, count: 3, material: 0 }].into_boxed_slice(),
+            indices: (0..6).collect::<Vec<_>>().into_boxed_slice(),
+            vertices: (0..6).map(|i| Vector3::new(i as f32, (i % 3) as f32, 0.0)).collect::<Vec<_>>().into_boxed_slice(),
+            normals: vec![Vector3::unit_z(); 6].into_boxed_slice(),
+            tangents: Box::new([]),
+            texcoords: (0..6).map(|i| Vector2::new(i as f32 / 6.0, 0.5)).collect::<Vec<_>>().into_boxed_slice(),
+        }
+    }
+
+    fn assert_geometry_eq(loaded: &model::Model, model: &model::Model) {
+        assert_eq!(loaded.indices, model.indices);
+        assert_eq!(loaded.vertices, model.vertices);
+        assert_eq!(loaded.texcoords, model.texcoords);
+        assert_eq!(loaded.normals, model.normals);
+        assert_eq!(loaded.tangents.len(), model.vertices.len());
+    }
+
+    #[test]
+    fn models_round_trip() {
+        let model = two_triangles();
+        let loaded = load_model(&mut Cursor::new(write_model(&model, VERSION, true)));
+
+        assert_geometry_eq(&loaded, &model);
+        assert_eq!(loaded.submeshes, model.submeshes);
+        for (loaded, material) in loaded.materials.iter().zip(model.materials.iter()) {
+            assert_eq!(loaded.name, material.name);
+            assert_eq!(loaded.factors, material.factors);
+        };
+    }
+
+    #[test]
+    fn unversioned_models_have_names_without_factors() {
+        let model = two_triangles();
+        let loaded = load_model(&mut Cursor::new(write_model(&model, 0, true)));
+
+        assert_geometry_eq(&loaded, &model);
+        assert_eq!(loaded.submeshes, model.submeshes);
+        assert_eq!(loaded.materials.iter().map(|material| material.name.as_str()).collect::<Vec<_>>(),
+                   vec!["glass", "steel"]);
+        assert!(loaded.materials.iter().all(|material| material.factors.is_none()));
+    }
+
+    #[test]
+    fn models_without_materials_are_one_submesh() {
+        let model = two_triangles();
+        let loaded = load_model(&mut Cursor::new(write_model(&model, 0, false)));
+
+        assert_geometry_eq(&loaded, &model);
+        assert!(loaded.materials.is_empty());
+        assert_eq!(&loaded.submeshes[..], &[Submesh { first_index: 0, count: 6, material: 0 }]);
+    }
+
+    #[test]
+    fn version_1_packs_the_index_size_into_the_count() {
+        let model = two_triangles();
+        let loaded = load_model(&mut Cursor::new(write_model(&model, 1, true)));
+
+        assert_geometry_eq(&loaded, &model);
+        assert_eq!(loaded.submeshes, model.submeshes);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported index size 1")]
+    fn byte_indices_are_refused() {
+        let mut data = write_model(&two_triangles(), VERSION, true);
+        // The index size follows the magic, version and transform
+        data[4 + 2 + 16 * 4] = 1;
+        load_model(&mut Cursor::new(data));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported model version")]
+    fn newer_versions_are_refused() {
+        load_model(&mut Cursor::new(write_model(&two_triangles(), VERSION + 1, true)));
+    }
+
+    #[test]
+    fn tangents_are_per_vertex() {
+        // A quad whose two triangles share a diagonal, with texture u along x
+        let indices = [0, 1, 2, 0, 2, 3];
+        let vertices = [Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0),
+                        Vector3::new(1.0, 1.0, 0.0), Vector3::new(0.0, 1.0, 0.0)];
+        let texcoords = [Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0),
+                         Vector2::new(1.0, 1.0), Vector2::new(0.0, 1.0)];
+        let normals = [Vector3::unit_z(); 4];
+
+        let tangents = calc_tangents(&indices, &vertices, &texcoords, &normals);
+        assert_eq!(&tangents[..], &[Vector3::unit_x(); 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Model has 6 vertices but 6 texture coordinates and 5 normals")]
+    fn mismatched_attributes_are_refused() {
+        let mut model = two_triangles();
+        model.normals = vec![Vector3::unit_z(); 5].into_boxed_slice();
+        load_model(&mut Cursor::new(write_model(&model, VERSION, true)));
+    }
+
+    #[test]
+    fn strings_are_length_prefixed() {
+        assert_eq!(read_string(&mut Cursor::new(b"\0\x05steel")).unwrap(), "steel");
+        assert_eq!(read_string(&mut Cursor::new(b"\0\0")).unwrap(), "");
+    }
+
+    #[test]
+    fn short_strings_are_errors() {
+        assert_eq!(read_string(&mut Cursor::new(b"\0\x05ste")).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(read_string(&mut Cursor::new(b"\0\x01\xff")).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
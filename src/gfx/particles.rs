@@ -0,0 +1,170 @@
+use std::path::Path;
+use gl;
+use gl::types::*;
+use cgmath::{Vector3, Vector4};
+use time::Timespec;
+use gfx::assets::Assets;
+use gfx::blocks::PARTICLE_BINDING;
+use gfx::lowlevel::*;
+use gfx::material::{Blend, Cull, RenderState};
+
+/// Particles emitted per second.
+const RATE: f32 = 400.0;
+/// Seconds a particle lives for, at most.
+const LIFETIME: f32 = 2.5;
+
+/// One particle as particles.glsl lays it out.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Particle {
+    /// `w` is the seconds left to live.
+    pub position: Vector4<f32>,
+    pub velocity: Vector4<f32>,
+}
+
+/// A fountain of sparks. The CPU only emits particles, overwriting the oldest; a compute
+/// shader moves them and the vertex shader reads them straight from the storage buffer.
+pub struct Particles {
+    pub origin: Vector3<f32>,
+    buffer: StorageBuffer<Particle>,
+    simulate: Handle<Program>,
+    program: Handle<Program>,
+    vao: VAO,
+    /// Where the next particle is emitted.
+    next: usize,
+    /// Fractional particles carried over to the next emission.
+    pending: f32,
+    /// The time step the next simulation covers.
+    step: f32,
+    last_think: Option<Timespec>,
+    seed: u32,
+}
+
+impl Particles {
+    pub fn new(origin: Vector3<f32>, assets: &mut Assets) -> Result<Particles, ShaderError> {
+        let simulate = assets.program_stages(&[(ShaderStage::Compute, Path::new("assets/particles.comp"))],
+                                             &Defines::new())?;
+        let program = assets.program(Path::new("assets/particles.vert"), Path::new("assets/particles.frag"))?;
+
+        let none = Vector4::new(0.0, 0.0, 0.0, 0.0);
+        let dead = Particle { position: none, velocity: none };
+        let capacity = (RATE * LIFETIME) as usize;
+
+        Ok(Particles {
+            origin,
+            buffer: StorageBuffer::new(&vec![dead; capacity]),
+            simulate,
+            program,
+            vao: VAO::empty(),
+            next: 0,
+            pending: 0.0,
+            step: 0.0,
+            last_think: None,
+            seed: 0x2545_f491,
+        })
+    }
+
+    /// A number in [0, 1), from a xorshift generator.
+    fn random(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn emit(&mut self) -> Particle {
+        let angle = self.random() * 2.0 * ::std::f32::consts::PI;
+        let spread = self.random() * 0.4;
+        let speed = 1.5 + self.random();
+        let life = LIFETIME * (0.5 + 0.5 * self.random());
+
+        Particle {
+            position: self.origin.extend(life),
+            velocity: Vector4::new(angle.cos() * spread, 1.0, angle.sin() * spread, 0.0) * speed,
+        }
+    }
+
+    /// Emits the particles due since the last call.
+    pub fn think(&mut self, time: Timespec) {
+        self.step = self.last_think.map_or(0.0, |last| {
+            (time - last).num_microseconds().unwrap_or(0) as f32 / 1.0e6
+        }).min(LIFETIME);
+        self.last_think = Some(time);
+
+        self.pending += self.step * RATE;
+        let count = (self.pending as usize).min(self.buffer.len());
+        self.pending -= count as f32;
+
+        let emitted: Vec<Particle> = (0..count).map(|_| self.emit()).collect();
+
+        // The ring of particles may wrap partway through
+        let split = emitted.len().min(self.buffer.len() - self.next);
+        self.buffer.write(self.next, &emitted[..split]);
+        self.buffer.write(0, &emitted[split..]);
+        self.next = (self.next + count) % self.buffer.len();
+    }
+
+    /// Advances the particles by the time the last `think` covered and draws them. Blended, so
+    /// it should come after opaque geometry and the sky.
+    pub fn draw(&self) {
+        let _group = debug_group("particles");
+
+        self.buffer.bind(PARTICLE_BINDING);
+
+        let simulate = self.simulate.borrow();
+        simulate.bind();
+        simulate.set_uniform("dt", &self.step);
+        simulate.dispatch_1d(self.buffer.len());
+
+        // Drawing reads what the simulation wrote, and the next emission overwrites it
+        memory_barrier(Barriers::STORAGE | Barriers::BUFFER_UPDATE);
+
+        self.program.borrow().bind();
+        self.vao.bind();
+        RenderState { cull: Cull::None, blend: Blend::Additive, depth_test: true, depth_write: false }.
+            apply();
+
+        unsafe {
+            gl::Enable(gl::PROGRAM_POINT_SIZE);
+            gl::DrawArrays(gl::POINTS, 0, self.buffer.len() as GLsizei);
+            gl::Disable(gl::PROGRAM_POINT_SIZE);
+        };
+
+        RenderState::default().apply();
+    }
+
+    /// How many particles are alive, read back from the GPU; it stalls, so only for reports.
+    pub fn live_count(&mut self) -> usize {
+        self.buffer.read().iter().filter(|particle| particle.position.w > 0.0).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+    use time::{Duration, Timespec};
+    use gfx::assets::Assets;
+    use gfx::lowlevel::test_context;
+    use super::*;
+
+    #[test]
+    fn emitted_particles_live_and_die() {
+        test_context::make_current();
+
+        let mut assets = Assets::new();
+        assets.program_cache = None;
+        let mut particles = Particles::new(Vector3::new(0.0, 0.0, 0.0), &mut assets).unwrap();
+        let start = Timespec::new(0, 0);
+
+        particles.think(start);
+        particles.think(start + Duration::milliseconds(100));
+        assert_eq!(particles.live_count(), (RATE * 0.1) as usize);
+
+        // Emission wraps around the ring, overwriting the oldest, and the step is capped at a
+        // lifetime, which the simulation then outlives everything in
+        particles.think(start + Duration::seconds(10));
+        assert_eq!(particles.live_count(), particles.buffer.len());
+        particles.draw();
+        assert_eq!(particles.live_count(), 0);
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::rc::Rc;
+use gl;
+use cgmath::{Matrix3, Matrix4, Vector3, Basis3, Decomposed, SquareMatrix};
+use gfx::assets::Assets;
+use gfx::image::Image;
+use gfx::lowlevel::*;
+
+pub const ENVIRONMENT_MAP: &str = "assets/skybox.png";
+
+/// The shared environment cube map, or a flat sky in the old clear color when there isn't one.
+pub fn environment_map(assets: &mut Assets) -> Handle<Texture> {
+    match assets.cube_map(Path::new(ENVIRONMENT_MAP)) {
+        Ok(cube) => cube,
+        Err(_) => assets.generated_texture("flat sky", || {
+            let face = || Image::solid(1, 1, [77, 77, 77, 255]);
+            Texture::new_cube("flat sky", &[face(), face(), face(), face(), face(), face()])
+        }),
+    }
+}
+
+pub struct Skybox {
+    pub program: Handle<Program>,
+    pub cube: Handle<Texture>,
+    pub sampler: Rc<Sampler>,
+    vao: VAO,
+}
+
+impl Skybox {
+    pub fn new(cube: Handle<Texture>, assets: &mut Assets) -> Skybox {
+        let program = assets.program(Path::new("assets/skybox.vert"), Path::new("assets/skybox.frag")).
+            unwrap_or_else(|err| panic!("{}", err));
+        let sampler = assets.sampler(SamplerParams::clamp());
+
+        Skybox { program, cube, sampler, vao: VAO::empty() }
+    }
+
+    /// Draws the sky behind everything already in the depth buffer, so it should come after
+    /// opaque geometry. Only the rotation of `view` is used.
+    pub fn draw(&self, view: &Decomposed<Vector3<f32>, Basis3<f32>>, proj: &Matrix4<f32>) {
+        let rot: Matrix3<f32> = view.rot.into();
+        let view_proj = proj * Matrix4::from(rot);
+        let inv_view_proj = view_proj.invert().unwrap_or(Matrix4::identity());
+
+        let _group = debug_group("skybox");
+        let program = self.program.borrow();
+
+        self.vao.bind();
+        program.bind();
+        self.cube.borrow().bind(0);
+        self.sampler.bind(0);
+
+        inv_view_proj.bind("inv_view_proj", &program);
+        0i32.bind("env_tex", &program);
+
+        unsafe {
+            gl::DepthFunc(gl::LEQUAL);
+            gl::DepthMask(gl::FALSE);
+
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+
+            gl::DepthMask(gl::TRUE);
+            gl::DepthFunc(gl::LESS);
+        };
+
+        Sampler::unbind(0);
+    }
+}
@@ -7,17 +7,26 @@ extern crate time;
 mod gfx;
 mod scene;
 
-use std::fs::File;
+use std::env;
 use std::path::Path;
 use time::Duration;
-use cgmath::{Vector3,Decomposed,Basis3,Deg,Rotation3,One};
+use cgmath::{Vector3,Point3,Decomposed,Basis3,Deg,One,EuclideanSpace,Transform,Matrix4};
 use glutin::GlContext;
-use gfx::model::model_loader;
-
-fn init_gl() -> (glutin::EventsLoop, glutin::GlWindow) {
+use gfx::assets::Assets;
+use gfx::blocks::{FrameBlock, FRAME_BINDING};
+use gfx::lowlevel::{TextureFormat, UniformBuffer};
+use gfx::lowlevel::accounting;
+use gfx::lowlevel::{check_errors, debug_group, enable_debug_output, requested_severity, Severity};
+use gfx::lowlevel::{Framebuffer, FramebufferDesc, DepthDesc};
+use gfx::particles::Particles;
+use gfx::skybox::{self, Skybox};
+
+/// Returns whether driver debug messages are coming through the callback; if not but debugging
+/// was requested, errors have to be polled.
+fn init_gl(debug: Option<Severity>) -> (glutin::EventsLoop, glutin::GlWindow, bool) {
 	let events_loop = glutin::EventsLoop::new();
     let window = glutin::WindowBuilder::new().with_title("four-kb");
-    let context = glutin::ContextBuilder::new();
+    let context = glutin::ContextBuilder::new().with_gl_debug_flag(debug.is_some());
     let gl_window = glutin::GlWindow::new(window, context, &events_loop).unwrap();
 
     // It is essential to make the context current before calling `gl::load_with`.
@@ -26,9 +35,19 @@ fn init_gl() -> (glutin::EventsLoop, glutin::GlWindow) {
     // Load the OpenGL function pointers
     gl::load_with(|symbol| gl_window.get_proc_address(symbol) as *const _);
 
+    let caps = gfx::lowlevel::caps();
+    println!("OpenGL {}.{}, direct state access {}", caps.version.0, caps.version.1,
+             if caps.direct_state_access { "on" } else { "off" });
+
+    let debug_output = debug.is_some_and(enable_debug_output);
+    if debug.is_some() && !debug_output {
+        println!("No GL debug output, checking glGetError once a frame instead");
+    }
+
 	unsafe {
 		gl::Enable(gl::DEPTH_TEST);
 		gl::Enable(gl::CULL_FACE);
+		gl::Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
         gl::FrontFace(gl::CCW);
 		
 		gl::ActiveTexture(gl::TEXTURE0);
@@ -36,27 +55,45 @@ fn init_gl() -> (glutin::EventsLoop, glutin::GlWindow) {
 		gl::ActiveTexture(gl::TEXTURE2);
 	}
 
-    (events_loop, gl_window)
+    (events_loop, gl_window, debug_output)
 }
 
 fn main() {
-    let (mut events_loop, gl_window) = init_gl();
+    let debug = requested_severity();
+    let (mut events_loop, gl_window, debug_output) = init_gl(debug);
 
-    let mut scene = scene::Scene {
-        objects: Vec::new(),
-    };
+    let mut scene = scene::Scene::new();
+
+    let mut assets = Assets::new();
+
+    if env::args().any(|arg| arg == "--clear-shader-cache") {
+        if let Some(ref cache) = assets.program_cache {
+            if let Err(err) = cache.clear() {
+                println!("Failed to clear the shader cache: {}", err);
+            };
+        };
+    }
+
+    // Displacement-mapped tessellation for meshes that don't bring their own material
+    assets.tessellate_meshes = env::args().any(|arg| arg == "--tessellate");
 
-    let mut file = File::open(&Path::new("assets/mesh.mdl")).unwrap();
-    let mdl = model_loader::load_model(&mut file);
+    let env_map = skybox::environment_map(&mut assets);
+    let skybox = Skybox::new(env_map, &mut assets);
 
-    scene.objects.push(Box::new(scene::MeshObject {
-        mesh: mdl.into(),
-        trans: Decomposed::<Vector3<f32>, Basis3<f32>> {
+    scene.objects.push(Box::new(scene::MeshObject::new(
+        assets.mesh(Path::new("assets/mesh.mdl")).unwrap(),
+        Decomposed::<Vector3<f32>, Basis3<f32>> {
             scale: 1.0,
             rot: Basis3::one(),
             disp: Vector3::new(0.0, 0.0, -0.5),
         },
-    }));
+    )));
+
+    let mut particles = Particles::new(Vector3::new(0.0, -0.6, -0.5), &mut assets).
+        unwrap_or_else(|err| panic!("{}", err));
+
+    assets.print_report();
+    println!("{}", accounting::summary());
 
     let view = Decomposed::<Vector3<f32>, Basis3<f32>> {
         scale: 1.0,
@@ -71,25 +108,79 @@ fn main() {
 		far: 100.0,
     };
 
+    let mut window_size = gl_window.get_inner_size().unwrap_or((800, 600));
+
+    // The scene is drawn multisampled offscreen, in floating point so lighting can go past 1.0
+    // until the resolve onto the window
+    let mut scene_fbo = Framebuffer::new("scene", FramebufferDesc {
+        scale: 1.0,
+        samples: 4,
+        colors: vec![TextureFormat::Rgba16F],
+        depth: Some(DepthDesc { format: TextureFormat::Depth24Stencil8, renderbuffer: true }),
+    }, window_size).unwrap();
+    let mut resolve_fbo = Framebuffer::new("resolve", FramebufferDesc {
+        scale: 1.0,
+        samples: 1,
+        colors: vec![TextureFormat::Rgba16F],
+        depth: None,
+    }, window_size).unwrap();
+    // Cleared when the framebuffers can't follow the window, such as while it's minimized
+    let mut framebuffers_ok = true;
+
+    let frame_block = |view: &Decomposed<Vector3<f32>, Basis3<f32>>, proj: Matrix4<f32>, time: f32| FrameBlock {
+        view: (*view).into(),
+        proj,
+        camera_pos: view.inverse_transform().map_or(Point3::origin(), |camera| Point3::from_vec(camera.disp)),
+        time,
+    };
+    let mut frame_ubo = UniformBuffer::new(FRAME_BINDING, &frame_block(&view, proj.into(), 0.0));
+    let start_time = time::get_time();
+
 	let mut running = true;
 
     let mut frames = 0;
     let mut duration = Duration::zero();
+    let mut fps_reports = 0;
 
     while running {
+        assets.reload_changed();
+
         scene.think(time::get_time());
+        particles.think(time::get_time());
 
         let time1 = time::get_time();
 
-        unsafe {
-            gl::ClearColor(0.3, 0.3, 0.3, 1.0);
-            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-        };
+        if framebuffers_ok {
+            scene_fbo.bind();
+
+            unsafe {
+                gl::ClearColor(0.3, 0.3, 0.3, 1.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+            };
 
-        let proj_mat = proj.into();
-        scene.render(&view, &proj_mat);
+            let proj_mat = proj.into();
+            let elapsed = (time1 - start_time).num_microseconds().unwrap_or(0) as f32 / 1.0e6;
+            frame_ubo.update(&frame_block(&view, proj_mat, elapsed));
 
-        gl_window.swap_buffers().unwrap();
+            scene.render();
+            skybox.draw(&view, &proj_mat);
+            particles.draw();
+
+            {
+                // Multisampled framebuffers can't be scaled, so resolve at the same size first
+                let _group = debug_group("resolve");
+                scene_fbo.resolve(&resolve_fbo).
+                    and_then(|_| resolve_fbo.blit(0, None, window_size, false)).
+                    unwrap_or_else(|err| println!("Failed to resolve the scene: {}", err));
+                Framebuffer::bind_default(window_size.0, window_size.1);
+            }
+
+            if debug.is_some() && !debug_output {
+                check_errors("frame");
+            }
+
+            gl_window.swap_buffers().unwrap();
+        }
 
 		events_loop.poll_events(|event| {
 			match event {
@@ -107,8 +198,15 @@ fn main() {
                         far: 100.0,
                     };
 
-                    unsafe {
-                        gl::Viewport(0, 0, width as i32, height as i32);
+                    window_size = (width, height);
+                    let resized = scene_fbo.on_window_resize(width, height).
+                        and_then(|_| resolve_fbo.on_window_resize(width, height));
+                    framebuffers_ok = match resized {
+                        Ok(()) => true,
+                        Err(err) => {
+                            println!("Skipping frames until the window can be drawn to: {}", err);
+                            false
+                        },
                     };
                 },
 				_ => (),
@@ -118,14 +216,30 @@ fn main() {
         let time2 = time::get_time();
         let frame_duration = time2 - time1;
 
-        frames = frames + 1;
+        frames += 1;
         duration = duration + frame_duration;
 
         if duration >= Duration::seconds(1) {
             println!("{} FPS", frames);
 
+            fps_reports += 1;
+            if fps_reports % 10 == 0 {
+                println!("{}", accounting::summary());
+                println!("{} particles alive", particles.live_count());
+            }
+
             frames = 0;
             duration = Duration::zero();
         };
     };
+
+    // Everything should be freed by now; whatever is left is held by a reference cycle
+    drop(frame_ubo);
+    drop(scene_fbo);
+    drop(resolve_fbo);
+    drop(skybox);
+    drop(particles);
+    drop(scene);
+    drop(assets);
+    accounting::report_leaks();
 }
@@ -1,19 +1,39 @@
-use cgmath::{Vector3, Basis3, Decomposed, Matrix4, Deg, Rotation3, Transform};
+use cgmath::{Vector3, Vector4, Basis3, Decomposed, Matrix4, Deg, Rotation3, Transform};
 use time;
-use gfx::mesh::Mesh;
+use gfx::blocks::{ObjectBlock, OBJECT_BINDING};
+use gfx::lowlevel::{Handle, UniformBuffer};
+use gfx::mesh::{InstanceData, Mesh};
 use scene::SceneObject;
 
 pub struct MeshObject {
-    pub mesh: Mesh,
+    pub mesh: Handle<Mesh>,
     pub trans: Decomposed<Vector3<f32>, Basis3<f32>>,
+    /// Multiplies the material's color.
+    pub color: Vector4<f32>,
+    block: UniformBuffer<ObjectBlock>,
+}
+
+impl MeshObject {
+    pub fn new(mesh: Handle<Mesh>, trans: Decomposed<Vector3<f32>, Basis3<f32>>) -> MeshObject {
+        let color = Vector4::new(1.0, 1.0, 1.0, 1.0);
+        let block = UniformBuffer::new(OBJECT_BINDING, &ObjectBlock { trans: trans.into(), color });
+
+        for material in &mesh.borrow().materials {
+            block.check(&material.borrow().program.borrow());
+        };
+
+        MeshObject { mesh, trans, color, block }
+    }
 }
 
 impl SceneObject for MeshObject {
-    fn render(&self, view: &Decomposed<Vector3<f32>, Basis3<f32>>, proj: &Matrix4<f32>) {
-        self.mesh.draw(view, proj);
+    fn render(&self) {
+        self.block.bind();
+
+        self.mesh.borrow().draw();
     }
 
-    fn think(&mut self, time: time::Timespec) {
+    fn think(&mut self, _time: time::Timespec) {
         let nt = Decomposed::<Vector3<f32>, Basis3<f32>> {
             scale: 1.0,
             rot: Basis3::from_angle_y(Deg(1.0)),
@@ -21,10 +41,11 @@ impl SceneObject for MeshObject {
         };
         self.trans.concat_self(&nt);
 
-        self.mesh.transform = self.trans.clone().into();        // FIXME: optimize
+        let trans: Matrix4<f32> = self.trans.into();
+        self.block.update(&ObjectBlock { trans, color: self.color });
     }
 
-    fn get_transform(&self) -> Decomposed<Vector3<f32>, Basis3<f32>> {
-        self.trans.clone()
+    fn instance(&self) -> Option<(Handle<Mesh>, InstanceData)> {
+        Some((self.mesh.clone(), InstanceData { instance_trans: self.trans.into(), instance_color: self.color }))
     }
 }
@@ -1,29 +1,74 @@
 use time;
-use cgmath::{Vector3, Basis3, Decomposed, Matrix4};
+use gfx::batch::MultiDraw;
+use gfx::lowlevel::{debug_group, Handle};
+use gfx::material::RenderState;
+use gfx::mesh::{InstanceData, Mesh};
 
 mod mesh_object;
 pub use self::mesh_object::MeshObject;
 
 pub trait SceneObject {
-    fn render(&self, view: &Decomposed<Vector3<f32>, Basis3<f32>>, proj: &Matrix4<f32>);
+    /// Draws the object. Camera state comes from the frame block bound by the caller.
+    fn render(&self);
     fn think(&mut self, time: time::Timespec);
-    fn get_transform(&self) -> Decomposed<Vector3<f32>, Basis3<f32>>;
+
+    /// The mesh and per-instance data to draw the object with, if it's nothing more than that.
+    /// Such objects are batched with others instead of calling `render`.
+    fn instance(&self) -> Option<(Handle<Mesh>, InstanceData)> {
+        None
+    }
 }
 
 pub struct Scene {
-    pub objects: Vec<Box<SceneObject>>,
+    pub objects: Vec<Box<dyn SceneObject>>,
+    /// Unset when the context can't draw indirectly; batches are then drawn instanced.
+    multi_draw: Option<MultiDraw>,
 }
 
 impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            objects: Vec::new(),
+            multi_draw: MultiDraw::new(),
+        }
+    }
+
     pub fn think(&mut self, time: time::Timespec) {
         for object in &mut self.objects {
             object.think(time);
         };
     }
 
-    pub fn render(&self, view: &Decomposed<Vector3<f32>, Basis3<f32>>, proj: &Matrix4<f32>) {
+    pub fn render(&mut self) {
+        let _group = debug_group("scene");
+        let mut instances = Vec::new();
+
         for object in &self.objects {
-            object.render(view, proj);
+            // Instancing is the fallback for anything multi-draw can't take
+            match object.instance() {
+                Some(instance) if instance.0.borrow().is_instanced() => instances.push(instance),
+                _ => object.render(),
+            };
+        };
+
+        if let Some(ref mut multi_draw) = self.multi_draw {
+            instances = multi_draw.draw(instances);
+        }
+
+        let mut batches: Vec<(Handle<Mesh>, Vec<InstanceData>)> = Vec::new();
+
+        for (mesh, instance) in instances {
+            match batches.iter_mut().find(|batch| batch.0.ptr_eq(&mesh)) {
+                Some(batch) => batch.1.push(instance),
+                None => batches.push((mesh, vec![instance])),
+            };
         };
+
+        for (mesh, instances) in batches {
+            mesh.borrow_mut().draw_instanced(&instances);
+        };
+
+        // Materials leave their render state behind; the skybox and resolve expect the default
+        RenderState::default().apply();
     }
 }